@@ -0,0 +1,218 @@
+//! Benchmarks of the voice crypt path, run with `cargo bench --bench crypt`
+
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use tokio::io::{self, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::runtime::Runtime;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore, ServerName};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use zumble::ban::BanList;
+use zumble::crypt::CryptState;
+use zumble::message;
+use zumble::proto::mumble::{Authenticate, Version};
+use zumble::state::ServerState;
+use zumble::tls::{create_tls_config, TlsVersion};
+use zumble::voice::{batch_voice_packets, Clientbound, Serverbound, VoicePacket, VoicePacketPayload};
+
+/// Typical opus frame, and the biggest frame fitting in a 1024 bytes udp packet
+const FRAME_SIZES: [usize; 2] = [160, 1000];
+const CLIENT_COUNTS: [usize; 3] = [10, 100, 500];
+
+fn voice_packet(frame_size: usize) -> VoicePacket<Serverbound> {
+    VoicePacket::Audio {
+        _dst: PhantomData,
+        target: 0,
+        session_id: (),
+        seq_num: 0,
+        payload: VoicePacketPayload::Opus(Bytes::from(vec![0x42; frame_size]), false),
+        position_info: None,
+    }
+}
+
+/// Create the client side crypt state matching a server crypt state
+fn client_crypt_state(server: &CryptState) -> CryptState {
+    CryptState::new(server.key, server.get_decrypt_nonce(), server.get_encrypt_nonce())
+}
+
+fn bench_encrypt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encrypt");
+
+    for frame_size in FRAME_SIZES {
+        let packet = voice_packet(frame_size);
+        let mut crypt_state = CryptState::default();
+
+        group.throughput(Throughput::Bytes(frame_size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(frame_size), &packet, |b, packet| {
+            b.iter(|| {
+                let mut dst = BytesMut::new();
+                crypt_state.encrypt(packet, &mut dst);
+
+                dst
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_decrypt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decrypt");
+
+    for frame_size in FRAME_SIZES {
+        let packet = voice_packet(frame_size);
+        let mut server = CryptState::default();
+        let mut client = client_crypt_state(&server);
+
+        group.throughput(Throughput::Bytes(frame_size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(frame_size), &packet, |b, packet| {
+            // Packets are encrypted in order by the setup, so each one is decrypted as a new in order packet
+            b.iter_batched(
+                || {
+                    let mut buf = BytesMut::new();
+                    client.encrypt(packet, &mut buf);
+
+                    buf
+                },
+                |mut buf| server.decrypt::<Serverbound>(&mut buf).expect("cannot decrypt packet"),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+/// Compare sending queued packets one by one with batching them in a single packet, as done with `--voice-batching`
+fn bench_batching(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batching");
+
+    for count in [3, 6] {
+        // 20ms celt frames of a typical size, contiguous sequence numbers
+        let packets = (0..count)
+            .map(|i| VoicePacket::<Clientbound>::Audio {
+                _dst: PhantomData,
+                target: 0,
+                session_id: 1,
+                seq_num: i * 2,
+                payload: VoicePacketPayload::Opus(Bytes::from([vec![0xF8], vec![0x42; FRAME_SIZES[0]]].concat()), false),
+                position_info: None,
+            })
+            .collect::<Vec<_>>();
+        let mut crypt_state = CryptState::default();
+
+        group.bench_with_input(BenchmarkId::new("separate", count), &packets, |b, packets| {
+            b.iter(|| {
+                for packet in packets {
+                    let mut dst = BytesMut::new();
+                    crypt_state.encrypt(packet, &mut dst);
+                }
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("batched", count), &packets, |b, packets| {
+            b.iter(|| {
+                let batched = batch_voice_packets(packets.clone()).expect("cannot batch packets");
+                let mut dst = BytesMut::new();
+                crypt_state.encrypt(&batched, &mut dst);
+
+                dst
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// Create a connected tls stream pair, the server side is kept by the state and the client side must be kept alive
+async fn tls_pair(
+    listener: &TcpListener,
+    acceptor: &TlsAcceptor,
+    connector: &TlsConnector,
+) -> (tokio_rustls::server::TlsStream<TcpStream>, TlsStream<TcpStream>) {
+    let address = listener.local_addr().expect("cannot get listener address");
+    let server_name = ServerName::try_from("localhost").expect("invalid server name");
+
+    let (accepted, connected) = tokio::join!(
+        async { acceptor.accept(listener.accept().await.expect("cannot accept").0).await },
+        async {
+            connector
+                .connect(server_name, TcpStream::connect(address).await.expect("cannot connect"))
+                .await
+        },
+    );
+
+    (accepted.expect("cannot accept tls"), connected.expect("cannot connect tls"))
+}
+
+/// Create a server state with the given number of connected clients
+async fn create_state(client_count: usize) -> (ServerState, Vec<TlsStream<TcpStream>>) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).expect("cannot generate certificate");
+    let certs = vec![Certificate(cert.serialize_der().expect("cannot serialize certificate"))];
+    let key = PrivateKey(cert.serialize_private_key_der());
+
+    let mut roots = RootCertStore::empty();
+    roots.add(&certs[0]).expect("cannot add root certificate");
+
+    let server_config =
+        create_tls_config(vec![(certs, key)], TlsVersion::Tls12, rustls::ALL_CIPHER_SUITES, false).expect("cannot create tls config");
+    let client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let connector = TlsConnector::from(Arc::new(client_config));
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("cannot bind tcp listener");
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("cannot bind udp socket"));
+
+    let mut state = ServerState::new(socket, BanList::load(None).expect("cannot create ban list"));
+    let mut client_streams = Vec::with_capacity(client_count);
+
+    for i in 0..client_count {
+        let (server_stream, mut client_stream) = tls_pair(&listener, &acceptor, &connector).await;
+        let address = server_stream.get_ref().0.peer_addr().expect("cannot get peer address");
+        let (_, write) = io::split(server_stream);
+        let (publisher, _) = message::channel(128);
+
+        let mut authenticate = Authenticate::new();
+        authenticate.set_username(format!("client-{}", i));
+
+        state.add_client(Version::new(), authenticate, CryptState::default(), write, address, publisher, None);
+
+        client_stream.flush().await.expect("cannot flush client stream");
+        client_streams.push(client_stream);
+    }
+
+    (state, client_streams)
+}
+
+fn bench_find_client_for_packet(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("cannot create runtime");
+    let mut group = c.benchmark_group("find_client_for_packet");
+
+    for client_count in CLIENT_COUNTS {
+        let (state, _client_streams) = runtime.block_on(create_state(client_count));
+        let address = "127.0.0.1:1".parse().expect("invalid address");
+
+        // Packet from an unknown client, its nonce is in order for every client so the whole scan goes up to the tag check
+        let mut buf = BytesMut::new();
+        CryptState::default().encrypt(&voice_packet(FRAME_SIZES[0]), &mut buf);
+
+        group.bench_with_input(BenchmarkId::from_parameter(client_count), &buf, |b, buf| {
+            b.to_async(&runtime).iter(|| async {
+                let mut buf = buf.clone();
+
+                state.find_client_for_packet(&mut buf, address).await.expect("cannot find client")
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_encrypt, bench_decrypt, bench_batching, bench_find_client_for_packet);
+criterion_main!(benches);
@@ -0,0 +1,140 @@
+//! Request and response bodies of the http api
+//!
+//! Only depends on serde, tools talking to the api can use them with `default-features = false, features = ["client-types"]`
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MumbleClient {
+    pub name: String,
+    pub session_id: u32,
+    /// Stable id of a registered user, unset for anonymous clients
+    #[serde(default)]
+    pub user_id: Option<u32>,
+    pub channel: Option<String>,
+    /// Muted or deafened by an admin
+    pub server_mute: bool,
+    pub server_deaf: bool,
+    /// Muted or deafened by the client itself
+    pub self_mute: bool,
+    pub self_deaf: bool,
+    /// Whether the last voice packet of the client had positional data
+    pub has_positional: bool,
+    /// Positional audio plugin context, non utf-8 bytes are replaced
+    pub plugin_context: String,
+    pub plugin_identity: String,
+    pub tcp_address: SocketAddr,
+    pub udp_address: Option<SocketAddr>,
+    pub queue_length: usize,
+    pub voice_packets_in: u64,
+    pub voice_packets_out: u64,
+    /// Voice packets per second over the last few seconds
+    pub voice_pps_in: f64,
+    pub voice_pps_out: f64,
+    pub good: u32,
+    pub late: u32,
+    pub lost: u32,
+    pub resync: u32,
+    /// Late and lost udp packets per second over the last few seconds
+    #[serde(default)]
+    pub late_per_sec: f64,
+    #[serde(default)]
+    pub lost_per_sec: f64,
+    pub last_good_duration: u128,
+    /// Absent when the status is requested without targets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub targets: Option<Vec<MumbleTarget>>,
+    /// Volume of each channel listened by this client, keyed by channel id
+    pub listening: HashMap<u32, f32>,
+    /// Sessions this client receives the voice of, wherever they speak
+    pub listened_users: HashSet<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MumbleTarget {
+    pub sessions: HashSet<u32>,
+    pub channels: HashSet<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StatusSummary {
+    pub clients: usize,
+    pub channels: usize,
+    /// Clients with a known udp address, the others receive voice through the tcp tunnel
+    pub udp_clients: usize,
+    pub tcp_clients: usize,
+}
+
+/// Sessions and channels reached by the voice of a speaker sent to one target
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RoutedVoice {
+    /// Sessions receiving the voice, ordered, without the speaker and the deaf clients
+    pub sessions: Vec<u32>,
+    /// Channels the voice is routed to, ordered
+    pub channels: Vec<u32>,
+}
+
+/// Routing of the voice of a speaker, to its channel and to each of its configured whisper targets
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VoiceRoutes {
+    pub session_id: u32,
+    pub channel: RoutedVoice,
+    /// Routes of the whisper targets by voice target id, targets without any session nor channel are omitted
+    pub targets: BTreeMap<u8, RoutedVoice>,
+}
+
+/// Udp packet counters summed over every connected client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CryptStats {
+    pub clients: usize,
+    pub good: u64,
+    pub late: u64,
+    pub lost: u64,
+    pub resync: u64,
+    /// Clients having lost more packets than the requested threshold since they connected
+    pub degraded_clients: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMember {
+    pub name: String,
+    pub session_id: u32,
+}
+
+/// Response of `GET /channels/{id}/members`, ordered by session id
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMembers {
+    pub channel_id: u32,
+    /// Clients in the channel
+    pub members: Vec<ChannelMember>,
+    /// Clients listening to the channel from another one
+    pub listeners: Vec<ChannelMember>,
+}
+
+/// Response item of `GET /export-sessions` and body item of `POST /import-sessions`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportedSession {
+    pub name: String,
+    pub channel_id: u32,
+    pub server_mute: bool,
+    pub server_deaf: bool,
+    /// Only exported, clients send their voice targets again when they reconnect
+    #[serde(default)]
+    pub targets: Vec<MumbleTarget>,
+}
+
+/// Body of `POST /mute` and response of `GET /mute/{user}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Mute {
+    pub mute: bool,
+    pub user: String,
+}
+
+/// Body of `POST /deaf` and response of `GET /deaf/{user}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Deaf {
+    pub deaf: bool,
+    pub user: String,
+}
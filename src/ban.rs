@@ -0,0 +1,410 @@
+use crate::proto::mumble::BanList_BanEntry;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Ban {
+    pub address: IpAddr,
+    /// Subnet mask length, expressed over the ipv6 (mapped) address like the mumble protocol does
+    pub mask: u32,
+    pub name: Option<String>,
+    pub hash: Option<String>,
+    pub reason: Option<String>,
+    pub start: Option<String>,
+    /// Ban duration in seconds, 0 means permanent
+    pub duration: u32,
+    /// Unix timestamp of when the ban was added, used for expiration
+    pub created_at: u64,
+}
+
+impl Ban {
+    pub fn new(address: IpAddr, mask: u32) -> Self {
+        Self {
+            address,
+            mask: mask.min(128),
+            name: None,
+            hash: None,
+            reason: None,
+            start: None,
+            duration: 0,
+            created_at: now(),
+        }
+    }
+
+    pub fn from_ban_entry(entry: &BanList_BanEntry) -> Option<Self> {
+        let address: [u8; 16] = match entry.get_address().len() {
+            4 => Ipv4Addr::from(<[u8; 4]>::try_from(entry.get_address()).ok()?)
+                .to_ipv6_mapped()
+                .octets(),
+            16 => entry.get_address().try_into().ok()?,
+            _ => return None,
+        };
+
+        let mut ban = Self::new(to_canonical(Ipv6Addr::from(address)), entry.get_mask());
+
+        if entry.has_name() {
+            ban.name = Some(entry.get_name().to_string());
+        }
+
+        if entry.has_hash() {
+            ban.hash = Some(entry.get_hash().to_string());
+        }
+
+        if entry.has_reason() {
+            ban.reason = Some(entry.get_reason().to_string());
+        }
+
+        // Expiration counts from the start sent by the client, a ban replacing an existing one keeps its start instead
+        if entry.has_start() {
+            ban.start = Some(entry.get_start().to_string());
+
+            if let Some(created_at) = parse_start(entry.get_start()) {
+                ban.created_at = created_at;
+            }
+        }
+
+        ban.duration = entry.get_duration();
+
+        Some(ban)
+    }
+
+    pub fn get_ban_entry(&self) -> BanList_BanEntry {
+        let mut entry = BanList_BanEntry::new();
+
+        entry.set_address(to_ipv6(&self.address).octets().to_vec());
+        entry.set_mask(self.mask);
+
+        if let Some(name) = &self.name {
+            entry.set_name(name.clone());
+        }
+
+        if let Some(hash) = &self.hash {
+            entry.set_hash(hash.clone());
+        }
+
+        if let Some(reason) = &self.reason {
+            entry.set_reason(reason.clone());
+        }
+
+        entry.set_start(format_start(self.created_at));
+
+        entry.set_duration(self.duration);
+
+        entry
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.duration != 0 && now() >= self.created_at + self.duration as u64
+    }
+
+    pub fn matches(&self, address: &IpAddr) -> bool {
+        if self.mask == 0 {
+            return true;
+        }
+
+        let mask = u128::MAX << (128 - self.mask);
+        let banned = u128::from(to_ipv6(&self.address));
+        let address = u128::from(to_ipv6(address));
+
+        banned & mask == address & mask
+    }
+
+    fn is_same(&self, address: &IpAddr, mask: u32) -> bool {
+        to_ipv6(&self.address) == to_ipv6(address) && self.mask == mask.min(128)
+    }
+}
+
+#[derive(Default)]
+pub struct BanList {
+    bans: Vec<Ban>,
+    path: Option<PathBuf>,
+    /// Incremented on each save, so a write never replaces the file with an older list
+    generation: u64,
+    /// Generation of the list last written to the file
+    written: Arc<tokio::sync::Mutex<u64>>,
+}
+
+/// Ban list serialized while the state is locked, written to the ban file once the lock is released
+#[must_use = "the ban list is only persisted once written"]
+pub struct BanListWrite {
+    file: Option<(PathBuf, Vec<u8>, u64)>,
+    written: Arc<tokio::sync::Mutex<u64>>,
+}
+
+impl BanListWrite {
+    /// Write to a temporary file renamed over the ban file, so a crash while writing cannot truncate the list
+    pub async fn write(self) {
+        let (path, content, generation) = match self.file {
+            Some(file) => file,
+            None => return,
+        };
+
+        let mut written = self.written.lock().await;
+
+        // A concurrent save already wrote a more recent list
+        if *written >= generation {
+            return;
+        }
+
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let result = match tokio::fs::write(&temp_path, &content).await {
+            Ok(()) => tokio::fs::rename(&temp_path, &path).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(()) => *written = generation,
+            Err(err) => tracing::error!("failed to save ban list to {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl BanList {
+    /// Load the ban list from the given file, an absent file is considered as an empty list
+    pub fn load(path: Option<PathBuf>) -> Result<Self, anyhow::Error> {
+        let bans = match &path {
+            Some(path) if path.exists() => serde_json::from_reader(std::fs::File::open(path)?)?,
+            _ => Vec::new(),
+        };
+
+        Ok(Self {
+            bans,
+            path,
+            ..Default::default()
+        })
+    }
+
+    pub fn get_bans(&self) -> Vec<Ban> {
+        self.bans.iter().filter(|ban| !ban.is_expired()).cloned().collect()
+    }
+
+    pub fn is_banned(&self, address: &IpAddr) -> Option<&Ban> {
+        self.bans.iter().find(|ban| !ban.is_expired() && ban.matches(address))
+    }
+
+    pub fn add(&mut self, ban: Ban) -> BanListWrite {
+        self.bans.retain(|existing| !existing.is_same(&ban.address, ban.mask));
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Remove the ban matching exactly the address and mask, `None` if no ban was removed
+    pub fn remove(&mut self, address: &IpAddr, mask: u32) -> Option<BanListWrite> {
+        let count = self.bans.len();
+        self.bans.retain(|ban| !ban.is_same(address, mask));
+
+        if count == self.bans.len() {
+            return None;
+        }
+
+        Some(self.save())
+    }
+
+    /// Replace the whole list, as sent back by a mumble client
+    ///
+    /// Bans already in the list keep when they were added, so sending the list back does not extend temporary bans.
+    pub fn replace(&mut self, mut bans: Vec<Ban>) -> BanListWrite {
+        for ban in &mut bans {
+            if let Some(existing) = self
+                .bans
+                .iter()
+                .find(|existing| existing.is_same(&ban.address, ban.mask) && existing.hash == ban.hash)
+            {
+                ban.created_at = existing.created_at;
+                ban.start = existing.start.clone();
+            }
+        }
+
+        self.bans = bans;
+        self.save()
+    }
+
+    /// Serialize the list for the ban file, the file is written by the returned `BanListWrite` without blocking the server
+    fn save(&mut self) -> BanListWrite {
+        self.bans.retain(|ban| !ban.is_expired());
+
+        let file = match &self.path {
+            Some(path) => match serde_json::to_vec_pretty(&self.bans) {
+                Ok(content) => {
+                    self.generation += 1;
+
+                    Some((path.clone(), content, self.generation))
+                }
+                Err(err) => {
+                    tracing::error!("failed to serialize ban list: {}", err);
+
+                    None
+                }
+            },
+            None => None,
+        };
+
+        BanListWrite {
+            file,
+            written: self.written.clone(),
+        }
+    }
+}
+
+/// Convert a mask length for the given address family to a mask length over an ipv6 (mapped) address
+pub fn to_ipv6_mask(address: &IpAddr, mask: u32) -> u32 {
+    match address {
+        IpAddr::V4(_) => mask.min(32) + 96,
+        IpAddr::V6(_) => mask.min(128),
+    }
+}
+
+fn to_ipv6(address: &IpAddr) -> Ipv6Addr {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped(),
+        IpAddr::V6(address) => *address,
+    }
+}
+
+fn to_canonical(address: Ipv6Addr) -> IpAddr {
+    match address.to_ipv4_mapped() {
+        Some(address) => IpAddr::V4(address),
+        None => IpAddr::V6(address),
+    }
+}
+
+/// Format a unix timestamp as the iso 8601 utc date used by mumble for the ban start, e.g. `2024-03-01T18:30:00`
+fn format_start(timestamp: u64) -> String {
+    let (year, month, day) = civil_from_days((timestamp / 86400) as i64);
+    let seconds = timestamp % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Parse the iso 8601 utc start of a ban to a unix timestamp, milliseconds and a trailing `Z` are accepted
+fn parse_start(start: &str) -> Option<u64> {
+    let start = start.trim_end_matches('Z');
+    let start = start.split('.').next()?;
+    let (date, time) = start.split_once('T')?;
+
+    let date = date.split('-').map(|value| value.parse::<i64>().ok()).collect::<Option<Vec<_>>>()?;
+    let time = time.split(':').map(|value| value.parse::<i64>().ok()).collect::<Option<Vec<_>>>()?;
+
+    let (year, month, day, hour, minute, second) = match (date.as_slice(), time.as_slice()) {
+        ([year, month, day], [hour, minute, second]) => (*year, *month, *day, *hour, *minute, *second),
+        _ => return None,
+    };
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let timestamp = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+
+    u64::try_from(timestamp).ok()
+}
+
+/// Days since the unix epoch of a date of the proleptic gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Date of the proleptic gregorian calendar of a number of days since the unix epoch
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_start_is_iso_8601_utc() {
+        assert_eq!(format_start(1709317800), "2024-03-01T18:30:00");
+        assert_eq!(parse_start("2024-03-01T18:30:00"), Some(1709317800));
+        assert_eq!(parse_start("2024-03-01T18:30:00.250Z"), Some(1709317800));
+        assert_eq!(parse_start("1970-01-01T00:00:00"), Some(0));
+        assert_eq!(parse_start("2024-13-01T18:30:00"), None);
+        assert_eq!(parse_start("yesterday"), None);
+    }
+
+    #[test]
+    fn sending_the_ban_list_back_does_not_extend_temporary_bans() {
+        let mut bans = BanList::load(None).unwrap();
+        let created_at = now() - 50;
+
+        let mut ban = Ban::new("192.0.2.1".parse().unwrap(), 128);
+        ban.duration = 60;
+        ban.created_at = created_at;
+        let _ = bans.add(ban);
+
+        // Clients may drop the start of the bans they send back
+        let mut entries = bans.get_bans().iter().map(Ban::get_ban_entry).collect::<Vec<_>>();
+        let _ = bans.replace(entries.iter().filter_map(Ban::from_ban_entry).collect());
+        assert_eq!(bans.get_bans()[0].created_at, created_at);
+
+        entries[0].clear_start();
+        let _ = bans.replace(entries.iter().filter_map(Ban::from_ban_entry).collect());
+        assert_eq!(bans.get_bans()[0].created_at, created_at);
+
+        // A new ban expires from the start given by the client
+        let mut entry = Ban::new("192.0.2.2".parse().unwrap(), 128).get_ban_entry();
+        entry.set_start("2024-03-01T18:30:00".to_string());
+        entry.set_duration(60);
+        entries.push(entry);
+        let _ = bans.replace(entries.iter().filter_map(Ban::from_ban_entry).collect());
+
+        assert_eq!(bans.get_bans().len(), 1);
+        assert!(bans.is_banned(&"192.0.2.2".parse().unwrap()).is_none());
+    }
+
+    #[tokio::test]
+    async fn saved_ban_list_is_loaded_back() {
+        let path = std::env::temp_dir().join(format!("zumble-bans-{}.json", std::process::id()));
+        let mut bans = BanList::load(Some(path.clone())).unwrap();
+
+        let first = bans.add(Ban::new("192.0.2.1".parse().unwrap(), 128));
+        let second = bans.add(Ban::new("192.0.2.2".parse().unwrap(), 128));
+
+        // The most recent list is kept whatever the order of the writes
+        second.write().await;
+        first.write().await;
+
+        let loaded = BanList::load(Some(path.clone())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.get_bans().len(), 2);
+    }
+}
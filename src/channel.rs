@@ -2,9 +2,13 @@ use crate::client::Client;
 use crate::proto::mumble::ChannelState;
 use crate::sync::RwLock;
 use crate::ServerState;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use std::fmt;
+use std::str::FromStr;
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Debug)]
 pub struct Channel {
@@ -14,6 +18,21 @@ pub struct Channel {
     pub description: String,
     pub temporary: bool,
     pub listeners: HashSet<u32>,
+    /// Volume adjustment of each listener session, listeners without an entry use the default volume
+    pub listener_volumes: HashMap<u32, f32>,
+    /// Distance beyond which clients of the channel do not hear a positional speaker, everyone hears it when unset
+    pub audio_range: Option<f32>,
+    /// Clients in a listen only channel hear it but their channel voice is dropped, admins can still speak
+    pub speak_allowed: bool,
+    /// Max number of clients in the channel, clients cannot join it once full, unlimited when unset
+    pub max_occupancy: Option<usize>,
+    /// Clients of an idle channel are moved out once they did not speak for the idle timeout
+    pub idle: bool,
+    /// Channels bridged with this one, a link is only followed when both channels list each other
+    pub links: HashSet<u32>,
+    /// Session of the client that created the channel, unset for channels not created by a connected client
+    pub creator: Option<u32>,
+    pub created_at: Instant,
 }
 
 impl Channel {
@@ -25,6 +44,14 @@ impl Channel {
             description,
             temporary,
             listeners: HashSet::new(),
+            listener_volumes: HashMap::new(),
+            audio_range: None,
+            speak_allowed: true,
+            max_occupancy: None,
+            idle: false,
+            links: HashSet::new(),
+            creator: None,
+            created_at: Instant::now(),
         }
     }
 
@@ -45,18 +72,27 @@ impl Channel {
         state
     }
 
-    pub async fn get_listeners(&self, state: Arc<RwLock<ServerState>>) -> HashMap<u32, Arc<RwLock<Client>>> {
-        let mut listening_clients = HashMap::new();
+    pub fn get_listener_volume(&self, session_id: u32) -> f32 {
+        self.listener_volumes.get(&session_id).copied().unwrap_or(1.0)
+    }
 
+    pub async fn get_listeners(&self, state: Arc<RwLock<ServerState>>) -> HashMap<u32, Arc<RwLock<Client>>> {
         let state_read = match state.read_err().await {
             Ok(s) => s,
             Err(err) => {
                 tracing::error!("failed to get listeners: {}", err);
 
-                return listening_clients;
+                return HashMap::new();
             }
         };
 
+        self.get_listeners_in(&state_read).await
+    }
+
+    /// Clients in the channel or listening to it, for callers already holding the state
+    pub async fn get_listeners_in(&self, state_read: &ServerState) -> HashMap<u32, Arc<RwLock<Client>>> {
+        let mut listening_clients = HashMap::new();
+
         for client in state_read.clients.values() {
             {
                 let client_read = match client.read_err().await {
@@ -75,6 +111,11 @@ impl Channel {
         }
 
         for client_id in &self.listeners {
+            // Opus frames cannot be re-gained without transcoding, only a muted listen is honored by not routing any audio
+            if self.get_listener_volume(*client_id) <= 0.0 {
+                continue;
+            }
+
             if let Some(client) = state_read.clients.get(client_id) {
                 listening_clients.insert(*client_id, client.clone());
             }
@@ -83,3 +124,118 @@ impl Channel {
         listening_clients
     }
 }
+
+/// Whether a listener hears a speaker of a channel with the given audio range, listeners without a known position always hear it
+pub fn is_in_audio_range(range: f32, speaker: [f32; 3], listener: Option<[f32; 3]>) -> bool {
+    let listener = match listener {
+        Some(listener) => listener,
+        None => return true,
+    };
+
+    let distance: f32 = speaker.iter().zip(listener.iter()).map(|(a, b)| (a - b) * (a - b)).sum();
+
+    distance.sqrt() <= range
+}
+
+/// Split a `channel:value` config on its last colon, so channel names may contain one
+fn split_channel_value<'a>(value: &'a str, kind: &str) -> Result<(&'a str, &'a str), String> {
+    match value.rsplit_once(':') {
+        Some((channel, value)) if !channel.is_empty() => Ok((channel, value)),
+        _ => Err(format!("invalid channel {} {}, expected channel_name:{}", kind, value, kind)),
+    }
+}
+
+/// Audio range of a channel, written as `channel_name:range`
+///
+/// Channels are given by name as ids are reused once a temporary channel is removed, e.g. `Hospital:15.5`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ChannelRangeConfig {
+    pub channel: String,
+    pub range: f32,
+}
+
+impl FromStr for ChannelRangeConfig {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (channel, range) = split_channel_value(value, "range")?;
+
+        let range = match range.parse::<f32>() {
+            Ok(range) if range.is_finite() && range >= 0.0 => range,
+            _ => return Err(format!("invalid channel range {}, expected a positive number", range)),
+        };
+
+        Ok(Self {
+            channel: channel.to_string(),
+            range,
+        })
+    }
+}
+
+impl TryFrom<String> for ChannelRangeConfig {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for ChannelRangeConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.channel, self.range)
+    }
+}
+
+impl From<ChannelRangeConfig> for String {
+    fn from(value: ChannelRangeConfig) -> Self {
+        value.to_string()
+    }
+}
+
+/// Max number of clients in a channel, written as `channel_name:capacity`
+///
+/// e.g. `Car:2`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ChannelCapacityConfig {
+    pub channel: String,
+    pub capacity: usize,
+}
+
+impl FromStr for ChannelCapacityConfig {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (channel, capacity) = split_channel_value(value, "capacity")?;
+
+        let capacity = capacity
+            .parse()
+            .map_err(|_| format!("invalid channel capacity {}, expected a number", capacity))?;
+
+        Ok(Self {
+            channel: channel.to_string(),
+            capacity,
+        })
+    }
+}
+
+impl TryFrom<String> for ChannelCapacityConfig {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for ChannelCapacityConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.channel, self.capacity)
+    }
+}
+
+impl From<ChannelCapacityConfig> for String {
+    fn from(value: ChannelCapacityConfig) -> Self {
+        value.to_string()
+    }
+}
@@ -1,15 +1,25 @@
+use crate::client::Client;
 use crate::error::MumbleError;
-use crate::message::ClientMessage;
+use crate::message::{ClientMessage, DisconnectReason};
 use crate::state::ServerState;
 use crate::sync::RwLock;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+/// Empty temporary channels are only removed once they are older than this, so their creator has time to join them
+const EMPTY_CHANNEL_MIN_AGE: Duration = Duration::from_secs(10);
 
 pub async fn clean_loop(state: Arc<RwLock<ServerState>>) {
+    let mut last_run = Instant::now();
+
     loop {
         tracing::trace!("cleaning clients");
 
-        match clean_run(state.clone()).await {
+        let elapsed = last_run.elapsed();
+        last_run = Instant::now();
+
+        match clean_run(state.clone(), elapsed).await {
             Ok(_) => (),
             Err(e) => {
                 tracing::error!("error in clean loop: {}", e);
@@ -20,18 +30,86 @@ pub async fn clean_loop(state: Arc<RwLock<ServerState>>) {
     }
 }
 
-async fn clean_run(state: Arc<RwLock<ServerState>>) -> Result<(), MumbleError> {
+/// Whether a client stayed in an idle channel without speaking for longer than the idle timeout
+///
+/// Clients in a listen only channel or already in the channel idle clients are moved to are never idle.
+async fn is_idle(state: &ServerState, client: &Arc<RwLock<Client>>) -> Result<bool, MumbleError> {
+    if state.idle_timeout.is_zero() {
+        return Ok(false);
+    }
+
+    let idle_move_channel = state.idle_move_channel.unwrap_or(state.default_channel);
+
+    if !state.channels.contains_key(&idle_move_channel) {
+        return Ok(false);
+    }
+
+    let (channel_id, idle_duration) = {
+        let client_read = client.read_err().await?;
+
+        (client_read.channel_id.load(Ordering::Relaxed), client_read.idle_duration())
+    };
+
+    if channel_id == idle_move_channel || idle_duration < state.idle_timeout {
+        return Ok(false);
+    }
+
+    let (idle, speak_allowed) = match state.channels.get(&channel_id) {
+        Some(channel) => {
+            let channel_read = channel.read_err().await?;
+
+            (channel_read.idle, channel_read.speak_allowed)
+        }
+        None => return Ok(false),
+    };
+
+    Ok(idle && speak_allowed)
+}
+
+/// `elapsed` is the time since the previous run, used to compute the packet rates of the clients
+async fn clean_run(state: Arc<RwLock<ServerState>>, elapsed: Duration) -> Result<(), MumbleError> {
     let mut client_to_delete = Vec::new();
     let mut client_to_disconnect = Vec::new();
+    let mut client_to_rekey = Vec::new();
+    let mut client_to_move = Vec::new();
 
     {
-        for client in state.read_err().await?.clients.values() {
+        let state_read = state.read_err().await?;
+
+        for client in state_read.clients.values() {
             if client.read_err().await?.publisher.is_closed() {
                 client_to_disconnect.push(client.clone());
 
                 continue;
             }
 
+            {
+                let client_read = client.read_err().await?;
+                client_read.voice_packets_in.sample(elapsed);
+                client_read.voice_packets_out.sample(elapsed);
+
+                let (late, lost) = {
+                    let crypt_state = client_read.crypt_state.read_err().await?;
+
+                    (crypt_state.late, crypt_state.lost)
+                };
+
+                client_read.crypt_late.sample_total(late as u64, elapsed);
+                client_read.crypt_lost.sample_total(lost as u64, elapsed);
+            }
+
+            if !state_read.crypt_rekey_interval.is_zero() {
+                let last_rekey = { client.read_err().await?.crypt_state.read_err().await?.last_rekey };
+
+                if last_rekey.elapsed() >= state_read.crypt_rekey_interval {
+                    client_to_rekey.push(client.clone());
+                }
+            }
+
+            if is_idle(&state_read, client).await? {
+                client_to_move.push(client.clone());
+            }
+
             let now = Instant::now();
 
             let duration = { now.duration_since(*client.read_err().await?.last_ping.read_err().await?) };
@@ -46,7 +124,12 @@ async fn clean_run(state: Arc<RwLock<ServerState>>) -> Result<(), MumbleError> {
         {
             let username = { client.read_err().await?.authenticate.get_username().to_string() };
 
-            match client.read_err().await?.publisher.try_send(ClientMessage::Disconnect) {
+            match client
+                .read_err()
+                .await?
+                .publisher
+                .try_send(ClientMessage::Disconnect(DisconnectReason::PingTimeout))
+            {
                 Ok(_) => (),
                 Err(err) => {
                     tracing::error!("error sending disconnect signal to {}: {}", username, err);
@@ -55,15 +138,69 @@ async fn clean_run(state: Arc<RwLock<ServerState>>) -> Result<(), MumbleError> {
         };
     }
 
+    for client in client_to_rekey {
+        let client_read = client.read_err().await?;
+
+        tracing::debug!("rekey crypt state of {}", client_read.authenticate.get_username());
+
+        if let Err(err) = client_read.rekey().await {
+            tracing::error!("error rekeying {}: {}", client_read.authenticate.get_username(), err);
+        }
+    }
+
+    let idle_move_channel = {
+        let state_read = state.read_err().await?;
+
+        state_read.idle_move_channel.unwrap_or(state_read.default_channel)
+    };
+
+    for client in client_to_move {
+        let leave_channel_id = {
+            state
+                .read_err()
+                .await?
+                .set_client_channel(client.clone(), idle_move_channel)
+                .await?
+        };
+
+        tracing::info!(
+            "moved idle client {} to channel {}",
+            client.read_err().await?.authenticate.get_username(),
+            idle_move_channel
+        );
+
+        if let Some(leave_channel_id) = leave_channel_id {
+            state.write_err().await?.remove_channel(leave_channel_id);
+        }
+    }
+
     for client in client_to_disconnect {
         let (user_id, channel_id) = { state.write_err().await?.disconnect(client).await? };
 
         crate::metrics::CLIENTS_TOTAL.dec();
 
-        {
-            state.read_err().await?.remove_client(user_id, channel_id).await?;
+        let leave_channel_id = { state.read_err().await?.remove_client(user_id, channel_id).await? };
+
+        if let Some(leave_channel_id) = leave_channel_id {
+            state.write_err().await?.remove_channel(leave_channel_id);
         }
     }
 
+    {
+        state.read_err().await?.connection_limiter.prune(Instant::now());
+    }
+
+    let removed_channels = {
+        state
+            .write_err()
+            .await?
+            .remove_empty_temporary_channels(EMPTY_CHANNEL_MIN_AGE)
+            .await?
+    };
+
+    for channel_id in removed_channels {
+        tracing::debug!("removed empty temporary channel {}", channel_id);
+    }
+
     Ok(())
 }
@@ -1,55 +1,209 @@
+use crate::context_action::ContextActionConfig;
 use crate::crypt::CryptState;
 use crate::error::MumbleError;
-use crate::message::ClientMessage;
-use crate::proto::mumble::{Authenticate, ServerConfig, ServerSync, UDPTunnel, UserState, Version};
-use crate::proto::{expected_message, message_to_bytes, send_message, MessageKind};
+use crate::message::{ClientMessage, DisconnectReason, Publisher};
+use crate::proto::mumble::{
+    Authenticate, PermissionDenied, PermissionDenied_DenyType, Reject, Reject_RejectType, ServerConfig, ServerSync, SuggestConfig,
+    UserState, Version,
+};
+use crate::proto::{expected_message, message_to_bytes, send_message, set_recording_allowed, tunnel_to_bytes, MessageKind};
+use crate::rate::PacketRate;
+use crate::registry::RegisteredUser;
 use crate::sync::RwLock;
-use crate::target::VoiceTarget;
+use crate::talking::TalkingState;
+use crate::target::{VoiceTarget, VOICE_TARGET_COUNT};
+use crate::tls::get_fingerprint;
 use crate::voice::{encode_voice_packet, Clientbound, VoicePacket};
 use crate::ServerState;
 use bytes::BytesMut;
 use protobuf::Message;
+use std::collections::HashSet;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::io::{AsyncWriteExt, WriteHalf};
 use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::mpsc::Sender;
 use tokio::time::timeout;
 use tokio_rustls::server::TlsStream;
 
+/// Oldest supported client version, 1.2.0 encoded as `major << 16 | minor << 8 | patch`
+const MIN_CLIENT_VERSION: u32 = 0x010200;
+const MAX_USERNAME_LENGTH: usize = 128;
+/// Consecutive udp send failures after which voice is only sent through the tcp tunnel
+const MAX_UDP_SEND_FAILURES: u32 = 10;
+/// Consecutive udp packets failing the mac check after which the crypt setup is reset
+const MAX_CRYPT_MAC_FAILURES: u32 = 5;
+
+/// A protocol violation during the handshake is reported to the client instead of dropping the connection
+fn reject_unexpected(err: MumbleError) -> MumbleError {
+    match err {
+        MumbleError::UnexpectedMessageKind(kind) => {
+            MumbleError::Rejected(Reject_RejectType::None, format!("Unexpected message {} during handshake", kind))
+        }
+        err => err,
+    }
+}
+
 pub struct Client {
     pub version: Version,
     pub authenticate: Authenticate,
     pub session_id: u32,
+    /// Stable id of a registered user, anonymous clients have none
+    pub user_id: Option<u32>,
     pub channel_id: AtomicU32,
-    pub mute: bool,
-    pub deaf: bool,
+    /// Set by an admin, the client cannot change it
+    pub server_mute: bool,
+    pub server_deaf: bool,
+    /// Set by the client itself through its user state
+    pub self_mute: bool,
+    pub self_deaf: bool,
     pub write: RwLock<WriteHalf<TlsStream<TcpStream>>>,
     pub tokens: Vec<String>,
     pub crypt_state: Arc<RwLock<CryptState>>,
+    pub tcp_socket_addr: SocketAddr,
     pub udp_socket_addr: Option<SocketAddr>,
+    /// Consecutive voice packets that could not be sent over udp
+    pub udp_send_failures: AtomicU32,
+    /// Consecutive udp packets that failed the mac check, a single corrupted datagram does not reset the crypt
+    pub crypt_mac_failures: AtomicU32,
+    /// Whether the last voice packet of this client had positional data
+    pub has_positional: AtomicBool,
+    /// Position sent in the last positional voice packet of this client
+    pub position: Mutex<Option<[f32; 3]>>,
+    /// Positional audio plugin context, only clients sharing it are in the same game
+    pub plugin_context: Vec<u8>,
+    pub plugin_identity: String,
+    /// Voice packets received from this client, over udp or the tcp tunnel
+    pub voice_packets_in: PacketRate,
+    /// Voice packets sent to this client, over udp or the tcp tunnel
+    pub voice_packets_out: PacketRate,
+    /// Late and lost udp packets reported by the crypt state, sampled by the clean loop to get their rates
+    pub crypt_late: PacketRate,
+    pub crypt_lost: PacketRate,
+    /// Whether this client is currently talking, derived from its audio packets
+    pub talking: TalkingState,
+    /// Last audio packet of this client, or its last channel change, used to move idle clients out of their channel
+    pub last_audio: Mutex<Instant>,
     pub use_opus: bool,
     pub codecs: Vec<i32>,
     pub udp_socket: Arc<UdpSocket>,
-    pub publisher: Sender<ClientMessage>,
+    pub publisher: Publisher,
+    /// A tcp write taking longer than this means the connection is dead, the client is then disconnected
+    pub write_timeout: Duration,
+    /// Combine queued opus packets of the same speaker before sending them
+    pub voice_batching: bool,
+    /// Voice is dropped instead of being tunneled over tcp when disabled
+    pub tcp_tunnel: bool,
     pub targets: Vec<Arc<RwLock<VoiceTarget>>>,
+    /// Sessions this client receives the voice of, wherever they speak
+    pub listened_users: HashSet<u32>,
+    /// Whether the client was told it cannot speak in its listen only channel, reset when it changes channel
+    pub speak_denied: AtomicBool,
     pub last_ping: RwLock<Instant>,
 }
 
 impl Client {
+    /// Run the connection handshake, a `Reject` with the reason is sent to the client if it is refused
     pub async fn init(
         stream: &mut TlsStream<TcpStream>,
         server_version: Version,
-    ) -> Result<(Version, Authenticate, CryptState), MumbleError> {
-        let version: Version = expected_message(MessageKind::Version, stream, 0).await?;
+        state: &Arc<RwLock<ServerState>>,
+    ) -> Result<(Version, Authenticate, CryptState, Option<RegisteredUser>), MumbleError> {
+        let handshake_timeout = { state.read_err().await?.handshake_timeout };
+
+        let result = match timeout(handshake_timeout, Self::handshake(stream, server_version, state)).await {
+            Ok(result) => result,
+            Err(_) => return Err(MumbleError::Timeout),
+        };
+
+        match result {
+            Err(MumbleError::Rejected(reject_type, reason)) => {
+                let mut reject = Reject::new();
+                reject.set_field_type(reject_type);
+                reject.set_reason(reason.clone());
+
+                send_message(MessageKind::Reject, &reject, stream).await?;
+
+                Err(MumbleError::Rejected(reject_type, reason))
+            }
+            result => result,
+        }
+    }
+
+    async fn handshake(
+        stream: &mut TlsStream<TcpStream>,
+        server_version: Version,
+        state: &Arc<RwLock<ServerState>>,
+    ) -> Result<(Version, Authenticate, CryptState, Option<RegisteredUser>), MumbleError> {
+        let version: Version = expected_message(MessageKind::Version, stream).await.map_err(reject_unexpected)?;
 
         // Send version
         send_message(MessageKind::Version, &server_version, stream).await?;
 
+        if version.has_version() && version.get_version() < MIN_CLIENT_VERSION {
+            return Err(MumbleError::Rejected(
+                Reject_RejectType::WrongVersion,
+                "Your client is too old, Mumble 1.2.0 or newer is required".to_string(),
+            ));
+        }
+
         // Get authenticate
-        let authenticate: Authenticate = expected_message(MessageKind::Authenticate, stream, 0).await?;
+        let authenticate: Authenticate = expected_message(MessageKind::Authenticate, stream)
+            .await
+            .map_err(reject_unexpected)?;
+
+        // Reject banned peers
+        let address = stream.get_ref().0.peer_addr()?.ip();
+        let ban_reason = { state.read_err().await?.bans.is_banned(&address).map(|ban| ban.reason.clone()) };
+
+        if let Some(reason) = ban_reason {
+            let mut reject = Reject::new();
+            reject.set_field_type(Reject_RejectType::None);
+            reject.set_reason(reason.unwrap_or_else(|| "You are banned from this server".to_string()));
+
+            send_message(MessageKind::Reject, &reject, stream).await?;
+
+            return Err(MumbleError::Banned(address));
+        }
+
+        let username = authenticate.get_username();
+
+        if username.trim().is_empty() || username.len() > MAX_USERNAME_LENGTH {
+            return Err(MumbleError::Rejected(
+                Reject_RejectType::InvalidUsername,
+                format!(
+                    "Invalid username, it must not be empty nor longer than {} bytes",
+                    MAX_USERNAME_LENGTH
+                ),
+            ));
+        }
+
+        let (clients, max_clients) = {
+            let state_read = state.read_err().await?;
+
+            (state_read.clients.len(), state_read.max_clients)
+        };
+
+        if max_clients > 0 && clients >= max_clients as usize {
+            return Err(MumbleError::Rejected(Reject_RejectType::ServerFull, "Server is full".to_string()));
+        }
+
+        let fingerprint = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(get_fingerprint);
+        let registered = {
+            state
+                .read_err()
+                .await?
+                .registry
+                .as_ref()
+                .and_then(|registry| registry.find(fingerprint.as_deref(), authenticate.get_tokens()))
+                .cloned()
+        };
 
         let crypt = CryptState::default();
         let crypt_setup = crypt.get_crypt_setup();
@@ -57,9 +211,10 @@ impl Client {
         // Send crypt setup
         send_message(MessageKind::CryptSetup, &crypt_setup, stream).await?;
 
-        Ok((version, authenticate, crypt))
+        Ok((version, authenticate, crypt, registered))
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         version: Version,
         authenticate: Authenticate,
@@ -67,30 +222,55 @@ impl Client {
         channel_id: u32,
         crypt_state: CryptState,
         write: WriteHalf<TlsStream<TcpStream>>,
+        tcp_socket_addr: SocketAddr,
         udp_socket: Arc<UdpSocket>,
-        publisher: Sender<ClientMessage>,
+        publisher: Publisher,
+        write_timeout: Duration,
+        voice_batching: bool,
+        tcp_tunnel: bool,
     ) -> Self {
         let tokens = authenticate.get_tokens().iter().map(|token| token.to_string()).collect();
-        let mut targets = Vec::with_capacity(30);
-        targets.resize_with(30, Default::default);
+        let mut targets = Vec::with_capacity(VOICE_TARGET_COUNT as usize);
+        targets.resize_with(VOICE_TARGET_COUNT as usize, Default::default);
 
         Self {
             version,
             session_id,
+            user_id: None,
             channel_id: AtomicU32::new(channel_id),
             crypt_state: Arc::new(RwLock::new(crypt_state)),
-            write: RwLock::new(write),
+            write: RwLock::with_label(write, "client_write"),
             tokens,
-            deaf: false,
-            mute: false,
+            server_deaf: false,
+            server_mute: false,
+            self_deaf: false,
+            self_mute: false,
+            tcp_socket_addr,
             udp_socket_addr: None,
+            udp_send_failures: AtomicU32::new(0),
+            crypt_mac_failures: AtomicU32::new(0),
+            has_positional: AtomicBool::new(false),
+            position: Mutex::new(None),
+            plugin_context: Vec::new(),
+            plugin_identity: String::new(),
+            voice_packets_in: PacketRate::default(),
+            voice_packets_out: PacketRate::default(),
+            crypt_late: PacketRate::default(),
+            crypt_lost: PacketRate::default(),
+            talking: TalkingState::default(),
+            last_audio: Mutex::new(Instant::now()),
             use_opus: if authenticate.has_opus() { authenticate.get_opus() } else { false },
             codecs: authenticate.get_celt_versions().to_vec(),
             authenticate,
             udp_socket,
             publisher,
+            write_timeout,
+            voice_batching,
+            tcp_tunnel,
             targets,
-            last_ping: RwLock::new(Instant::now()),
+            listened_users: HashSet::new(),
+            speak_denied: AtomicBool::new(false),
+            last_ping: RwLock::with_label(Instant::now(), "client_last_ping"),
         }
     }
 
@@ -99,19 +279,38 @@ impl Client {
     }
 
     pub async fn send(&self, data: &[u8]) -> Result<(), MumbleError> {
-        match timeout(Duration::from_secs(1), self.write.write_err().await?.write_all(data)).await {
+        match timeout(self.write_timeout, self.write.write_err().await?.write_all(data)).await {
             Ok(Ok(())) => Ok(()),
             Ok(Err(e)) => Err(MumbleError::Io(e)),
-            Err(_) => Err(MumbleError::Timeout),
+            Err(_) => {
+                // The stream may be left with a partial message, the connection cannot be used anymore
+                tracing::warn!("write timeout for client {}, disconnecting", self.authenticate.get_username());
+
+                if let Err(e) = self.publisher.try_send(ClientMessage::Disconnect(DisconnectReason::IoError)) {
+                    tracing::error!("error sending disconnect signal to {}: {}", self.authenticate.get_username(), e);
+                }
+
+                Err(MumbleError::Timeout)
+            }
         }
     }
 
-    pub fn mute(&mut self, mute: bool) {
-        self.mute = mute;
+    pub fn server_mute(&mut self, mute: bool) {
+        self.server_mute = mute;
     }
 
-    pub fn deaf(&mut self, deaf: bool) {
-        self.deaf = deaf;
+    pub fn server_deaf(&mut self, deaf: bool) {
+        self.server_deaf = deaf;
+    }
+
+    /// Whether the voice of this client must not be routed, muted either by an admin or by itself
+    pub fn is_muted(&self) -> bool {
+        self.server_mute || self.self_mute
+    }
+
+    /// Whether this client must not receive any voice, deafened either by an admin or by itself
+    pub fn is_deaf(&self) -> bool {
+        self.server_deaf || self.self_deaf
     }
 
     pub async fn send_message<T: Message>(&self, kind: MessageKind, message: &T) -> Result<(), MumbleError> {
@@ -138,6 +337,26 @@ impl Client {
         Ok(())
     }
 
+    pub async fn send_permission_denied(&self, deny_type: PermissionDenied_DenyType, reason: &str) -> Result<(), MumbleError> {
+        let mut permission_denied = PermissionDenied::new();
+        permission_denied.set_field_type(deny_type);
+        permission_denied.set_session(self.session_id);
+        permission_denied.set_reason(reason.to_string());
+
+        self.send_message(MessageKind::PermissionDenied, &permission_denied).await
+    }
+
+    /// Notify the client it is missing a permission in a channel
+    pub async fn send_channel_permission_denied(&self, permission: u32, channel_id: u32) -> Result<(), MumbleError> {
+        let mut permission_denied = PermissionDenied::new();
+        permission_denied.set_field_type(PermissionDenied_DenyType::Permission);
+        permission_denied.set_session(self.session_id);
+        permission_denied.set_permission(permission);
+        permission_denied.set_channel_id(channel_id);
+
+        self.send_message(MessageKind::PermissionDenied, &permission_denied).await
+    }
+
     pub async fn send_crypt_setup(&self, reset: bool) -> Result<(), MumbleError> {
         if reset {
             {
@@ -150,6 +369,15 @@ impl Client {
         self.send_message(MessageKind::CryptSetup, &crypt_setup).await
     }
 
+    /// Replace the crypt key of the client, and send it the new crypt setup
+    pub async fn rekey(&self) -> Result<(), MumbleError> {
+        {
+            self.crypt_state.write_err().await?.rekey();
+        }
+
+        self.send_crypt_setup(false).await
+    }
+
     pub async fn send_my_user_state(&self) -> Result<(), MumbleError> {
         let user_state = self.get_user_state();
 
@@ -178,24 +406,45 @@ impl Client {
         Ok(())
     }
 
-    pub async fn send_server_sync(&self) -> Result<(), MumbleError> {
+    pub async fn send_server_sync(&self, max_bandwidth: u32) -> Result<(), MumbleError> {
         let mut server_sync = ServerSync::default();
-        server_sync.set_max_bandwidth(144000);
+        server_sync.set_max_bandwidth(max_bandwidth);
         server_sync.set_session(self.session_id);
         server_sync.set_welcome_text("SoZ Mumble Server".to_string());
 
         self.send_message(MessageKind::ServerSync, &server_sync).await
     }
 
-    pub async fn send_server_config(&self) -> Result<(), MumbleError> {
+    /// `max_users` is left unset when the number of clients is unlimited
+    pub async fn send_server_config(&self, max_bandwidth: u32, max_users: u32, recording_allowed: bool) -> Result<(), MumbleError> {
         let mut server_config = ServerConfig::default();
+        server_config.set_max_bandwidth(max_bandwidth);
         server_config.set_allow_html(true);
         server_config.set_message_length(512);
         server_config.set_image_message_length(0);
 
+        if max_users > 0 {
+            server_config.set_max_users(max_users);
+        }
+
+        set_recording_allowed(&mut server_config, recording_allowed);
+
         self.send_message(MessageKind::ServerConfig, &server_config).await
     }
 
+    pub async fn send_suggest_config(&self, suggest_config: &SuggestConfig) -> Result<(), MumbleError> {
+        self.send_message(MessageKind::SuggestConfig, suggest_config).await
+    }
+
+    pub async fn send_context_actions(&self, context_actions: &[ContextActionConfig]) -> Result<(), MumbleError> {
+        for context_action in context_actions {
+            self.send_message(MessageKind::ContextActionModify, &context_action.get_context_action_modify())
+                .await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn send_voice_packet(&self, packet: VoicePacket<Clientbound>) -> Result<(), MumbleError> {
         if let Some(addr) = self.udp_socket_addr {
             let mut dest = BytesMut::new();
@@ -204,50 +453,154 @@ impl Client {
             let buf = &dest.freeze()[..];
 
             match timeout(Duration::from_secs(1), self.udp_socket.send_to(buf, addr)).await {
-                Ok(Ok(_)) => Ok(()),
-                Ok(Err(e)) => Err(MumbleError::Io(e)),
-                Err(_) => Err(MumbleError::Timeout),
-            }?;
+                Ok(Ok(_)) => {
+                    self.udp_send_failures.store(0, Ordering::Relaxed);
+                    self.voice_packets_out.inc();
+
+                    crate::metrics::MESSAGES_TOTAL
+                        .with_label_values(&["udp", "output", "VoicePacket"])
+                        .inc();
+
+                    crate::metrics::MESSAGES_BYTES
+                        .with_label_values(&["udp", "output", "VoicePacket"])
+                        .inc_by(buf.len() as u64);
+
+                    return Ok(());
+                }
+                Ok(Err(e)) => tracing::debug!("udp send error to {}: {}", addr, e),
+                Err(_) => tracing::debug!("udp send timeout to {}", addr),
+            }
 
-            crate::metrics::MESSAGES_TOTAL
-                .with_label_values(&["udp", "output", "VoicePacket"])
-                .inc();
+            // Do not lose the packet, the tcp tunnel is still usable unless it is disabled
+            self.udp_send_failures.fetch_add(1, Ordering::Relaxed);
+        }
 
-            crate::metrics::MESSAGES_BYTES
-                .with_label_values(&["udp", "output", "VoicePacket"])
-                .inc_by(buf.len() as u64);
+        if !self.tcp_tunnel {
+            crate::metrics::TCP_TUNNEL_DROPPED_PACKETS_TOTAL
+                .with_label_values(&["output"])
+                .inc();
 
             return Ok(());
         }
 
+        self.send_tunnel_voice_packet(packet).await?;
+        self.voice_packets_out.inc();
+
+        Ok(())
+    }
+
+    pub fn set_positional(&self, has_positional: bool) {
+        if self.has_positional.swap(has_positional, Ordering::Relaxed) == has_positional {
+            return;
+        }
+
+        if has_positional {
+            crate::metrics::POSITIONAL_CLIENTS_TOTAL.inc();
+        } else {
+            crate::metrics::POSITIONAL_CLIENTS_TOTAL.dec();
+        }
+    }
+
+    pub fn set_position(&self, position: Option<[f32; 3]>) {
+        match self.position.lock() {
+            Ok(mut current) => *current = position,
+            Err(err) => tracing::error!("client position lock poisoned: {}", err),
+        }
+    }
+
+    pub fn get_position(&self) -> Option<[f32; 3]> {
+        self.position.lock().ok().and_then(|position| *position)
+    }
+
+    pub fn touch_audio(&self) {
+        match self.last_audio.lock() {
+            Ok(mut last_audio) => *last_audio = Instant::now(),
+            Err(err) => tracing::error!("client last audio lock poisoned: {}", err),
+        }
+    }
+
+    /// Time since the last audio packet or channel change of this client
+    pub fn idle_duration(&self) -> Duration {
+        self.last_audio.lock().map(|last_audio| last_audio.elapsed()).unwrap_or_default()
+    }
+
+    /// Whether udp sends failed too many times in a row, the udp address should then be forgotten
+    pub fn is_udp_dead(&self) -> bool {
+        self.udp_socket_addr.is_some() && self.udp_send_failures.load(Ordering::Relaxed) >= MAX_UDP_SEND_FAILURES
+    }
+
+    /// Record an udp packet failing the mac check, returns whether failures are sustained enough to reset the crypt
+    pub fn crypt_mac_failure(&self) -> bool {
+        self.crypt_mac_failures.fetch_add(1, Ordering::Relaxed) + 1 >= MAX_CRYPT_MAC_FAILURES
+    }
+
+    /// Called once an udp packet is decrypted or the crypt setup is reset
+    pub fn reset_crypt_mac_failures(&self) {
+        self.crypt_mac_failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Send a voice packet over tcp, inside an UDPTunnel message
+    pub async fn send_tunnel_voice_packet(&self, packet: VoicePacket<Clientbound>) -> Result<(), MumbleError> {
         let mut data = BytesMut::new();
         encode_voice_packet(&packet, &mut data);
-        let bytes = data.freeze();
 
-        let mut tunnel_message = UDPTunnel::default();
-        tunnel_message.set_packet(bytes.to_vec());
+        let bytes = tunnel_to_bytes(&data);
+
+        self.send(bytes.as_ref()).await?;
+
+        crate::metrics::MESSAGES_TOTAL
+            .with_label_values(&["tcp", "output", "UDPTunnel"])
+            .inc();
+
+        crate::metrics::MESSAGES_BYTES
+            .with_label_values(&["tcp", "output", "UDPTunnel"])
+            .inc_by(bytes.len() as u64);
 
-        self.send_message(MessageKind::UDPTunnel, &tunnel_message).await
+        Ok(())
     }
 
-    pub fn update(&mut self, state: &UserState) {
-        if state.has_mute() {
-            self.mute = state.get_mute();
+    /// Update the client from its own user state, returns whether something visible to other clients changed
+    ///
+    /// Server mute and deaf are only set by an admin, a client can only change its self mute and self deaf flags
+    pub fn update(&mut self, state: &UserState) -> bool {
+        let mut changed = false;
+
+        if state.has_self_mute() && state.get_self_mute() != self.self_mute {
+            self.self_mute = state.get_self_mute();
+            changed = true;
         }
 
-        if state.has_deaf() {
-            self.deaf = state.get_deaf();
+        if state.has_self_deaf() && state.get_self_deaf() != self.self_deaf {
+            self.self_deaf = state.get_self_deaf();
+            changed = true;
         }
+
+        if state.has_plugin_context() && state.get_plugin_context() != self.plugin_context.as_slice() {
+            self.plugin_context = state.get_plugin_context().to_vec();
+            changed = true;
+        }
+
+        if state.has_plugin_identity() && state.get_plugin_identity() != self.plugin_identity {
+            self.plugin_identity = state.get_plugin_identity().to_string();
+            changed = true;
+        }
+
+        changed
     }
 
+    /// Move the client to a channel, returns the previous channel if it changed
+    ///
+    /// Channel id is swapped in a single atomic operation, so concurrent moves each see the channel they left
     pub fn join_channel(&self, channel_id: u32) -> Option<u32> {
-        let current_channel = self.channel_id.load(Ordering::Relaxed);
+        let current_channel = self.channel_id.swap(channel_id, Ordering::Relaxed);
 
         if channel_id == current_channel {
             return None;
         }
 
-        self.channel_id.store(channel_id, Ordering::Relaxed);
+        self.speak_denied.store(false, Ordering::Relaxed);
+        // The idle time is counted in the new channel only
+        self.touch_audio();
 
         Some(current_channel)
     }
@@ -255,10 +608,28 @@ impl Client {
     pub fn get_user_state(&self) -> UserState {
         let mut user_state = UserState::new();
 
-        user_state.set_user_id(self.session_id);
+        // Anonymous clients have no user id, like on murmur, mumble clients key local settings on it so it must not collide with a registered one
+        if let Some(user_id) = self.user_id {
+            user_state.set_user_id(user_id);
+        }
         user_state.set_channel_id(self.channel_id.load(Ordering::Relaxed));
         user_state.set_session(self.session_id);
         user_state.set_name(self.authenticate.get_username().to_string());
+        user_state.set_mute(self.server_mute);
+        // Clients only show that they cannot transmit when suppressed, a server mute alone just shows the mute icon
+        user_state.set_suppress(self.server_mute);
+        user_state.set_deaf(self.server_deaf);
+        user_state.set_self_mute(self.self_mute);
+        user_state.set_self_deaf(self.self_deaf);
+
+        // Relayed so clients can also compare contexts before applying positional audio
+        if !self.plugin_context.is_empty() {
+            user_state.set_plugin_context(self.plugin_context.clone());
+        }
+
+        if !self.plugin_identity.is_empty() {
+            user_state.set_plugin_identity(self.plugin_identity.clone());
+        }
 
         user_state
     }
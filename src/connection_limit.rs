@@ -0,0 +1,98 @@
+//! Connection attempts per source ip, so a reconnect storm does not cost a tls handshake for each attempt
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Window over which the connection attempts of an ip are counted
+pub const CONNECTION_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Default, Debug)]
+pub struct ConnectionLimiter {
+    /// Max connection attempts of an ip in a window, 0 means unlimited
+    max_per_window: u32,
+    /// Start of the current window and attempts in it, by ip
+    attempts: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_window: u32) -> Self {
+        Self {
+            max_per_window,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a connection attempt, returns whether it is allowed
+    ///
+    /// Rejected attempts are counted too, a client looping on reconnect stays rejected until it slows down
+    pub fn allow(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.max_per_window == 0 {
+            return true;
+        }
+
+        let mut attempts = match self.attempts.lock() {
+            Ok(attempts) => attempts,
+            Err(err) => {
+                tracing::error!("connection limiter lock poisoned: {}", err);
+
+                return true;
+            }
+        };
+
+        let (window_start, count) = attempts.entry(ip).or_insert((now, 0));
+
+        if now.saturating_duration_since(*window_start) >= CONNECTION_LIMIT_WINDOW {
+            *window_start = now;
+            *count = 0;
+        }
+
+        *count = count.saturating_add(1);
+
+        *count <= self.max_per_window
+    }
+
+    /// Forget the ips whose window is over
+    pub fn prune(&self, now: Instant) {
+        if let Ok(mut attempts) = self.attempts.lock() {
+            attempts.retain(|_, (window_start, _)| now.saturating_duration_since(*window_start) < CONNECTION_LIMIT_WINDOW);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.attempts.lock().map(|attempts| attempts.len()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn attempts_are_limited_per_ip_and_window() {
+        let limiter = ConnectionLimiter::new(2);
+        let first = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let second = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let start = Instant::now();
+
+        assert!(limiter.allow(first, start));
+        assert!(limiter.allow(first, start));
+        assert!(!limiter.allow(first, start + Duration::from_secs(1)));
+        assert!(limiter.allow(second, start + Duration::from_secs(1)));
+
+        assert!(limiter.allow(first, start + CONNECTION_LIMIT_WINDOW));
+
+        limiter.prune(start + CONNECTION_LIMIT_WINDOW + Duration::from_secs(1));
+        assert_eq!(limiter.len(), 1);
+
+        let unlimited = ConnectionLimiter::default();
+        assert!((0..100).all(|_| unlimited.allow(first, start)));
+        assert!(unlimited.is_empty());
+    }
+}
@@ -0,0 +1,90 @@
+//! Server defined context menu actions, registered on the clients when they connect
+
+use crate::proto::mumble::{ContextActionModify, ContextActionModify_Context, ContextActionModify_Operation};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// A context menu action, written as `context:action:text` where context is a `+` separated list of `server`, `channel` and `user`
+///
+/// e.g. `user:send_dispatch:Send to dispatch`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ContextActionConfig {
+    pub action: String,
+    pub text: String,
+    pub context: u32,
+}
+
+impl ContextActionConfig {
+    pub fn get_context_action_modify(&self) -> ContextActionModify {
+        let mut context_action_modify = ContextActionModify::new();
+        context_action_modify.set_action(self.action.clone());
+        context_action_modify.set_text(self.text.clone());
+        context_action_modify.set_context(self.context);
+        context_action_modify.set_operation(ContextActionModify_Operation::Add);
+
+        context_action_modify
+    }
+}
+
+impl FromStr for ContextActionConfig {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.splitn(3, ':');
+
+        let (contexts, action, text) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(contexts), Some(action), Some(text)) if !action.is_empty() && !text.is_empty() => (contexts, action, text),
+            _ => return Err(format!("invalid context action {}, expected context:action:text", value)),
+        };
+
+        let mut context = 0;
+
+        for name in contexts.split('+') {
+            context |= match name {
+                "server" => ContextActionModify_Context::Server as u32,
+                "channel" => ContextActionModify_Context::Channel as u32,
+                "user" => ContextActionModify_Context::User as u32,
+                _ => return Err(format!("invalid context action context {}, expected server, channel or user", name)),
+            };
+        }
+
+        Ok(Self {
+            action: action.to_string(),
+            text: text.to_string(),
+            context,
+        })
+    }
+}
+
+impl TryFrom<String> for ContextActionConfig {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for ContextActionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let contexts = [
+            (ContextActionModify_Context::Server, "server"),
+            (ContextActionModify_Context::Channel, "channel"),
+            (ContextActionModify_Context::User, "user"),
+        ]
+        .iter()
+        .filter(|(context, _)| self.context & *context as u32 != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<&str>>()
+        .join("+");
+
+        write!(f, "{}:{}:{}", contexts, self.action, self.text)
+    }
+}
+
+impl From<ContextActionConfig> for String {
+    fn from(value: ContextActionConfig) -> Self {
+        value.to_string()
+    }
+}
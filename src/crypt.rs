@@ -6,7 +6,7 @@ use aes::cipher::generic_array::GenericArray;
 use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
 use aes::Aes128;
 use ring::rand::{SecureRandom, SystemRandom};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 lazy_static! {
     static ref SYSTEM_RANDOM: SystemRandom = SystemRandom::new();
@@ -14,6 +14,44 @@ lazy_static! {
 
 const KEY_SIZE: usize = 16;
 const BLOCK_SIZE: usize = std::mem::size_of::<u128>();
+/// Bytes of the encrypt nonce sent in clear at the start of each packet, its lowest byte
+pub const NONCE_HEADER_SIZE: usize = 1;
+/// Bytes of the ocb tag kept in the packet header by mumble, the tag is truncated to its first bytes in big endian
+pub const MUMBLE_TAG_SIZE: usize = 3;
+/// Minimum duration between two server initiated crypt resets, avoid resync loops when packets are reordered
+const MIN_RESET_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Layout of the header prepended to each encrypted voice packet
+///
+/// Mumble uses the lowest nonce byte followed by 3 bytes of tag, forks using another tag length can change it here
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CryptCompat {
+    tag_size: usize,
+}
+
+impl CryptCompat {
+    pub const MUMBLE: Self = Self { tag_size: MUMBLE_TAG_SIZE };
+
+    /// Returns `None` if the tag does not fit in an ocb tag
+    pub fn with_tag_size(tag_size: usize) -> Option<Self> {
+        (1..=BLOCK_SIZE).contains(&tag_size).then_some(Self { tag_size })
+    }
+
+    pub fn tag_size(&self) -> usize {
+        self.tag_size
+    }
+
+    /// Bytes before the encrypted voice packet
+    pub fn header_size(&self) -> usize {
+        NONCE_HEADER_SIZE + self.tag_size
+    }
+}
+
+impl Default for CryptCompat {
+    fn default() -> Self {
+        Self::MUMBLE
+    }
+}
 
 pub struct CryptState {
     pub key: [u8; KEY_SIZE],
@@ -22,12 +60,16 @@ pub struct CryptState {
     decrypt_nonce: u128,
     decrypt_history: [u8; 0x100],
     aes: Aes128,
+    compat: CryptCompat,
 
     pub good: u32,
     pub late: u32,
     pub lost: u32,
     pub resync: u32,
     pub last_good: Instant,
+    pub last_resync: Option<Instant>,
+    /// When the current key was generated
+    pub last_rekey: Instant,
 }
 
 impl Default for CryptState {
@@ -41,28 +83,84 @@ impl Default for CryptState {
             encrypt_nonce: 0,
             decrypt_nonce: 1 << 127,
             decrypt_history: [0; 0x100],
+            compat: CryptCompat::default(),
 
             good: 0,
             late: 0,
             lost: 0,
             resync: 0,
             last_good: Instant::now(),
+            last_resync: None,
+            last_rekey: Instant::now(),
         }
     }
 }
 
 impl CryptState {
+    /// Create a crypt state from a known key and nonces, as the other side of a crypt setup would do
+    pub fn new(key: [u8; KEY_SIZE], encrypt_nonce: [u8; BLOCK_SIZE], decrypt_nonce: [u8; BLOCK_SIZE]) -> Self {
+        Self {
+            aes: Aes128::new(GenericArray::from_slice(&key)),
+            key,
+            encrypt_nonce: u128::from_le_bytes(encrypt_nonce),
+            decrypt_nonce: u128::from_le_bytes(decrypt_nonce),
+            decrypt_history: [0; 0x100],
+            compat: CryptCompat::default(),
+
+            good: 0,
+            late: 0,
+            lost: 0,
+            resync: 0,
+            last_good: Instant::now(),
+            last_resync: None,
+            last_rekey: Instant::now(),
+        }
+    }
+
+    /// Use another packet header layout, both sides of the connection must use the same
+    pub fn with_compat(mut self, compat: CryptCompat) -> Self {
+        self.compat = compat;
+        self
+    }
+
     pub fn reset(&mut self) {
+        self.reset_nonces();
+        self.resync += 1;
+        self.last_resync = Some(Instant::now());
+
+        crate::metrics::CRYPT_RESET_TOTAL.inc();
+    }
+
+    /// Generate a new key and reset the nonces, the client must receive the new crypt setup
+    ///
+    /// A rekey is not a resync, it does not count in `resync` and does not delay the next reset
+    pub fn rekey(&mut self) {
+        SYSTEM_RANDOM.fill(&mut self.key).expect("Failed to generate random key");
+        self.aes = Aes128::new(GenericArray::from_slice(&self.key));
+        self.last_rekey = Instant::now();
+        self.reset_nonces();
+
+        crate::metrics::CRYPT_REKEY_TOTAL.inc();
+    }
+
+    fn reset_nonces(&mut self) {
         self.encrypt_nonce = 0;
         self.decrypt_nonce = 1 << 127;
         self.decrypt_history = [0; 0x100];
         self.good = 0;
         self.late = 0;
         self.lost = 0;
-        self.resync = 0;
         self.last_good = Instant::now();
     }
 
+    /// Whether a reset is allowed, there must be at least `MIN_RESET_INTERVAL` since the last one
+    pub fn can_reset(&self) -> bool {
+        match self.last_resync {
+            Some(last_resync) => last_resync.elapsed() >= MIN_RESET_INTERVAL,
+            None => true,
+        }
+    }
+
     /// Returns the nonce used for encrypting.
     pub fn get_encrypt_nonce(&self) -> [u8; BLOCK_SIZE] {
         self.encrypt_nonce.to_le_bytes()
@@ -92,25 +190,28 @@ impl CryptState {
     pub fn encrypt<EncodeDst: VoicePacketDst>(&mut self, packet: &VoicePacket<EncodeDst>, dst: &mut BytesMut) {
         self.encrypt_nonce = self.encrypt_nonce.wrapping_add(1);
 
-        // Leave four bytes for header
-        dst.resize(4, 0);
-        let mut inner = dst.split_off(4);
+        // Leave room for the nonce byte and the tag
+        let header_size = self.compat.header_size();
+        dst.resize(header_size, 0);
+        let mut inner = dst.split_off(header_size);
 
-        encode_voice_packet(&packet, &mut inner);
+        encode_voice_packet(packet, &mut inner);
 
         let tag = self.ocb_encrypt(inner.as_mut());
         dst.unsplit(inner);
 
         dst[0] = self.encrypt_nonce as u8;
-        dst[1..4].copy_from_slice(&tag.to_be_bytes()[0..3]);
+        dst[NONCE_HEADER_SIZE..header_size].copy_from_slice(&tag.to_be_bytes()[..self.compat.tag_size()]);
     }
 
     /// Decrypts a voice packet and (if successful) returns the `Result` of parsing the packet.
     pub fn decrypt<DecodeDst: VoicePacketDst>(&mut self, buf: &mut BytesMut) -> Result<VoicePacket<DecodeDst>, DecryptError> {
-        if buf.len() < 4 {
+        let header_size = self.compat.header_size();
+
+        if buf.len() < header_size {
             return Err(DecryptError::Eof);
         }
-        let header = buf.split_to(4);
+        let header = buf.split_to(header_size);
         let nonce_0 = header[0];
 
         // If we update our decrypt_nonce and the tag check fails or we've been processing late
@@ -146,7 +247,9 @@ impl CryptState {
 
         let tag = self.ocb_decrypt(buf.as_mut());
 
-        if Ok(()) != ring::constant_time::verify_slices_are_equal(&header[1..4], &tag.to_be_bytes()[0..3]) {
+        if Ok(())
+            != ring::constant_time::verify_slices_are_equal(&header[NONCE_HEADER_SIZE..], &tag.to_be_bytes()[..self.compat.tag_size()])
+        {
             self.decrypt_nonce = saved_nonce;
             return Err(DecryptError::Mac);
         }
@@ -155,14 +258,27 @@ impl CryptState {
         self.good += 1;
         self.last_good = Instant::now();
 
+        let packet = decode_voice_packet(buf);
+        // Late keepalives carry no audio, they must not lead to a crypt reset
+        let keepalive = matches!(&packet, Ok(packet) if packet.is_keepalive());
+
         if late {
-            self.late += 1;
             self.decrypt_nonce = saved_nonce;
+
+            if !keepalive {
+                self.late += 1;
+
+                crate::metrics::CRYPT_LATE_TOTAL.inc();
+            }
+        }
+
+        if lost > 0 {
+            crate::metrics::CRYPT_LOST_TOTAL.inc_by(lost as u64);
         }
 
-        self.lost = (self.lost as i32 + lost as i32) as u32;
+        self.lost = (self.lost as i32 + lost) as u32;
 
-        decode_voice_packet(buf)
+        packet
     }
 
     /// Encrypt the provided buffer using AES-OCB, returning the tag.
@@ -260,3 +376,74 @@ fn s2(block: u128) -> u128 {
     let carry = rot & 1;
     rot ^ (carry * 0x86)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voice::{Clientbound, Serverbound, VoicePacketPayload};
+    use bytes::Bytes;
+    use std::marker::PhantomData;
+
+    fn packet() -> VoicePacket<Clientbound> {
+        VoicePacket::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id: 5,
+            seq_num: 1,
+            payload: VoicePacketPayload::Opus(Bytes::from_static(b"zumble"), false),
+            position_info: None,
+        }
+    }
+
+    #[test]
+    fn mumble_layout_is_unchanged() {
+        let mut crypt = CryptState::new([7; KEY_SIZE], [1; BLOCK_SIZE], [2; BLOCK_SIZE]);
+        let mut dst = BytesMut::new();
+        crypt.encrypt(&packet(), &mut dst);
+
+        // Nonce byte, 3 bytes of tag, then the encrypted packet
+        assert_eq!(CryptCompat::default().header_size(), 4);
+        assert_eq!(&dst[..], &[2, 28, 122, 80, 236, 91, 86, 154, 11, 101, 205, 22, 232, 10]);
+    }
+
+    #[test]
+    fn other_tag_size_roundtrips() {
+        let compat = CryptCompat::with_tag_size(8).expect("valid tag size");
+        let mut server = CryptState::new([7; KEY_SIZE], [1; BLOCK_SIZE], [2; BLOCK_SIZE]).with_compat(compat);
+        let mut client = CryptState::new([7; KEY_SIZE], [2; BLOCK_SIZE], [1; BLOCK_SIZE]).with_compat(compat);
+
+        let mut dst = BytesMut::new();
+        server.encrypt(&packet(), &mut dst);
+        assert_eq!(dst.len(), 14 + 5);
+
+        let mut mumble_client = CryptState::new([7; KEY_SIZE], [2; BLOCK_SIZE], [1; BLOCK_SIZE]);
+        assert!(matches!(
+            mumble_client.decrypt::<Serverbound>(&mut dst.clone()),
+            Err(DecryptError::Mac)
+        ));
+        assert!(client.decrypt::<Serverbound>(&mut dst).is_ok());
+
+        assert!(CryptCompat::with_tag_size(0).is_none());
+        assert!(CryptCompat::with_tag_size(BLOCK_SIZE + 1).is_none());
+    }
+
+    #[test]
+    fn rekey_is_not_a_resync() {
+        let mut crypt = CryptState::new([7; KEY_SIZE], [1; BLOCK_SIZE], [2; BLOCK_SIZE]);
+        let mut dst = BytesMut::new();
+        crypt.encrypt(&packet(), &mut dst);
+
+        crypt.rekey();
+
+        assert_ne!(crypt.key, [7; KEY_SIZE]);
+        assert_eq!(crypt.encrypt_nonce, 0);
+        assert_eq!(crypt.resync, 0);
+        assert!(crypt.last_resync.is_none());
+        assert!(crypt.can_reset());
+
+        crypt.reset();
+
+        assert_eq!(crypt.resync, 1);
+        assert!(!crypt.can_reset());
+    }
+}
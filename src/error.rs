@@ -1,4 +1,5 @@
-use crate::message::ClientMessage;
+use crate::message::{ClientMessage, DisconnectReason};
+use crate::proto::mumble::Reject_RejectType;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -11,18 +12,22 @@ pub enum MumbleError {
     Parse(#[from] protobuf::ProtobufError),
     #[error("voice decrypt error: {0}")]
     Decrypt(#[from] DecryptError),
-    #[error("force disconnecting client")]
-    ForceDisconnect,
+    #[error("force disconnecting client: {0}")]
+    ForceDisconnect(DisconnectReason),
     #[error("lock error: {0}")]
     LockError(#[from] crate::sync::Error),
     #[error("send message error: {0}")]
     SendError(#[from] tokio::sync::mpsc::error::SendTimeoutError<ClientMessage>),
     #[error("timeout error")]
     Timeout,
+    #[error("client {0} is banned")]
+    Banned(std::net::IpAddr),
+    #[error("client rejected: {1}")]
+    Rejected(Reject_RejectType, String),
+    #[error("{0} not found")]
+    NotFound(String),
 }
 
-impl actix_web::error::ResponseError for MumbleError {}
-
 #[derive(Error, Debug)]
 pub enum DecryptError {
     #[error("tokio io error: {0}")]
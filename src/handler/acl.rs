@@ -0,0 +1,74 @@
+use crate::client::Client;
+use crate::error::MumbleError;
+use crate::handler::Handler;
+use crate::permission::{is_admin, PERM_RESTRICTED, PERM_WRITE};
+use crate::proto::mumble::{ACL_ChanACL, ACL};
+use crate::proto::MessageKind;
+use crate::sync::RwLock;
+use crate::ServerState;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+#[async_trait]
+impl Handler for ACL {
+    async fn handle(&self, state: Arc<RwLock<ServerState>>, client: Arc<RwLock<Client>>) -> Result<(), MumbleError> {
+        let tokens = { client.read_err().await?.tokens.clone() };
+
+        let (admin, channel_tokens) = {
+            let state_read = state.read_err().await?;
+
+            (
+                is_admin(&state_read, &tokens),
+                state_read.restricted_channels.get(&self.get_channel_id()).cloned(),
+            )
+        };
+
+        // Acls are derived from the server configuration, they can be read by admins but never edited
+        if !admin || !self.get_query() {
+            {
+                client
+                    .read_err()
+                    .await?
+                    .send_channel_permission_denied(PERM_WRITE, self.get_channel_id())
+                    .await?;
+            }
+
+            return Ok(());
+        }
+
+        let mut acl = ACL::new();
+        acl.set_channel_id(self.get_channel_id());
+        acl.set_inherit_acls(true);
+        acl.set_query(false);
+
+        // A restricted channel is denied to everyone, except to the `#token` groups matching its access tokens
+        if let Some(channel_tokens) = channel_tokens {
+            let mut deny = ACL_ChanACL::new();
+            deny.set_apply_here(true);
+            deny.set_apply_subs(false);
+            deny.set_inherited(false);
+            deny.set_group("all".to_string());
+            deny.set_deny(PERM_RESTRICTED);
+            acl.mut_acls().push(deny);
+
+            let mut channel_tokens = channel_tokens.into_iter().collect::<Vec<String>>();
+            channel_tokens.sort();
+
+            for token in channel_tokens {
+                let mut grant = ACL_ChanACL::new();
+                grant.set_apply_here(true);
+                grant.set_apply_subs(false);
+                grant.set_inherited(false);
+                grant.set_group(format!("#{}", token));
+                grant.set_grant(PERM_RESTRICTED);
+                acl.mut_acls().push(grant);
+            }
+        }
+
+        {
+            client.read_err().await?.send_message(MessageKind::Acl, &acl).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use crate::ban::Ban;
+use crate::client::Client;
+use crate::error::MumbleError;
+use crate::handler::Handler;
+use crate::permission::{is_admin, PERM_BAN};
+use crate::proto::mumble::BanList;
+use crate::proto::MessageKind;
+use crate::sync::RwLock;
+use crate::ServerState;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+#[async_trait]
+impl Handler for BanList {
+    async fn handle(&self, state: Arc<RwLock<ServerState>>, client: Arc<RwLock<Client>>) -> Result<(), MumbleError> {
+        let admin = {
+            let tokens = { client.read_err().await?.tokens.clone() };
+            let state_read = state.read_err().await?;
+
+            is_admin(&state_read, &tokens)
+        };
+
+        // The ban list can only be read or replaced by admins
+        if !admin {
+            {
+                client.read_err().await?.send_channel_permission_denied(PERM_BAN, 0).await?;
+            }
+
+            return Ok(());
+        }
+
+        if self.get_query() {
+            let bans = { state.read_err().await?.bans.get_bans() };
+
+            let mut ban_list = BanList::new();
+            ban_list.set_query(false);
+
+            for ban in bans {
+                ban_list.mut_bans().push(ban.get_ban_entry());
+            }
+
+            {
+                client.read_err().await?.send_message(MessageKind::BanList, &ban_list).await?;
+            }
+
+            return Ok(());
+        }
+
+        let mut bans = Vec::new();
+
+        for entry in self.get_bans() {
+            match Ban::from_ban_entry(entry) {
+                Some(ban) => bans.push(ban),
+                None => tracing::warn!("invalid ban entry address: {:?}", entry.get_address()),
+            }
+        }
+
+        let ban_list_write = { state.write_err().await?.bans.replace(bans) };
+        ban_list_write.write().await;
+
+        Ok(())
+    }
+}
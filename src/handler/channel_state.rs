@@ -1,7 +1,7 @@
 use crate::client::Client;
 use crate::error::MumbleError;
 use crate::handler::Handler;
-use crate::proto::mumble::ChannelState;
+use crate::proto::mumble::{ChannelState, PermissionDenied_DenyType};
 use crate::proto::MessageKind;
 use crate::sync::RwLock;
 use crate::ServerState;
@@ -58,7 +58,46 @@ impl Handler for ChannelState {
 
             channel_state.get_channel_id()
         } else {
+            let (depth, created, max_depth, max_created, limit_reached) = {
+                let state_read = state.read_err().await?;
+                let session_id = { client.read_err().await?.session_id };
+
+                (
+                    state_read.get_channel_depth(self.get_parent()).await? + 1,
+                    state_read.count_channels_created_by(session_id).await?,
+                    state_read.max_channel_depth,
+                    state_read.max_channels_per_client,
+                    state_read.is_channel_limit_reached(true).await?,
+                )
+            };
+
+            // Deep or numerous channels would let a single client exhaust the server
+            if max_depth > 0 && depth > max_depth {
+                client
+                    .read_err()
+                    .await?
+                    .send_permission_denied(PermissionDenied_DenyType::NestingLimit, "Channel nesting limit reached")
+                    .await?;
+
+                return Ok(());
+            }
+
+            if limit_reached || (max_created > 0 && created >= max_created) {
+                client
+                    .read_err()
+                    .await?
+                    .send_permission_denied(PermissionDenied_DenyType::ChannelCountLimit, "Channel count limit reached")
+                    .await?;
+
+                return Ok(());
+            }
+
             let channel = { state.write_err().await?.add_channel(self) };
+
+            {
+                channel.write_err().await?.creator = Some(client.read_err().await?.session_id);
+            }
+
             let channel_state = { channel.read_err().await?.get_channel_state() };
 
             {
@@ -72,7 +111,7 @@ impl Handler for ChannelState {
             channel_state.get_channel_id()
         };
 
-        let leave_channel_id_result = { state.read_err().await?.set_client_channel(client.clone(), new_channel_id).await };
+        let leave_channel_id_result = { state.read_err().await?.join_client_channel(client.clone(), new_channel_id).await };
 
         let leave_channel_id = match leave_channel_id_result {
             Ok(Some(leave_channel_id)) => leave_channel_id,
@@ -81,7 +120,7 @@ impl Handler for ChannelState {
         };
 
         {
-            state.write_err().await?.channels.remove(&leave_channel_id);
+            state.write_err().await?.remove_channel(leave_channel_id);
         }
 
         Ok(())
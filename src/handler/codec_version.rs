@@ -0,0 +1,33 @@
+use crate::client::Client;
+use crate::error::MumbleError;
+use crate::handler::Handler;
+use crate::proto::mumble::CodecVersion;
+use crate::sync::RwLock;
+use crate::ServerState;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+#[async_trait]
+impl Handler for CodecVersion {
+    async fn handle(&self, state: Arc<RwLock<ServerState>>, client: Arc<RwLock<Client>>) -> Result<(), MumbleError> {
+        let mut codecs = vec![self.get_alpha()];
+
+        if self.has_beta() && self.get_beta() != self.get_alpha() {
+            codecs.push(self.get_beta());
+        }
+
+        {
+            let mut client_write = client.write_err().await?;
+            client_write.codecs = codecs;
+
+            if self.has_opus() {
+                client_write.use_opus = self.get_opus();
+            }
+        }
+
+        // The new version is broadcast to every client when the vote changes
+        state.read_err().await?.check_codec().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+use crate::client::Client;
+use crate::error::MumbleError;
+use crate::handler::Handler;
+use crate::proto::mumble::ContextAction;
+use crate::sync::RwLock;
+use crate::webhook::{self, WebhookEvent};
+use crate::ServerState;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+#[async_trait]
+impl Handler for ContextAction {
+    async fn handle(&self, state: Arc<RwLock<ServerState>>, client: Arc<RwLock<Client>>) -> Result<(), MumbleError> {
+        let (username, session_id) = {
+            let client_read = client.read_err().await?;
+
+            (client_read.authenticate.get_username().to_string(), client_read.session_id)
+        };
+
+        let state_read = state.read_err().await?;
+
+        if !state_read.context_actions.iter().any(|action| action.action == self.get_action()) {
+            tracing::warn!("client {} triggered an unknown context action {}", username, self.get_action());
+
+            return Ok(());
+        }
+
+        tracing::info!("client {} triggered context action {}", username, self.get_action());
+
+        if let Some(webhook) = &state_read.webhook {
+            webhook.send(WebhookEvent::ContextAction {
+                username,
+                session_id,
+                action: self.get_action().to_string(),
+                target_session_id: if self.has_session() { Some(self.get_session()) } else { None },
+                target_channel_id: if self.has_channel_id() { Some(self.get_channel_id()) } else { None },
+                timestamp: webhook::now(),
+            });
+        }
+
+        Ok(())
+    }
+}
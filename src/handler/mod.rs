@@ -1,8 +1,14 @@
+mod acl;
 mod authenticate;
+mod ban_list;
 mod channel_state;
+mod codec_version;
+mod context_action;
 mod crypt_setup;
 mod permission_query;
 mod ping;
+mod query_users;
+mod request_blob;
 mod user_state;
 mod version;
 mod voice_packet;
@@ -10,11 +16,11 @@ mod voice_target;
 
 use crate::client::Client;
 use crate::error::MumbleError;
-use crate::message::ClientMessage;
+use crate::message::{ClientMessage, Consumer};
 use crate::proto::mumble;
 use crate::proto::MessageKind;
 use crate::sync::RwLock;
-use crate::voice::{decode_voice_packet, Serverbound};
+use crate::voice::{batch_voice_packets, can_batch_voice_packet, decode_voice_packet, Clientbound, Serverbound, VoicePacket};
 use crate::ServerState;
 use anyhow::Context;
 use async_trait::async_trait;
@@ -22,7 +28,6 @@ use bytes::BytesMut;
 use protobuf::Message;
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncReadExt};
-use tokio::sync::mpsc::Receiver;
 
 #[async_trait]
 pub trait Handler {
@@ -56,75 +61,174 @@ impl MessageHandler {
         Ok(())
     }
 
+    /// Combine the voice packets of the same speaker already waiting in the queue, a client lagging behind gets fewer but bigger packets
+    fn batch_queued_voice_packets(packet: VoicePacket<Clientbound>, consumer: &mut Consumer) -> VoicePacket<Clientbound> {
+        let mut batch = vec![packet];
+
+        while let Some(message) = consumer.try_recv() {
+            match message {
+                ClientMessage::SendVoicePacket(next) if can_batch_voice_packet(&batch, &next) => batch.push(next),
+                message => {
+                    consumer.put_back(message);
+                    break;
+                }
+            }
+        }
+
+        if batch.len() > 1 {
+            crate::metrics::VOICE_BATCHED_PACKETS_TOTAL.inc_by(batch.len() as u64 - 1);
+        }
+
+        match batch_voice_packets(batch.clone()) {
+            Some(batched) => batched,
+            None => batch.swap_remove(0),
+        }
+    }
+
+    /// Read a message from the client and handle it
     pub async fn handle<S: AsyncRead + Unpin>(
         stream: &mut S,
-        consumer: &mut Receiver<ClientMessage>,
         state: Arc<RwLock<ServerState>>,
         client: Arc<RwLock<Client>>,
     ) -> Result<(), anyhow::Error> {
-        tokio::select! {
-            kind_read = stream.read_u16() => {
-                let kind = kind_read?;
-                let size = stream.read_u32().await?;
-                let mut buf = vec![0; size as usize];
-                stream.read_exact(&mut buf).await?;
-
-                let message_kind = MessageKind::try_from(kind)?;
+        let kind = stream.read_u16().await?;
+        let size = stream.read_u32().await?;
+        let mut buf = vec![0; size as usize];
+        stream.read_exact(&mut buf).await?;
+
+        let message_kind = MessageKind::try_from(kind)?;
+
+        crate::metrics::MESSAGES_TOTAL
+            .with_label_values(&["tcp", "input", message_kind.to_string().as_str()])
+            .inc();
+        crate::metrics::MESSAGES_BYTES
+            .with_label_values(&["tcp", "input", message_kind.to_string().as_str()])
+            .inc_by(buf.len() as u64);
+
+        match message_kind {
+            MessageKind::Version => Self::try_handle::<mumble::Version>(&buf, state, client)
+                .await
+                .context("kind: Version"),
+            MessageKind::UDPTunnel => {
+                if !{ client.read_err().await?.tcp_tunnel } {
+                    crate::metrics::TCP_TUNNEL_DROPPED_PACKETS_TOTAL.with_label_values(&["input"]).inc();
+
+                    return Ok(());
+                }
 
-                crate::metrics::MESSAGES_TOTAL.with_label_values(&["tcp", "input", message_kind.to_string().as_str()]).inc();
-                crate::metrics::MESSAGES_BYTES.with_label_values(&["tcp", "input", message_kind.to_string().as_str()]).inc_by(buf.len() as u64);
+                let mut bytes = BytesMut::from(buf.as_slice());
 
-                match message_kind {
-                    MessageKind::Version => Self::try_handle::<mumble::Version>(&buf, state, client).await.context("kind: Version"),
-                    MessageKind::UDPTunnel => {
-                        let mut bytes = BytesMut::from(buf.as_slice());
+                let voice_packet = match decode_voice_packet::<Serverbound>(&mut bytes) {
+                    Ok(voice_packet) => voice_packet,
+                    Err(e) => {
+                        tracing::error!("error decoding voice packet: {}", e);
 
-                        let voice_packet = match decode_voice_packet::<Serverbound>(&mut bytes) {
-                            Ok(voice_packet) => voice_packet,
-                            Err(e) => {
-                                tracing::error!("error decoding voice packet: {}", e);
+                        return Ok(());
+                    }
+                };
+
+                let output_voice_packet = { voice_packet.into_client_bound(client.read_err().await?.session_id) };
+
+                // Tunneled voice ping, echo it back over tcp
+                if let VoicePacket::Ping { .. } = output_voice_packet {
+                    return client
+                        .read_err()
+                        .await?
+                        .send_tunnel_voice_packet(output_voice_packet)
+                        .await
+                        .context("kind: UDPTunnel ping");
+                }
 
-                                return Ok(());
-                            }
-                        };
+                {
+                    client.read_err().await?.voice_packets_in.inc();
+                }
 
-                        let output_voice_packet = { voice_packet.into_client_bound(client.read_err().await?.session_id) };
+                output_voice_packet.handle(state, client).await.context("kind: UDPTunnel")
+            }
+            MessageKind::Authenticate => Self::try_handle::<mumble::Authenticate>(&buf, state, client)
+                .await
+                .context("kind: Authenticate"),
+            MessageKind::Ping => Self::try_handle::<mumble::Ping>(&buf, state, client).await.context("kind: Ping =>"),
+            MessageKind::ChannelState => Self::try_handle::<mumble::ChannelState>(&buf, state, client)
+                .await
+                .context("kind: ChannelState"),
+            MessageKind::CryptSetup => Self::try_handle::<mumble::CryptSetup>(&buf, state, client)
+                .await
+                .context("kind: CryptSetup"),
+            MessageKind::PermissionQuery => Self::try_handle::<mumble::PermissionQuery>(&buf, state, client)
+                .await
+                .context("kind: PermissionQuery"),
+            MessageKind::UserState => Self::try_handle::<mumble::UserState>(&buf, state, client)
+                .await
+                .context("kind: UserState"),
+            MessageKind::VoiceTarget => Self::try_handle::<mumble::VoiceTarget>(&buf, state, client)
+                .await
+                .context("kind: VoiceTarget"),
+            MessageKind::BanList => Self::try_handle::<mumble::BanList>(&buf, state, client)
+                .await
+                .context("kind: BanList"),
+            MessageKind::ContextAction => Self::try_handle::<mumble::ContextAction>(&buf, state, client)
+                .await
+                .context("kind: ContextAction"),
+            MessageKind::QueryUsers => Self::try_handle::<mumble::QueryUsers>(&buf, state, client)
+                .await
+                .context("kind: QueryUsers"),
+            MessageKind::Acl => Self::try_handle::<mumble::ACL>(&buf, state, client).await.context("kind: ACL"),
+            MessageKind::CodecVersion => Self::try_handle::<mumble::CodecVersion>(&buf, state, client)
+                .await
+                .context("kind: CodecVersion"),
+            MessageKind::RequestBlob => Self::try_handle::<mumble::RequestBlob>(&buf, state, client)
+                .await
+                .context("kind: RequestBlob"),
+            _ => {
+                tracing::warn!("unsupported message kind: {:?}", message_kind);
+
+                Ok(())
+            }
+        }
+    }
 
-                        output_voice_packet.handle(state, client).await.context("kind: UDPTunnel")
-                    }
-                    MessageKind::Authenticate => Self::try_handle::<mumble::Authenticate>(&buf, state, client).await.context("kind: Authenticate"),
-                    MessageKind::Ping => Self::try_handle::<mumble::Ping>(&buf, state, client).await.context("kind: Ping =>"),
-                    MessageKind::ChannelState => Self::try_handle::<mumble::ChannelState>(&buf, state, client).await.context("kind: ChannelState"),
-                    MessageKind::CryptSetup => Self::try_handle::<mumble::CryptSetup>(&buf, state, client).await.context("kind: CryptSetup"),
-                    MessageKind::PermissionQuery => Self::try_handle::<mumble::PermissionQuery>(&buf, state, client).await.context("kind: PermissionQuery"),
-                    MessageKind::UserState => Self::try_handle::<mumble::UserState>(&buf, state, client).await.context("kind: UserState"),
-                    MessageKind::VoiceTarget => Self::try_handle::<mumble::VoiceTarget>(&buf, state, client).await.context("kind: VoiceTarget"),
-                    _ => {
-                        tracing::warn!("unsupported message kind: {:?}", message_kind);
-
-                        Ok(())
-                    }
-                }
-            },
-            consume = consumer.recv() => {
-                match consume {
-                    Some(ClientMessage::RouteVoicePacket(packet)) => {
-                        packet.handle(state, client).await.context("handle voice packet")
-                    },
-                    Some(ClientMessage::SendVoicePacket(packet)) => {
-                        client.read_err().await?.send_voice_packet(packet).await.context("send voice packet")
-                    },
-                    Some(ClientMessage::SendMessage { kind, payload }) => {
-                        client.read_err().await?.send(payload.as_ref()).await.context(format!("send message of type: {}", kind))
-                    },
-                    Some(ClientMessage::Disconnect) => {
-                        Err(MumbleError::ForceDisconnect).context("force disconnect")
-                    },
-                    _ => {
-                        Ok(())
-                    }
+    /// Handle a message queued for the client, run by its send task so a slow write does not stop the reads
+    pub async fn handle_queued(
+        message: ClientMessage,
+        consumer: &mut Consumer,
+        state: Arc<RwLock<ServerState>>,
+        client: Arc<RwLock<Client>>,
+    ) -> Result<(), anyhow::Error> {
+        match message {
+            ClientMessage::RouteVoicePacket(packet) => packet.handle(state, client).await.context("handle voice packet"),
+            ClientMessage::SendVoicePacket(packet) => {
+                let udp_dead = {
+                    let client_read = client.read_err().await?;
+
+                    let packet = if client_read.voice_batching {
+                        Self::batch_queued_voice_packets(packet, consumer)
+                    } else {
+                        packet
+                    };
+
+                    client_read.send_voice_packet(packet).await.context("send voice packet")?;
+                    client_read.is_udp_dead()
+                };
+
+                if udp_dead {
+                    state
+                        .write_err()
+                        .await?
+                        .disable_client_udp(client)
+                        .await
+                        .context("disable client udp")?;
                 }
-            },
+
+                Ok(())
+            }
+            ClientMessage::SendMessage { kind, payload } => client
+                .read_err()
+                .await?
+                .send(payload.as_ref())
+                .await
+                .context(format!("send message of type: {}", kind)),
+            ClientMessage::Disconnect(reason) => Err(MumbleError::ForceDisconnect(reason)).context("force disconnect"),
         }
     }
 }
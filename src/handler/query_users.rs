@@ -0,0 +1,45 @@
+use crate::client::Client;
+use crate::error::MumbleError;
+use crate::handler::Handler;
+use crate::proto::mumble::QueryUsers;
+use crate::proto::MessageKind;
+use crate::sync::RwLock;
+use crate::ServerState;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+#[async_trait]
+impl Handler for QueryUsers {
+    async fn handle(&self, state: Arc<RwLock<ServerState>>, client: Arc<RwLock<Client>>) -> Result<(), MumbleError> {
+        // There is no user database, connected clients are resolved by their session id
+        let mut response = QueryUsers::new();
+
+        {
+            let state_read = state.read_err().await?;
+
+            for id in self.get_ids() {
+                if let Some(found) = state_read.clients.get(id) {
+                    let username = { found.read_err().await?.authenticate.get_username().to_string() };
+
+                    response.mut_ids().push(*id);
+                    response.mut_names().push(username);
+                }
+            }
+
+            for name in self.get_names() {
+                if let Some(found) = state_read.get_client_by_name(name).await? {
+                    let session_id = { found.read_err().await?.session_id };
+
+                    response.mut_ids().push(session_id);
+                    response.mut_names().push(name.clone());
+                }
+            }
+        }
+
+        {
+            client.read_err().await?.send_message(MessageKind::QueryUsers, &response).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use crate::client::Client;
+use crate::error::MumbleError;
+use crate::handler::Handler;
+use crate::proto::mumble::{ChannelState, RequestBlob, UserState};
+use crate::proto::MessageKind;
+use crate::sync::RwLock;
+use crate::ServerState;
+use async_trait::async_trait;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+#[async_trait]
+impl Handler for RequestBlob {
+    async fn handle(&self, state: Arc<RwLock<ServerState>>, client: Arc<RwLock<Client>>) -> Result<(), MumbleError> {
+        // Comments and textures are not stored, clients get empty ones so they stop asking for them
+        let mut user_states = Vec::new();
+        let mut channel_states = Vec::new();
+
+        {
+            let state_read = state.read_err().await?;
+            let sessions = self
+                .get_session_texture()
+                .iter()
+                .chain(self.get_session_comment())
+                .copied()
+                .collect::<BTreeSet<u32>>();
+
+            for session in sessions {
+                if !state_read.clients.contains_key(&session) {
+                    continue;
+                }
+
+                let mut user_state = UserState::new();
+                user_state.set_session(session);
+
+                if self.get_session_texture().contains(&session) {
+                    user_state.set_texture(Vec::new());
+                }
+
+                if self.get_session_comment().contains(&session) {
+                    user_state.set_comment(String::new());
+                }
+
+                user_states.push(user_state);
+            }
+
+            for channel_id in self.get_channel_description() {
+                if let Some(channel) = state_read.channels.get(channel_id) {
+                    let mut channel_state = ChannelState::new();
+                    channel_state.set_channel_id(*channel_id);
+                    channel_state.set_description(channel.read_err().await?.description.clone());
+
+                    channel_states.push(channel_state);
+                }
+            }
+        }
+
+        let client_read = client.read_err().await?;
+
+        for user_state in &user_states {
+            client_read.send_message(MessageKind::UserState, user_state).await?;
+        }
+
+        for channel_state in &channel_states {
+            client_read.send_message(MessageKind::ChannelState, channel_state).await?;
+        }
+
+        Ok(())
+    }
+}
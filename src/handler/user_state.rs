@@ -1,7 +1,9 @@
 use crate::client::Client;
 use crate::error::MumbleError;
 use crate::handler::Handler;
+use crate::permission::{can_enter, PERM_LISTEN};
 use crate::proto::mumble::UserState;
+use crate::proto::{get_listening_volume_adjustments, MessageKind};
 use crate::sync::RwLock;
 use crate::ServerState;
 use async_trait::async_trait;
@@ -16,24 +18,37 @@ impl Handler for UserState {
             return Ok(());
         }
 
-        {
-            client.write_err().await?.update(self);
+        let tokens = { client.read_err().await?.tokens.clone() };
+
+        let updated_user_state = {
+            let mut client_write = client.write_err().await?;
+
+            if client_write.update(self) {
+                Some(client_write.get_user_state())
+            } else {
+                None
+            }
+        };
+
+        if let Some(user_state) = updated_user_state {
+            state
+                .read_err()
+                .await?
+                .broadcast_message(MessageKind::UserState, &user_state)
+                .await?;
         }
 
         if self.has_channel_id() {
-            let leave_channel_id = match state
+            let leave_channel_id = state
                 .read_err()
                 .await?
-                .set_client_channel(client.clone(), self.get_channel_id())
+                .join_client_channel(client.clone(), self.get_channel_id())
                 .await
-            {
-                Ok(l) => l,
-                Err(_) => None,
-            };
+                .unwrap_or_default();
 
             if let Some(leave_channel_id) = leave_channel_id {
                 {
-                    state.write_err().await?.channels.remove(&leave_channel_id);
+                    state.write_err().await?.remove_channel(leave_channel_id);
                 }
             }
         }
@@ -41,6 +56,16 @@ impl Handler for UserState {
         let session_id = { client.read_err().await?.session_id };
 
         for channel_id in self.get_listening_channel_add() {
+            if !{ can_enter(&*state.read_err().await?, &tokens, *channel_id) } {
+                client
+                    .read_err()
+                    .await?
+                    .send_channel_permission_denied(PERM_LISTEN, *channel_id)
+                    .await?;
+
+                continue;
+            }
+
             {
                 if let Some(channel) = state.read_err().await?.channels.get(channel_id) {
                     channel.write_err().await?.listeners.insert(session_id);
@@ -51,7 +76,21 @@ impl Handler for UserState {
         for channel_id in self.get_listening_channel_remove() {
             {
                 if let Some(channel) = state.read_err().await?.channels.get(channel_id) {
-                    channel.write_err().await?.listeners.remove(&session_id);
+                    let mut channel_write = channel.write_err().await?;
+                    channel_write.listeners.remove(&session_id);
+                    channel_write.listener_volumes.remove(&session_id);
+                }
+            }
+        }
+
+        for (channel_id, volume) in get_listening_volume_adjustments(self)? {
+            {
+                if let Some(channel) = state.read_err().await?.channels.get(&channel_id) {
+                    let mut channel_write = channel.write_err().await?;
+
+                    if channel_write.listeners.contains(&session_id) {
+                        channel_write.listener_volumes.insert(session_id, volume);
+                    }
                 }
             }
         }
@@ -2,87 +2,197 @@ use crate::client::Client;
 use crate::error::MumbleError;
 use crate::handler::Handler;
 use crate::message::ClientMessage;
+use crate::permission::PERM_SPEAK;
+use crate::recorder::Recorder;
+use crate::route::{get_voice_dropped, route_voice, VoiceDropped};
 use crate::sync::RwLock;
 use crate::voice::{Clientbound, VoicePacket};
+use crate::webhook::{self, Webhook, WebhookEvent};
 use crate::ServerState;
 use async_trait::async_trait;
-use std::collections::HashMap;
-use std::sync::Arc;
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Server config used for each voice packet, read under a single state lock before any client is locked
+struct VoiceConfig {
+    max_position_info_size: usize,
+    positional_context: bool,
+    webhook: Option<Arc<Webhook>>,
+    recorder: Option<Arc<Recorder>>,
+    #[cfg(feature = "monitor")]
+    monitor: Option<Arc<crate::monitor::Monitor>>,
+    #[cfg(feature = "transcode")]
+    transcoder: Option<Arc<crate::transcoder::Transcoder>>,
+}
+
+impl VoiceConfig {
+    fn new(state: &ServerState) -> Self {
+        Self {
+            max_position_info_size: state.max_position_info_size,
+            positional_context: state.positional_context,
+            webhook: state.webhook.clone(),
+            recorder: state.recorder.clone(),
+            #[cfg(feature = "monitor")]
+            monitor: state.monitor.clone(),
+            #[cfg(feature = "transcode")]
+            transcoder: state.transcoder.clone(),
+        }
+    }
+}
 
 #[async_trait]
 impl Handler for VoicePacket<Clientbound> {
     async fn handle(&self, state: Arc<RwLock<ServerState>>, client: Arc<RwLock<Client>>) -> Result<(), MumbleError> {
-        let mute = { client.read_err().await?.mute };
+        let config = { VoiceConfig::new(&*state.read_err().await?) };
+
+        if let VoicePacket::<Clientbound>::Audio {
+            position_info: Some(position_info),
+            ..
+        } = self
+        {
+            // Positional data is copied to every listener, a long one would amplify the bandwidth of the speaker
+            if position_info.len() > config.max_position_info_size {
+                crate::metrics::POSITION_INFO_OVERSIZED_TOTAL.inc();
 
-        if mute {
-            return Ok(());
+                return self.without_position().handle(state, client).await;
+            }
         }
 
-        if let VoicePacket::<Clientbound>::Audio { target, session_id, .. } = self {
-            let mut listening_clients = HashMap::new();
+        {
+            let client_read = client.read_err().await?;
 
-            match *target {
-                // Channel
-                0 => {
-                    let channel_id = { client.read_err().await?.channel_id.load(Ordering::Relaxed) };
-                    let channel_result = { state.read_err().await?.channels.get(&channel_id).cloned() };
+            if let VoicePacket::<Clientbound>::Audio { position_info, .. } = self {
+                client_read.set_positional(position_info.is_some());
+                client_read.set_position(self.position());
 
-                    if let Some(channel) = channel_result {
-                        {
-                            listening_clients.extend(channel.read_err().await?.get_listeners(state.clone()).await);
-                        }
+                if !self.is_keepalive() {
+                    client_read.touch_audio();
+                }
+            }
+        }
+
+        if let VoicePacket::<Clientbound>::Audio { target, .. } = self {
+            match get_voice_dropped(&state, &client, *target).await? {
+                Some(VoiceDropped::Muted) => return Ok(()),
+                // The client is told once per channel
+                Some(VoiceDropped::ListenOnly { channel_id }) => {
+                    let client_read = client.read_err().await?;
+
+                    if !client_read.speak_denied.swap(true, Ordering::Relaxed) {
+                        client_read.send_channel_permission_denied(PERM_SPEAK, channel_id).await?;
                     }
+
+                    return Ok(());
                 }
-                // Voice target (whisper)
-                1..=30 => {
-                    let target = { client.read_err().await?.get_target((*target - 1) as usize) };
+                None => (),
+            }
+        }
 
-                    if let Some(target) = target {
-                        let target = target.read_err().await?;
+        if let VoicePacket::<Clientbound>::Audio { session_id, .. } = self {
+            let started = {
+                client
+                    .read_err()
+                    .await?
+                    .talking
+                    .audio(!self.is_keepalive(), self.is_end_of_transmission(), Instant::now())
+            };
 
-                        for client_id in &target.sessions {
-                            let client_result = { state.read_err().await?.clients.get(client_id).cloned() };
+            if started {
+                if let Some(webhook) = &config.webhook {
+                    let username = { client.read_err().await?.authenticate.get_username().to_string() };
 
-                            if let Some(client) = client_result {
-                                listening_clients.insert(*client_id, client);
-                            }
-                        }
+                    webhook.send(WebhookEvent::TalkingStart {
+                        username,
+                        session_id: *session_id,
+                        timestamp: webhook::now(),
+                    });
+                }
+            }
+        }
 
-                        for channel_id in &target.channels {
-                            let channel_result = { state.read_err().await?.channels.get(channel_id).cloned() };
+        if let VoicePacket::<Clientbound>::Audio { target, session_id, .. } = self {
+            // Loopback
+            if *target == 31 {
+                {
+                    let client_read = client.read_err().await?;
 
-                            if let Some(channel) = channel_result {
-                                {
-                                    listening_clients.extend(channel.read_err().await?.get_listeners(state.clone()).await);
-                                }
-                            }
-                        }
+                    // A deaf client does not receive any voice, including its own loopback
+                    if !client_read.is_deaf() {
+                        client_read.send_voice_packet(self.clone()).await?;
                     }
                 }
-                // Loopback
-                31 => {
-                    {
-                        client.read_err().await?.send_voice_packet(self.clone()).await?;
-                    }
+
+                return Ok(());
+            }
+
+            let (listening_clients, routed_channels) = match route_voice(&state, &client, *target, self.position()).await? {
+                Some(route) => (route.listeners, route.channels),
+                None => {
+                    tracing::error!("invalid voice target: {}", *target);
 
                     return Ok(());
                 }
-                _ => {
-                    tracing::error!("invalid voice target: {}", *target);
+            };
+
+            #[cfg(feature = "monitor")]
+            if let Some(monitor) = &config.monitor {
+                if routed_channels.contains(&monitor.channel_id()) {
+                    monitor.push(self);
+                }
+            }
+
+            if let Some(recorder) = &config.recorder {
+                if routed_channels.contains(&recorder.channel_id()) {
+                    recorder.push(self);
                 }
             }
 
+            #[cfg(feature = "transcode")]
+            let transcoding = config.transcoder.is_some()
+                && matches!(
+                    self,
+                    VoicePacket::<Clientbound>::Audio {
+                        payload: crate::voice::VoicePacketPayload::Opus(..),
+                        ..
+                    }
+                );
+            #[cfg(feature = "transcode")]
+            let mut transcoded_listeners = Vec::new();
+
+            // Positional data is only kept for listeners in the same game as the speaker
+            let speaker_context = match self {
+                VoicePacket::<Clientbound>::Audio {
+                    position_info: Some(_), ..
+                } if config.positional_context => Some(client.read_err().await?.plugin_context.clone()),
+                _ => None,
+            };
+
             for client in listening_clients.values() {
                 {
                     let client_read = client.read_err().await?;
 
-                    if client_read.deaf {
+                    // Do not queue voice for deaf listeners, their client would discard it anyway
+                    if client_read.is_deaf() {
                         continue;
                     }
 
                     if client_read.session_id != *session_id {
-                        match client_read.publisher.try_send(ClientMessage::SendVoicePacket(self.clone())) {
+                        let keep_position = match &speaker_context {
+                            Some(context) => !context.is_empty() && *context == client_read.plugin_context,
+                            None => true,
+                        };
+
+                        #[cfg(feature = "transcode")]
+                        if transcoding {
+                            transcoded_listeners.push((client_read.publisher.clone(), keep_position));
+
+                            continue;
+                        }
+
+                        let packet = if keep_position { self.clone() } else { self.without_position() };
+
+                        match client_read.publisher.try_send(ClientMessage::SendVoicePacket(packet)) {
                             Ok(_) => {}
                             Err(err) => {
                                 tracing::error!(
@@ -95,6 +205,13 @@ impl Handler for VoicePacket<Clientbound> {
                     }
                 }
             }
+
+            #[cfg(feature = "transcode")]
+            if transcoding && !transcoded_listeners.is_empty() {
+                if let Some(transcoder) = &config.transcoder {
+                    transcoder.push(*session_id, self.clone(), transcoded_listeners);
+                }
+            }
         }
 
         Ok(())
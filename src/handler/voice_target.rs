@@ -1,7 +1,7 @@
 use crate::client::Client;
 use crate::error::MumbleError;
 use crate::handler::Handler;
-use crate::proto::mumble::VoiceTarget;
+use crate::proto::mumble::{PermissionDenied_DenyType, VoiceTarget};
 use crate::sync::RwLock;
 use crate::ServerState;
 use async_trait::async_trait;
@@ -15,12 +15,26 @@ impl Handler for VoiceTarget {
             return Ok(());
         }
 
-        let target_opt = { client.read_err().await?.get_target((self.get_id() - 1) as usize) };
+        // Target 0 is normal talking and 31 is the server loopback, only 1..=targets count can be configured
+        let target_opt = match self.get_id() {
+            0 => None,
+            id => client.read_err().await?.get_target((id - 1) as usize),
+        };
 
         let target = match target_opt {
             Some(target) => target,
             None => {
-                tracing::error!("invalid voice target id: {}", self.get_id());
+                tracing::warn!("invalid voice target id: {}", self.get_id());
+
+                crate::metrics::INVALID_VOICE_TARGETS_TOTAL.inc();
+
+                {
+                    client
+                        .read_err()
+                        .await?
+                        .send_permission_denied(PermissionDenied_DenyType::Text, "Invalid voice target id")
+                        .await?;
+                }
 
                 return Ok(());
             }
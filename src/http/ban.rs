@@ -0,0 +1,64 @@
+use crate::ban::{to_ipv6_mask, Ban};
+use crate::http::HttpApiError;
+use crate::sync::RwLock;
+use crate::ServerState;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize)]
+pub struct BanRequest {
+    address: IpAddr,
+    /// Subnet mask length for the address family, defaults to the full address
+    mask: Option<u32>,
+    name: Option<String>,
+    reason: Option<String>,
+    /// Ban duration in seconds, 0 or absent means permanent
+    duration: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnbanRequest {
+    address: IpAddr,
+    mask: Option<u32>,
+}
+
+#[actix_web::get("/ban")]
+pub async fn get_ban(state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let bans = { state.read_err().await?.bans.get_bans() };
+
+    Ok(HttpResponse::Ok().json(&bans))
+}
+
+#[actix_web::post("/ban")]
+pub async fn post_ban(request: web::Json<BanRequest>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let request = request.into_inner();
+    let mask = to_ipv6_mask(&request.address, request.mask.unwrap_or(128));
+
+    let mut ban = Ban::new(request.address, mask);
+    ban.name = request.name;
+    ban.reason = request.reason;
+    ban.duration = request.duration.unwrap_or(0);
+
+    let ban_list_write = { state.write_err().await?.bans.add(ban) };
+    ban_list_write.write().await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[actix_web::delete("/ban")]
+pub async fn delete_ban(
+    request: web::Json<UnbanRequest>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    let mask = to_ipv6_mask(&request.address, request.mask.unwrap_or(128));
+    let ban_list_write = { state.write_err().await?.bans.remove(&request.address, mask) };
+
+    match ban_list_write {
+        Some(ban_list_write) => ban_list_write.write().await,
+        None => return Err(HttpApiError::NotFound(format!("ban {}", request.address))),
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,327 @@
+use crate::api_types::{ChannelMember, ChannelMembers};
+use crate::channel::Channel;
+use crate::http::HttpApiError;
+use crate::sync::RwLock;
+use crate::ServerState;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize)]
+pub struct KickChannel {
+    channel_id: u32,
+    /// Channel where the clients are moved, defaults to the root channel
+    target_channel_id: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct KickChannelResult {
+    moved: usize,
+}
+
+#[actix_web::post("/kick-channel")]
+pub async fn post_kick_channel(
+    request: web::Json<KickChannel>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    let target_channel_id = request.target_channel_id.unwrap_or(0);
+
+    if target_channel_id == request.channel_id {
+        return Err(HttpApiError::BadRequest("target channel is the kicked channel".to_string()));
+    }
+
+    let clients = {
+        let state_read = state.read_err().await?;
+
+        for channel_id in [request.channel_id, target_channel_id] {
+            if !state_read.channels.contains_key(&channel_id) {
+                return Err(HttpApiError::NotFound(format!("channel {}", channel_id)));
+            }
+        }
+
+        let mut clients = Vec::new();
+
+        for client in state_read.clients.values() {
+            if client.read_err().await?.channel_id.load(Ordering::Relaxed) == request.channel_id {
+                clients.push(client.clone());
+            }
+        }
+
+        clients
+    };
+
+    let mut moved = 0;
+
+    for client in clients {
+        let leave_channel_id = { state.read_err().await?.set_client_channel(client, target_channel_id).await? };
+
+        moved += 1;
+
+        if let Some(leave_channel_id) = leave_channel_id {
+            state.write_err().await?.remove_channel(leave_channel_id);
+        }
+    }
+
+    tracing::info!(
+        "moved {} clients from channel {} to {}",
+        moved,
+        request.channel_id,
+        target_channel_id
+    );
+
+    Ok(HttpResponse::Ok().json(&KickChannelResult { moved }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MoveChannel {
+    from: u32,
+    to: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MoveChannelResult {
+    moved: usize,
+    /// Whether the emptied channel was temporary and got removed
+    removed: bool,
+}
+
+/// Move every client of a channel to another one, no client can join or leave the channels while they are moved
+#[actix_web::post("/move-channel")]
+pub async fn post_move_channel(
+    request: web::Json<MoveChannel>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    if request.from == request.to {
+        return Err(HttpApiError::BadRequest("channels to move from and to are the same".to_string()));
+    }
+
+    let mut state_write = state.write_err().await?;
+
+    for channel_id in [request.from, request.to] {
+        if !state_write.channels.contains_key(&channel_id) {
+            return Err(HttpApiError::NotFound(format!("channel {}", channel_id)));
+        }
+    }
+
+    let mut clients = Vec::new();
+
+    for client in state_write.clients.values() {
+        if client.read_err().await?.channel_id.load(Ordering::Relaxed) == request.from {
+            clients.push(client.clone());
+        }
+    }
+
+    let moved = clients.len();
+    let mut removed = false;
+
+    for client in clients {
+        if let Some(leave_channel_id) = state_write.set_client_channel(client, request.to).await? {
+            removed |= state_write.remove_channel(leave_channel_id).is_some() && leave_channel_id == request.from;
+        }
+    }
+
+    tracing::info!("moved {} clients from channel {} to {}", moved, request.from, request.to);
+
+    Ok(HttpResponse::Ok().json(&MoveChannelResult { moved, removed }))
+}
+
+#[actix_web::get("/channels/{id}/members")]
+pub async fn get_channel_members(
+    channel_id: web::Path<u32>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    let channel_id = channel_id.into_inner();
+    let state_read = state.read_err().await?;
+
+    let channel = state_read
+        .channels
+        .get(&channel_id)
+        .ok_or_else(|| HttpApiError::NotFound(format!("channel {}", channel_id)))?;
+    let listener_ids = { channel.read_err().await?.listeners.clone() };
+
+    let mut members = Vec::new();
+    let mut listeners = Vec::new();
+
+    for client in state_read.clients.values() {
+        let client_read = client.read_err().await?;
+        let member = ChannelMember {
+            name: client_read.authenticate.get_username().to_string(),
+            session_id: client_read.session_id,
+        };
+
+        if client_read.channel_id.load(Ordering::Relaxed) == channel_id {
+            members.push(member);
+        } else if listener_ids.contains(&client_read.session_id) {
+            listeners.push(member);
+        }
+    }
+
+    members.sort_unstable_by_key(|member| member.session_id);
+    listeners.sort_unstable_by_key(|member| member.session_id);
+
+    Ok(HttpResponse::Ok().json(&ChannelMembers {
+        channel_id,
+        members,
+        listeners,
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChannelRange {
+    channel_id: u32,
+    /// Distance beyond which clients of the channel do not hear a positional speaker, unset to let everyone hear it
+    range: Option<f32>,
+}
+
+#[actix_web::post("/channel-range")]
+pub async fn post_channel_range(
+    request: web::Json<ChannelRange>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    if let Some(range) = request.range {
+        if !range.is_finite() || range < 0.0 {
+            return Err(HttpApiError::BadRequest(format!("invalid range {}", range)));
+        }
+    }
+
+    let channel = { state.read_err().await?.channels.get(&request.channel_id).cloned() };
+    let channel = channel.ok_or_else(|| HttpApiError::NotFound(format!("channel {}", request.channel_id)))?;
+
+    {
+        channel.write_err().await?.audio_range = request.range;
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChannelCapacity {
+    channel_id: u32,
+    /// Max number of clients in the channel, unset to remove the limit
+    capacity: Option<usize>,
+}
+
+/// Clients already in the channel stay in it, the capacity only applies to the next joins
+#[actix_web::post("/channel-capacity")]
+pub async fn post_channel_capacity(
+    request: web::Json<ChannelCapacity>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    let channel = { state.read_err().await?.channels.get(&request.channel_id).cloned() };
+    let channel = channel.ok_or_else(|| HttpApiError::NotFound(format!("channel {}", request.channel_id)))?;
+
+    {
+        channel.write_err().await?.max_occupancy = request.capacity;
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChannelSpeak {
+    channel_id: u32,
+    /// Clients of a listen only channel hear it but cannot speak in it
+    speak_allowed: bool,
+}
+
+#[actix_web::post("/channel-speak")]
+pub async fn post_channel_speak(
+    request: web::Json<ChannelSpeak>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    let channel = { state.read_err().await?.channels.get(&request.channel_id).cloned() };
+    let channel = channel.ok_or_else(|| HttpApiError::NotFound(format!("channel {}", request.channel_id)))?;
+
+    {
+        channel.write_err().await?.speak_allowed = request.speak_allowed;
+    }
+
+    tracing::info!("channel {} speak allowed: {}", request.channel_id, request.speak_allowed);
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChannelLink {
+    channel_id: u32,
+    linked_channel_id: u32,
+}
+
+/// Both channels of a link, or a not found error naming the missing one
+async fn get_link_channels(
+    request: &ChannelLink,
+    state: &RwLock<ServerState>,
+) -> Result<(Arc<RwLock<Channel>>, Arc<RwLock<Channel>>), HttpApiError> {
+    let state_read = state.read_err().await?;
+    let get_channel = |channel_id: u32| {
+        state_read
+            .channels
+            .get(&channel_id)
+            .cloned()
+            .ok_or_else(|| HttpApiError::NotFound(format!("channel {}", channel_id)))
+    };
+
+    Ok((get_channel(request.channel_id)?, get_channel(request.linked_channel_id)?))
+}
+
+#[actix_web::post("/link")]
+pub async fn post_link(request: web::Json<ChannelLink>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    if request.channel_id == request.linked_channel_id {
+        return Err(HttpApiError::BadRequest("a channel cannot be linked to itself".to_string()));
+    }
+
+    let (channel, linked_channel) = get_link_channels(&request, &state).await?;
+
+    // Links are followed transitively, a link between channels already reachable from each other would only add a cycle
+    let already_linked = {
+        state
+            .read_err()
+            .await?
+            .get_linked_channels(request.channel_id)
+            .await?
+            .iter()
+            .any(|(linked_id, _)| *linked_id == request.linked_channel_id)
+    };
+
+    if already_linked {
+        return Err(HttpApiError::BadRequest(format!(
+            "channels {} and {} are already linked",
+            request.channel_id, request.linked_channel_id
+        )));
+    }
+
+    {
+        channel.write_err().await?.links.insert(request.linked_channel_id);
+    }
+
+    {
+        linked_channel.write_err().await?.links.insert(request.channel_id);
+    }
+
+    tracing::info!("channel {} linked to {}", request.channel_id, request.linked_channel_id);
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[actix_web::delete("/link")]
+pub async fn delete_link(
+    request: web::Json<ChannelLink>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    let (channel, linked_channel) = get_link_channels(&request, &state).await?;
+
+    let removed = { channel.write_err().await?.links.remove(&request.linked_channel_id) };
+    let linked_removed = { linked_channel.write_err().await?.links.remove(&request.channel_id) };
+
+    if !removed || !linked_removed {
+        return Err(HttpApiError::NotFound(format!(
+            "link between channels {} and {}",
+            request.channel_id, request.linked_channel_id
+        )));
+    }
+
+    tracing::info!("channel {} unlinked from {}", request.channel_id, request.linked_channel_id);
+
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,40 @@
+use crate::http::HttpApiError;
+use crate::sync::RwLock;
+use crate::ServerState;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize)]
+pub struct Codec {
+    pub opus: bool,
+    pub alpha: i32,
+    pub beta: i32,
+    pub prefer_alpha: bool,
+    /// Celt version currently used, alpha or beta depending on prefer_alpha
+    pub version: i32,
+    /// Number of connected clients supporting each celt version
+    pub votes: HashMap<i32, usize>,
+}
+
+#[actix_web::get("/codec")]
+pub async fn get_codec(state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let state_read = state.read_err().await?;
+    let votes = state_read.get_codec_votes().await?;
+
+    let codec = {
+        let codec_state = state_read.codec_state.read_err().await?;
+
+        Codec {
+            opus: codec_state.opus,
+            alpha: codec_state.alpha,
+            beta: codec_state.beta,
+            prefer_alpha: codec_state.prefer_alpha,
+            version: codec_state.get_version(),
+            votes,
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(&codec))
+}
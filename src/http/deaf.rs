@@ -1,44 +1,57 @@
-use crate::error::MumbleError;
+use crate::api_types::Deaf;
+use crate::http::HttpApiError;
+use crate::proto::MessageKind;
 use crate::sync::RwLock;
 use crate::ServerState;
 use actix_web::{web, HttpResponse};
-use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
-#[derive(Serialize, Deserialize)]
-pub struct Deaf {
-    deaf: bool,
-    user: String,
-}
-
 #[actix_web::post("/deaf")]
-pub async fn post_deaf(deaf: web::Json<Deaf>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, MumbleError> {
+pub async fn post_deaf(deaf: web::Json<Deaf>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
     let client = { state.read_err().await?.get_client_by_name(deaf.user.as_str()).await? };
 
     Ok(match client {
         Some(client) => {
-            client.write_err().await?.deaf(deaf.deaf);
+            let user_state = {
+                let mut client_write = client.write_err().await?;
+                client_write.server_deaf(deaf.deaf);
+                client_write.get_user_state()
+            };
+
+            // Kept by name so a reconnect does not lift it
+            {
+                state.write_err().await?.set_moderation_deaf(deaf.user.as_str(), deaf.deaf);
+            }
+
+            // Let every client, including the targeted one, update its ui
+            {
+                state
+                    .read_err()
+                    .await?
+                    .broadcast_message(MessageKind::UserState, &user_state)
+                    .await?;
+            }
 
             HttpResponse::Ok().finish()
         }
-        None => HttpResponse::NotFound().finish(),
+        None => return Err(HttpApiError::NotFound(format!("user {}", deaf.user))),
     })
 }
 
 #[actix_web::get("/deaf/{user}")]
-pub async fn get_deaf(user: web::Path<String>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, MumbleError> {
+pub async fn get_deaf(user: web::Path<String>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
     let username = user.into_inner();
     let client = { state.read_err().await?.get_client_by_name(username.as_str()).await? };
 
     Ok(match client {
         Some(client) => {
             let deaf = Deaf {
-                deaf: { client.read_err().await?.deaf },
+                deaf: { client.read_err().await?.server_deaf },
                 user: username,
             };
 
             HttpResponse::Ok().json(&deaf)
         }
-        None => HttpResponse::NotFound().finish(),
+        None => return Err(HttpApiError::NotFound(format!("user {}", username))),
     })
 }
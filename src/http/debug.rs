@@ -0,0 +1,83 @@
+use crate::api_types::{RoutedVoice, VoiceRoutes};
+use crate::client::Client;
+use crate::http::HttpApiError;
+use crate::route::{get_voice_dropped, route_voice, Route, CHANNEL_TARGET};
+use crate::sync::RwLock;
+use crate::target::VOICE_TARGET_COUNT;
+use crate::ServerState;
+use actix_web::{web, HttpResponse};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Skip the listeners the voice handler does not send to, the speaker itself and the deaf clients
+async fn get_routed_voice(route: Route, session_id: u32) -> Result<RoutedVoice, HttpApiError> {
+    let mut sessions = Vec::with_capacity(route.listeners.len());
+
+    for (listener_id, listener) in route.listeners {
+        if listener_id != session_id && !listener.read_err().await?.is_deaf() {
+            sessions.push(listener_id);
+        }
+    }
+
+    let mut channels = route.channels.into_iter().collect::<Vec<_>>();
+
+    sessions.sort_unstable();
+    channels.sort_unstable();
+
+    Ok(RoutedVoice { sessions, channels })
+}
+
+async fn is_target_configured(client: &Arc<RwLock<Client>>, target: u8) -> Result<bool, HttpApiError> {
+    let target = { client.read_err().await?.get_target((target - 1) as usize) };
+
+    Ok(match target {
+        Some(target) => {
+            let target_read = target.read_err().await?;
+
+            !target_read.sessions.is_empty() || !target_read.channels.is_empty()
+        }
+        None => false,
+    })
+}
+
+/// Route the voice of a speaker like the voice handler does, a dropped voice reaches nobody
+async fn route(
+    state: &Arc<RwLock<ServerState>>,
+    client: &Arc<RwLock<Client>>,
+    target: u8,
+    position: Option<[f32; 3]>,
+) -> Result<Option<Route>, HttpApiError> {
+    if get_voice_dropped(state, client, target).await?.is_some() {
+        return Ok(Some(Route::default()));
+    }
+
+    Ok(route_voice(state, client, target, position).await?)
+}
+
+/// Sessions the voice of a speaker reaches in its channel and with each of its whisper targets, computed like the voice handler does
+#[actix_web::get("/debug/route/{session}")]
+pub async fn get_debug_route(session: web::Path<u32>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let session_id = session.into_inner();
+    let client = { state.read_err().await?.clients.get(&session_id).cloned() };
+    let client = client.ok_or_else(|| HttpApiError::NotFound(format!("session {}", session_id)))?;
+    let position = { client.read_err().await?.get_position() };
+
+    let channel_route = route(&state, &client, CHANNEL_TARGET, position).await?.unwrap_or_default();
+    let mut targets = BTreeMap::new();
+
+    for target in 1..=VOICE_TARGET_COUNT {
+        if !is_target_configured(&client, target).await? {
+            continue;
+        }
+
+        if let Some(route) = route(&state, &client, target, position).await? {
+            targets.insert(target, get_routed_voice(route, session_id).await?);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(&VoiceRoutes {
+        session_id,
+        channel: get_routed_voice(channel_route, session_id).await?,
+        targets,
+    }))
+}
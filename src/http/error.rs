@@ -0,0 +1,78 @@
+use crate::error::MumbleError;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use serde::Serialize;
+use thiserror::Error;
+
+/// Error of an http api request, each kind maps to its own status code
+#[derive(Error, Debug)]
+pub enum HttpApiError {
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    /// The server state could not be locked in time, the request can be retried
+    #[error("{0}")]
+    Locked(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl From<MumbleError> for HttpApiError {
+    fn from(err: MumbleError) -> Self {
+        match err {
+            MumbleError::NotFound(what) => HttpApiError::NotFound(what),
+            MumbleError::Parse(_) => HttpApiError::BadRequest(err.to_string()),
+            MumbleError::LockError(_) | MumbleError::Timeout => HttpApiError::Locked(err.to_string()),
+            _ => HttpApiError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<crate::sync::Error> for HttpApiError {
+    fn from(err: crate::sync::Error) -> Self {
+        MumbleError::from(err).into()
+    }
+}
+
+impl actix_web::error::ResponseError for HttpApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            HttpApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            HttpApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            HttpApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            HttpApiError::Locked(_) => StatusCode::SERVICE_UNAVAILABLE,
+            HttpApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorResponse { error: self.to_string() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::error::ResponseError;
+
+    #[test]
+    fn mumble_errors_keep_their_status_code() {
+        let not_found = HttpApiError::from(MumbleError::NotFound("channel 3".to_string()));
+        assert_eq!(not_found.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(not_found.to_string(), "channel 3 not found");
+
+        let locked = HttpApiError::from(crate::sync::Error::ReadLockTimeout(100));
+        assert_eq!(locked.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let internal = HttpApiError::from(MumbleError::UnexpectedMessageKind(0));
+        assert_eq!(internal.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,20 @@
+use crate::sync::RwLock;
+use crate::ServerState;
+use actix_web::{web, HttpResponse};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Readiness probe, does not require authentication and only reads the server flags
+#[actix_web::get("/healthz")]
+pub async fn get_health(state: web::Data<Arc<RwLock<ServerState>>>) -> HttpResponse {
+    let healthy = match state.read_err().await {
+        Ok(state) => state.ready.load(Ordering::Relaxed) && !state.maintenance.load(Ordering::Relaxed),
+        Err(_) => false,
+    };
+
+    if healthy {
+        HttpResponse::Ok().body("ok")
+    } else {
+        HttpResponse::ServiceUnavailable().body("unavailable")
+    }
+}
@@ -0,0 +1,49 @@
+use crate::http::HttpApiError;
+use crate::sync::RwLock;
+use crate::tls::TlsVersion;
+use crate::ServerState;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize)]
+pub struct Info {
+    pub version: String,
+    /// Mumble protocol version announced to clients, as major.minor.patch
+    pub protocol_version: String,
+    pub listen: Option<SocketAddr>,
+    pub http_listen: String,
+    pub https: bool,
+    pub tls_min_version: TlsVersion,
+    pub max_clients: u32,
+    /// Unix timestamp in seconds of the server start
+    pub started_at: u64,
+    /// Seconds since the server start
+    pub uptime: u64,
+}
+
+#[actix_web::get("/info")]
+pub async fn get_info(state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let state_read = state.read_err().await?;
+    let info = &state_read.info;
+    let version = info.protocol_version;
+
+    let info = Info {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol_version: format!("{}.{}.{}", version >> 16, (version >> 8) & 0xff, version & 0xff),
+        listen: info.listen,
+        http_listen: info.http_listen.clone(),
+        https: info.https,
+        tls_min_version: info.tls_min_version,
+        max_clients: state_read.max_clients,
+        started_at: info.started_at.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+        uptime: SystemTime::now()
+            .duration_since(info.started_at)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    };
+
+    Ok(HttpResponse::Ok().json(&info))
+}
@@ -0,0 +1,148 @@
+use crate::http::HttpApiError;
+use crate::proto::mumble::UserState;
+use crate::proto::MessageKind;
+use crate::sync::RwLock;
+use crate::ServerState;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize)]
+pub struct Listen {
+    /// Client receiving the voice
+    user: String,
+    /// Client whose voice is received, wherever it speaks
+    target: Option<String>,
+    /// Channel whose voice is received, as if the client listened to it from its mumble client
+    channel_id: Option<u32>,
+    listen: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Listening {
+    user: String,
+    targets: Vec<String>,
+    channels: Vec<u32>,
+}
+
+#[actix_web::post("/listen")]
+pub async fn post_listen(listen: web::Json<Listen>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    match (&listen.target, listen.channel_id) {
+        (Some(target), None) => listen_user(&listen.user, target, listen.listen, &state).await,
+        (None, Some(channel_id)) => listen_channel(&listen.user, channel_id, listen.listen, &state).await,
+        _ => Err(HttpApiError::BadRequest("either a target or a channel_id is required".to_string())),
+    }
+}
+
+async fn listen_user(user: &str, target: &str, listen: bool, state: &RwLock<ServerState>) -> Result<HttpResponse, HttpApiError> {
+    let (client, target_client) = {
+        let state_read = state.read_err().await?;
+
+        (
+            state_read.get_client_by_name(user).await?,
+            state_read.get_client_by_name(target).await?,
+        )
+    };
+
+    let client = client.ok_or_else(|| HttpApiError::NotFound(format!("user {}", user)))?;
+    let target = target_client.ok_or_else(|| HttpApiError::NotFound(format!("user {}", target)))?;
+    let target_session_id = { target.read_err().await?.session_id };
+
+    {
+        let mut client_write = client.write_err().await?;
+
+        if client_write.session_id == target_session_id {
+            return Err(HttpApiError::BadRequest("a user cannot listen to itself".to_string()));
+        }
+
+        if listen {
+            client_write.listened_users.insert(target_session_id);
+        } else {
+            client_write.listened_users.remove(&target_session_id);
+        }
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn listen_channel(user: &str, channel_id: u32, listen: bool, state: &RwLock<ServerState>) -> Result<HttpResponse, HttpApiError> {
+    let (client, channel) = {
+        let state_read = state.read_err().await?;
+
+        (
+            state_read.get_client_by_name(user).await?,
+            state_read.channels.get(&channel_id).cloned(),
+        )
+    };
+
+    let client = client.ok_or_else(|| HttpApiError::NotFound(format!("user {}", user)))?;
+    let channel = channel.ok_or_else(|| HttpApiError::NotFound(format!("channel {}", channel_id)))?;
+    let session_id = { client.read_err().await?.session_id };
+
+    let changed = {
+        let mut channel_write = channel.write_err().await?;
+
+        if listen {
+            channel_write.listeners.insert(session_id)
+        } else {
+            channel_write.listener_volumes.remove(&session_id);
+            channel_write.listeners.remove(&session_id)
+        }
+    };
+
+    // Let mumble clients show the listener in the channel, as when the client changes it by itself
+    if changed {
+        let mut user_state = UserState::new();
+        user_state.set_session(session_id);
+
+        if listen {
+            user_state.mut_listening_channel_add().push(channel_id);
+        } else {
+            user_state.mut_listening_channel_remove().push(channel_id);
+        }
+
+        state
+            .read_err()
+            .await?
+            .broadcast_message(MessageKind::UserState, &user_state)
+            .await?;
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[actix_web::get("/listen/{user}")]
+pub async fn get_listen(user: web::Path<String>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let username = user.into_inner();
+    let state_read = state.read_err().await?;
+    let client = state_read
+        .get_client_by_name(username.as_str())
+        .await?
+        .ok_or_else(|| HttpApiError::NotFound(format!("user {}", username)))?;
+
+    let listened_users = { client.read_err().await?.listened_users.clone() };
+    let session_id = { client.read_err().await?.session_id };
+    let mut targets = Vec::new();
+
+    for session_id in listened_users {
+        if let Some(target) = state_read.clients.get(&session_id) {
+            targets.push(target.read_err().await?.authenticate.get_username().to_string());
+        }
+    }
+
+    let mut channels = Vec::new();
+
+    for (channel_id, channel) in &state_read.channels {
+        if channel.read_err().await?.listeners.contains(&session_id) {
+            channels.push(*channel_id);
+        }
+    }
+
+    channels.sort_unstable();
+
+    Ok(HttpResponse::Ok().json(&Listening {
+        user: username,
+        targets,
+        channels,
+    }))
+}
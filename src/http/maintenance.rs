@@ -0,0 +1,53 @@
+use crate::http::HttpApiError;
+use crate::sync::RwLock;
+use crate::ServerState;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize)]
+pub struct Maintenance {
+    maintenance: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisconnectAll {
+    disconnected: usize,
+}
+
+#[actix_web::post("/maintenance")]
+pub async fn post_maintenance(
+    maintenance: web::Json<Maintenance>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    {
+        state
+            .read_err()
+            .await?
+            .maintenance
+            .store(maintenance.maintenance, Ordering::Relaxed);
+    }
+
+    tracing::info!("maintenance mode {}", if maintenance.maintenance { "enabled" } else { "disabled" });
+
+    Ok(HttpResponse::Ok().json(maintenance.into_inner()))
+}
+
+#[actix_web::get("/maintenance")]
+pub async fn get_maintenance(state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let maintenance = Maintenance {
+        maintenance: { state.read_err().await?.maintenance.load(Ordering::Relaxed) },
+    };
+
+    Ok(HttpResponse::Ok().json(&maintenance))
+}
+
+#[actix_web::post("/disconnect-all")]
+pub async fn post_disconnect_all(state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let disconnected = { state.read_err().await?.disconnect_all().await? };
+
+    tracing::info!("disconnecting all {} clients", disconnected);
+
+    Ok(HttpResponse::Ok().json(&DisconnectAll { disconnected }))
+}
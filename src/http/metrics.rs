@@ -1,7 +1,7 @@
 use actix_web::HttpResponse;
 use prometheus::{Encoder, TextEncoder};
 
-#[actix_web::get("/metrics")]
+/// Registered as a plain resource so basic authentication can be skipped when metrics are public
 pub async fn get_metrics() -> HttpResponse {
     let encoder = TextEncoder::new();
     let mut buffer = vec![];
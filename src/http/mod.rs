@@ -1,59 +1,147 @@
+mod ban;
+mod channel;
+mod codec;
 mod deaf;
+mod debug;
+mod error;
+mod health;
+mod info;
+mod listen;
+mod maintenance;
 mod metrics;
+mod moderation;
 mod mute;
+mod session;
 mod status;
+mod whisper;
 
 use crate::sync::RwLock;
 use crate::ServerState;
 use actix_server::Server;
+use actix_web::dev::ServiceRequest;
+use actix_web::http::Method;
 use actix_web::middleware::Condition;
 use actix_web::{middleware, web, App, HttpServer};
+use actix_web_httpauth::extractors::basic::BasicAuth;
 use actix_web_httpauth::{extractors::AuthenticationError, headers::www_authenticate::basic::Basic, middleware::HttpAuthentication};
 use rustls::ServerConfig;
 use std::sync::Arc;
 
+pub use error::HttpApiError;
+
+/// Basic authentification credentials of the http api
+#[derive(Clone)]
+pub struct HttpCredentials {
+    pub user: String,
+    pub password: String,
+    /// Only allowed to read, requests other than GET are forbidden for it
+    pub readonly: Option<(String, String)>,
+}
+
+impl HttpCredentials {
+    fn is_admin(&self, credentials: &BasicAuth) -> bool {
+        credentials.user_id() == self.user.as_str() && credentials.password() == Some(self.password.as_str())
+    }
+
+    fn is_readonly(&self, credentials: &BasicAuth) -> bool {
+        match &self.readonly {
+            Some((user, password)) => credentials.user_id() == user.as_str() && credentials.password() == Some(password.as_str()),
+            None => false,
+        }
+    }
+}
+
+async fn validate(req: ServiceRequest, credentials: BasicAuth) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    let allowed = match req.app_data::<web::Data<HttpCredentials>>() {
+        Some(allowed) => allowed.clone(),
+        None => return Err((HttpApiError::Forbidden("no credentials configured".to_string()).into(), req)),
+    };
+
+    if allowed.is_admin(&credentials) {
+        return Ok(req);
+    }
+
+    if allowed.is_readonly(&credentials) {
+        if req.method() == Method::GET || req.method() == Method::HEAD {
+            return Ok(req);
+        }
+
+        return Err((HttpApiError::Forbidden("read only credentials".to_string()).into(), req));
+    }
+
+    Err((AuthenticationError::new(Basic::with_realm("Restricted area")).into(), req))
+}
+
 pub fn create_http_server(
     listen: String,
     tls_config: ServerConfig,
     use_tls: bool,
     state: Arc<RwLock<ServerState>>,
-    user: String,
-    password: String,
+    credentials: HttpCredentials,
     log_requests: bool,
+    metrics_public: bool,
 ) -> Option<Server> {
     let mut server = HttpServer::new(move || {
-        let user = user.clone();
-        let password = password.clone();
-
-        let auth = HttpAuthentication::basic(move |req, credentials| {
-            let user = user.clone();
-            let password = password.clone();
-
-            async move {
-                let user = user.clone();
-                let password = password.clone();
-
-                if credentials.user_id() == user.as_str() && credentials.password() == Some(password.as_str()) {
-                    Ok(req)
-                } else {
-                    Err((AuthenticationError::new(Basic::with_realm("Restricted area")).into(), req))
-                }
-            }
-        });
+        let auth = HttpAuthentication::basic(validate);
 
         let mut logger = middleware::Logger::default();
-        logger = logger.exclude("/metrics").exclude("/status").log_target("log_http");
+        logger = logger
+            .exclude("/metrics")
+            .exclude("/status")
+            .exclude("/healthz")
+            .log_target("log_http");
 
         App::new()
             .app_data(web::Data::new(state.clone()))
-            .wrap(auth)
+            .app_data(web::Data::new(credentials.clone()))
+            // Malformed bodies get the same json error format as the handlers
+            .app_data(web::JsonConfig::default().error_handler(|err, _| HttpApiError::BadRequest(err.to_string()).into()))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| HttpApiError::BadRequest(err.to_string()).into()))
             .wrap(Condition::new(log_requests, logger))
-            .service(metrics::get_metrics)
-            .service(mute::get_mute)
-            .service(mute::post_mute)
-            .service(deaf::get_deaf)
-            .service(deaf::post_deaf)
-            .service(status::get_status)
+            .service(health::get_health)
+            .service(
+                web::resource("/metrics")
+                    .wrap(Condition::new(!metrics_public, auth.clone()))
+                    .route(web::get().to(metrics::get_metrics)),
+            )
+            .service(
+                web::scope("")
+                    .wrap(auth)
+                    .service(mute::get_mute)
+                    .service(mute::post_mute)
+                    .service(deaf::get_deaf)
+                    .service(deaf::post_deaf)
+                    .service(moderation::get_moderation)
+                    .service(moderation::delete_moderation)
+                    .service(moderation::delete_user_moderation)
+                    .service(status::get_status)
+                    // Registered before the user status so it is not taken for a user name
+                    .service(status::get_status_summary)
+                    .service(status::get_user_status)
+                    .service(status::get_crypt_stats)
+                    .service(ban::get_ban)
+                    .service(ban::post_ban)
+                    .service(ban::delete_ban)
+                    .service(channel::post_kick_channel)
+                    .service(channel::post_move_channel)
+                    .service(channel::get_channel_members)
+                    .service(channel::post_channel_range)
+                    .service(channel::post_channel_capacity)
+                    .service(channel::post_channel_speak)
+                    .service(channel::post_link)
+                    .service(channel::delete_link)
+                    .service(codec::get_codec)
+                    .service(debug::get_debug_route)
+                    .service(info::get_info)
+                    .service(listen::get_listen)
+                    .service(listen::post_listen)
+                    .service(whisper::post_whisper)
+                    .service(maintenance::get_maintenance)
+                    .service(maintenance::post_maintenance)
+                    .service(maintenance::post_disconnect_all)
+                    .service(session::get_export_sessions)
+                    .service(session::post_import_sessions),
+            )
     });
 
     server = if use_tls {
@@ -0,0 +1,68 @@
+use crate::http::HttpApiError;
+use crate::sync::RwLock;
+use crate::ServerState;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Server mute and deaf of a user, applied again when it reconnects
+#[derive(Serialize, Deserialize)]
+pub struct Moderation {
+    user: String,
+    mute: bool,
+    deaf: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClearedModerations {
+    cleared: usize,
+}
+
+#[actix_web::get("/moderation")]
+pub async fn get_moderation(state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let mut moderations = {
+        state
+            .read_err()
+            .await?
+            .moderations
+            .iter()
+            .map(|(user, (mute, deaf))| Moderation {
+                user: user.clone(),
+                mute: *mute,
+                deaf: *deaf,
+            })
+            .collect::<Vec<Moderation>>()
+    };
+
+    moderations.sort_by(|a, b| a.user.cmp(&b.user));
+
+    Ok(HttpResponse::Ok().json(&moderations))
+}
+
+/// Forget every kept mute and deaf, connected clients stay muted or deafened until they reconnect
+#[actix_web::delete("/moderation")]
+pub async fn delete_moderation(state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let cleared = {
+        let mut state_write = state.write_err().await?;
+        let cleared = state_write.moderations.len();
+        state_write.moderations.clear();
+
+        cleared
+    };
+
+    Ok(HttpResponse::Ok().json(&ClearedModerations { cleared }))
+}
+
+#[actix_web::delete("/moderation/{user}")]
+pub async fn delete_user_moderation(
+    user: web::Path<String>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    let username = user.into_inner();
+    let removed = { state.write_err().await?.moderations.remove(&username) };
+
+    match removed {
+        Some(_) => Ok(HttpResponse::Ok().finish()),
+        None => Err(HttpApiError::NotFound(format!("moderation of user {}", username))),
+    }
+}
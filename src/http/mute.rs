@@ -1,44 +1,57 @@
-use crate::error::MumbleError;
+use crate::api_types::Mute;
+use crate::http::HttpApiError;
+use crate::proto::MessageKind;
 use crate::sync::RwLock;
 use crate::ServerState;
 use actix_web::{web, HttpResponse};
-use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
-#[derive(Serialize, Deserialize)]
-pub struct Mute {
-    mute: bool,
-    user: String,
-}
-
 #[actix_web::post("/mute")]
-pub async fn post_mute(mute: web::Json<Mute>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, MumbleError> {
+pub async fn post_mute(mute: web::Json<Mute>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
     let client = { state.read_err().await?.get_client_by_name(mute.user.as_str()).await? };
 
     Ok(match client {
         Some(client) => {
-            client.write_err().await?.mute(mute.mute);
+            let user_state = {
+                let mut client_write = client.write_err().await?;
+                client_write.server_mute(mute.mute);
+                client_write.get_user_state()
+            };
+
+            // Kept by name so a reconnect does not lift it
+            {
+                state.write_err().await?.set_moderation_mute(mute.user.as_str(), mute.mute);
+            }
+
+            // Let every client, including the targeted one, update its ui
+            {
+                state
+                    .read_err()
+                    .await?
+                    .broadcast_message(MessageKind::UserState, &user_state)
+                    .await?;
+            }
 
             HttpResponse::Ok().finish()
         }
-        None => HttpResponse::NotFound().finish(),
+        None => return Err(HttpApiError::NotFound(format!("user {}", mute.user))),
     })
 }
 
 #[actix_web::get("/mute/{user}")]
-pub async fn get_mute(user: web::Path<String>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, MumbleError> {
+pub async fn get_mute(user: web::Path<String>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
     let username = user.into_inner();
     let client = { state.read_err().await?.get_client_by_name(username.as_str()).await? };
 
     Ok(match client {
         Some(client) => {
             let mute = Mute {
-                mute: { client.read_err().await?.mute },
+                mute: { client.read_err().await?.server_mute },
                 user: username,
             };
 
             HttpResponse::Ok().json(&mute)
         }
-        None => HttpResponse::NotFound().finish(),
+        None => return Err(HttpApiError::NotFound(format!("user {}", username))),
     })
 }
@@ -0,0 +1,71 @@
+use crate::api_types::{ExportedSession, MumbleTarget};
+use crate::http::HttpApiError;
+use crate::sync::RwLock;
+use crate::ServerState;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize)]
+pub struct ImportedSessions {
+    imported: usize,
+}
+
+/// Sessions of the connected clients, ordered by name, to be imported by another instance before they reconnect to it
+#[actix_web::get("/export-sessions")]
+pub async fn get_export_sessions(state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let clients = { state.read_err().await?.clients.values().cloned().collect::<Vec<_>>() };
+    let mut sessions = Vec::with_capacity(clients.len());
+
+    for client in clients {
+        let client_read = client.read_err().await?;
+        let mut targets = Vec::new();
+
+        for target in &client_read.targets {
+            let target_read = target.read_err().await?;
+
+            if target_read.sessions.is_empty() && target_read.channels.is_empty() {
+                continue;
+            }
+
+            targets.push(MumbleTarget {
+                sessions: target_read.sessions.clone(),
+                channels: target_read.channels.clone(),
+            });
+        }
+
+        sessions.push(ExportedSession {
+            name: client_read.authenticate.get_username().to_string(),
+            channel_id: client_read.channel_id.load(Ordering::Relaxed),
+            server_mute: client_read.server_mute,
+            server_deaf: client_read.server_deaf,
+            targets,
+        });
+    }
+
+    sessions.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(HttpResponse::Ok().json(&sessions))
+}
+
+/// Keep the channel, mute and deaf of exported sessions, applied when the users connect to this instance
+///
+/// Clients are not moved by the import, they must reconnect to this instance themselves.
+#[actix_web::post("/import-sessions")]
+pub async fn post_import_sessions(
+    sessions: web::Json<Vec<ExportedSession>>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    let mut state_write = state.write_err().await?;
+
+    for session in sessions.iter() {
+        state_write.set_moderation_mute(&session.name, session.server_mute);
+        state_write.set_moderation_deaf(&session.name, session.server_deaf);
+        state_write.channel_placements.insert(session.name.clone(), session.channel_id);
+    }
+
+    tracing::info!("imported {} sessions", sessions.len());
+
+    Ok(HttpResponse::Ok().json(&ImportedSessions { imported: sessions.len() }))
+}
@@ -1,86 +1,210 @@
-use crate::error::MumbleError;
+use crate::api_types::{CryptStats, MumbleClient, MumbleTarget, StatusSummary};
+use crate::client::Client;
+use crate::http::HttpApiError;
 use crate::sync::RwLock;
 use crate::ServerState;
 use actix_web::{web, HttpResponse};
-use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Instant;
 
-#[derive(Serialize, Deserialize)]
-pub struct MumbleClient {
-    pub name: String,
-    pub session_id: u32,
-    pub channel: Option<String>,
-    pub mute: bool,
-    pub good: u32,
-    pub late: u32,
-    pub lost: u32,
-    pub resync: u32,
-    pub last_good_duration: u128,
-    pub targets: Vec<MumbleTarget>,
+#[derive(Deserialize)]
+pub struct StatusQuery {
+    /// Max number of clients returned, ordered by session id
+    limit: Option<usize>,
+    offset: Option<usize>,
+    /// Include the voice targets of each client, true by default
+    targets: Option<bool>,
+}
+
+const DEFAULT_DEGRADED_LOST_THRESHOLD: u32 = 50;
+
+#[derive(Deserialize)]
+pub struct CryptStatsQuery {
+    /// Number of lost packets above which a client is counted as degraded, 50 by default
+    lost_threshold: Option<u32>,
+}
+
+/// Channels listened by each session, with their volume
+async fn get_listening(state: &Arc<RwLock<ServerState>>) -> Result<HashMap<u32, HashMap<u32, f32>>, HttpApiError> {
+    let mut listening: HashMap<u32, HashMap<u32, f32>> = HashMap::new();
+    let channels = { state.read_err().await?.channels.values().cloned().collect::<Vec<_>>() };
+
+    for channel in channels {
+        let channel_read = channel.read_err().await?;
+
+        for session in &channel_read.listeners {
+            listening
+                .entry(*session)
+                .or_default()
+                .insert(channel_read.id, channel_read.get_listener_volume(*session));
+        }
+    }
+
+    Ok(listening)
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct MumbleTarget {
-    pub sessions: HashSet<u32>,
-    pub channels: HashSet<u32>,
+async fn get_mumble_client(
+    state: &Arc<RwLock<ServerState>>,
+    client: &Arc<RwLock<Client>>,
+    listening: HashMap<u32, f32>,
+    with_targets: bool,
+) -> Result<MumbleClient, HttpApiError> {
+    let channel_id = { client.read_err().await?.channel_id.load(Ordering::Relaxed) };
+    let channel = { state.read_err().await?.channels.get(&channel_id).cloned() };
+    let channel_name = {
+        if let Some(channel) = channel {
+            Some(channel.read_err().await?.name.clone())
+        } else {
+            None
+        }
+    };
+
+    let client_read = client.read_err().await?;
+    let crypt_state = client_read.crypt_state.read_err().await?;
+
+    let mut mumble_client = MumbleClient {
+        name: client_read.authenticate.get_username().to_string(),
+        session_id: client_read.session_id,
+        user_id: client_read.user_id,
+        channel: channel_name,
+        server_mute: client_read.server_mute,
+        server_deaf: client_read.server_deaf,
+        self_mute: client_read.self_mute,
+        self_deaf: client_read.self_deaf,
+        has_positional: client_read.has_positional.load(Ordering::Relaxed),
+        plugin_context: String::from_utf8_lossy(&client_read.plugin_context).into_owned(),
+        plugin_identity: client_read.plugin_identity.clone(),
+        tcp_address: client_read.tcp_socket_addr,
+        udp_address: client_read.udp_socket_addr,
+        queue_length: client_read.publisher.queue_length(),
+        voice_packets_in: client_read.voice_packets_in.total(),
+        voice_packets_out: client_read.voice_packets_out.total(),
+        voice_pps_in: client_read.voice_packets_in.rate(),
+        voice_pps_out: client_read.voice_packets_out.rate(),
+        good: crypt_state.good,
+        late: crypt_state.late,
+        lost: crypt_state.lost,
+        resync: crypt_state.resync,
+        late_per_sec: client_read.crypt_late.rate(),
+        lost_per_sec: client_read.crypt_lost.rate(),
+        last_good_duration: Instant::now().duration_since(crypt_state.last_good).as_millis(),
+        targets: None,
+        listening,
+        listened_users: client_read.listened_users.clone(),
+    };
+
+    if with_targets {
+        let mut targets = Vec::with_capacity(client_read.targets.len());
+
+        for target in &client_read.targets {
+            let target_read = target.read_err().await?;
+
+            targets.push(MumbleTarget {
+                sessions: target_read.sessions.clone(),
+                channels: target_read.channels.clone(),
+            });
+        }
+
+        mumble_client.targets = Some(targets);
+    }
+
+    Ok(mumble_client)
 }
 
 #[actix_web::get("/status")]
-pub async fn get_status(state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, MumbleError> {
+pub async fn get_status(query: web::Query<StatusQuery>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
     let mut clients = HashMap::new();
-    let sessions = { state.read_err().await?.clients.keys().cloned().collect::<Vec<u32>>() };
+    let mut listening = get_listening(&state).await?;
+    let mut sessions = { state.read_err().await?.clients.keys().cloned().collect::<Vec<u32>>() };
+    sessions.sort_unstable();
+
+    let sessions = sessions
+        .into_iter()
+        .skip(query.offset.unwrap_or(0))
+        .take(query.limit.unwrap_or(usize::MAX));
 
     for session in sessions {
         let client = { state.read_err().await?.clients.get(&session).cloned() };
 
         if let Some(client) = client {
-            let channel_id = { client.read_err().await?.channel_id.load(Ordering::Relaxed) };
-            let channel = { state.read_err().await?.channels.get(&channel_id).cloned() };
-            let channel_name = {
-                if let Some(channel) = channel {
-                    Some(channel.read_err().await?.name.clone())
-                } else {
-                    None
-                }
-            };
-
-            {
-                let client_read = client.read_err().await?;
-                let crypt_state = client_read.crypt_state.read_err().await?;
-
-                let mut mumble_client = MumbleClient {
-                    name: client_read.authenticate.get_username().to_string(),
-                    session_id: client_read.session_id,
-                    channel: channel_name,
-                    mute: client_read.mute,
-                    good: crypt_state.good,
-                    late: crypt_state.late,
-                    lost: crypt_state.lost,
-                    resync: crypt_state.resync,
-                    last_good_duration: Instant::now().duration_since(crypt_state.last_good).as_millis(),
-                    targets: Vec::new(),
-                };
-
-                for target in &client_read.targets {
-                    let mumble_target = {
-                        let target_read = target.read_err().await?;
-
-                        MumbleTarget {
-                            sessions: target_read.sessions.clone(),
-                            channels: target_read.channels.clone(),
-                        }
-                    };
-
-                    mumble_client.targets.push(mumble_target);
-                }
-
-                clients.insert(session, mumble_client);
-            }
+            let listening = listening.remove(&session).unwrap_or_default();
+            let mumble_client = get_mumble_client(&state, &client, listening, query.targets.unwrap_or(true)).await?;
+
+            clients.insert(session, mumble_client);
         }
     }
 
     Ok(HttpResponse::Ok().json(&clients))
 }
+
+#[actix_web::get("/status/summary")]
+pub async fn get_status_summary(state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let state_read = state.read_err().await?;
+    let mut udp_clients = 0;
+
+    for client in state_read.clients.values() {
+        if client.read_err().await?.udp_socket_addr.is_some() {
+            udp_clients += 1;
+        }
+    }
+
+    let summary = StatusSummary {
+        clients: state_read.clients.len(),
+        channels: state_read.channels.len(),
+        udp_clients,
+        tcp_clients: state_read.clients.len() - udp_clients,
+    };
+
+    Ok(HttpResponse::Ok().json(&summary))
+}
+
+/// Udp packet counters of all the clients, to tell a degraded server from a single degraded client
+#[actix_web::get("/stats/crypt")]
+pub async fn get_crypt_stats(
+    query: web::Query<CryptStatsQuery>,
+    state: web::Data<Arc<RwLock<ServerState>>>,
+) -> Result<HttpResponse, HttpApiError> {
+    let lost_threshold = query.lost_threshold.unwrap_or(DEFAULT_DEGRADED_LOST_THRESHOLD);
+    let clients = { state.read_err().await?.clients.values().cloned().collect::<Vec<_>>() };
+
+    let mut stats = CryptStats {
+        clients: clients.len(),
+        good: 0,
+        late: 0,
+        lost: 0,
+        resync: 0,
+        degraded_clients: 0,
+    };
+
+    for client in clients {
+        let crypt_state = { client.read_err().await?.crypt_state.clone() };
+        let crypt_state_read = crypt_state.read_err().await?;
+
+        stats.good += crypt_state_read.good as u64;
+        stats.late += crypt_state_read.late as u64;
+        stats.lost += crypt_state_read.lost as u64;
+        stats.resync += crypt_state_read.resync as u64;
+
+        if crypt_state_read.lost > lost_threshold {
+            stats.degraded_clients += 1;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(&stats))
+}
+
+#[actix_web::get("/status/{user}")]
+pub async fn get_user_status(user: web::Path<String>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let username = user.into_inner();
+    let client = { state.read_err().await?.get_client_by_name(username.as_str()).await? };
+    let client = client.ok_or_else(|| HttpApiError::NotFound(format!("user {}", username)))?;
+
+    let session = { client.read_err().await?.session_id };
+    let listening = get_listening(&state).await?.remove(&session).unwrap_or_default();
+    let mumble_client = get_mumble_client(&state, &client, listening, true).await?;
+
+    Ok(HttpResponse::Ok().json(&mumble_client))
+}
@@ -0,0 +1,57 @@
+use crate::http::HttpApiError;
+use crate::sync::RwLock;
+use crate::target::VOICE_TARGET_COUNT;
+use crate::ServerState;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize)]
+pub struct Whisper {
+    user: String,
+    /// Voice target slot of the client, from 1 to 30, the client can still override it with its own voice target configuration
+    target: u8,
+    #[serde(default)]
+    sessions: HashSet<u32>,
+    #[serde(default)]
+    channels: HashSet<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WhisperTarget {
+    target: u8,
+    sessions: HashSet<u32>,
+    channels: HashSet<u32>,
+}
+
+#[actix_web::post("/whisper")]
+pub async fn post_whisper(whisper: web::Json<Whisper>, state: web::Data<Arc<RwLock<ServerState>>>) -> Result<HttpResponse, HttpApiError> {
+    let whisper = whisper.into_inner();
+
+    if whisper.target == 0 || whisper.target > VOICE_TARGET_COUNT {
+        return Err(HttpApiError::BadRequest(format!(
+            "invalid voice target {}, it must be between 1 and {}",
+            whisper.target, VOICE_TARGET_COUNT
+        )));
+    }
+
+    let client = { state.read_err().await?.get_client_by_name(whisper.user.as_str()).await? };
+    let client = client.ok_or_else(|| HttpApiError::NotFound(format!("user {}", whisper.user)))?;
+    let target = { client.read_err().await?.get_target((whisper.target - 1) as usize) };
+    let target = target.ok_or_else(|| HttpApiError::BadRequest(format!("invalid voice target {}", whisper.target)))?;
+
+    let updated = {
+        let mut target_write = target.write_err().await?;
+        target_write.sessions = whisper.sessions;
+        target_write.channels = whisper.channels;
+
+        WhisperTarget {
+            target: whisper.target,
+            sessions: target_write.sessions.clone(),
+            channels: target_write.channels.clone(),
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(&updated))
+}
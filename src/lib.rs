@@ -0,0 +1,78 @@
+//! Zumble, a mumble server implementation for FiveM
+//!
+//! The server is run by the `zumble` binary, modules are exposed so benchmarks can exercise the voice path.
+//! Without the default `server` feature, the `client-types` feature only builds the http api types.
+
+#[cfg(feature = "server")]
+#[macro_use]
+extern crate lazy_static;
+
+#[cfg(any(feature = "server", feature = "client-types"))]
+pub mod api_types;
+#[cfg(feature = "server")]
+pub mod ban;
+#[cfg(feature = "server")]
+pub mod channel;
+#[cfg(feature = "server")]
+pub mod check;
+#[cfg(feature = "server")]
+pub mod clean;
+#[cfg(feature = "server")]
+pub mod client;
+#[cfg(feature = "server")]
+pub mod connection_limit;
+#[cfg(feature = "server")]
+pub mod context_action;
+#[cfg(feature = "server")]
+pub mod crypt;
+#[cfg(feature = "server")]
+pub mod error;
+#[cfg(feature = "server")]
+pub mod handler;
+#[cfg(feature = "server")]
+pub mod http;
+#[cfg(feature = "server")]
+pub mod message;
+#[cfg(feature = "server")]
+pub mod metrics;
+#[cfg(feature = "monitor")]
+pub mod monitor;
+#[cfg(feature = "server")]
+pub mod opus;
+#[cfg(feature = "server")]
+pub mod permission;
+#[cfg(feature = "server")]
+pub mod proto;
+#[cfg(feature = "server")]
+pub mod rate;
+#[cfg(feature = "server")]
+pub mod recorder;
+#[cfg(feature = "server")]
+pub mod registry;
+#[cfg(feature = "server")]
+pub mod route;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod state;
+#[cfg(feature = "server")]
+pub mod sync;
+#[cfg(feature = "server")]
+pub mod talking;
+#[cfg(feature = "server")]
+pub mod target;
+#[cfg(feature = "server")]
+pub mod tls;
+#[cfg(feature = "transcode")]
+pub mod transcoder;
+#[cfg(feature = "server")]
+pub mod varint;
+#[cfg(feature = "server")]
+pub mod voice;
+#[cfg(feature = "server")]
+pub mod webhook;
+#[cfg(feature = "server")]
+pub mod welcome;
+
+#[cfg(feature = "server")]
+pub use crate::state::ServerState;
@@ -1,46 +1,41 @@
-#[macro_use]
-extern crate lazy_static;
-
-mod channel;
-mod check;
-mod clean;
-mod client;
-mod crypt;
-mod error;
-mod handler;
-mod http;
-mod message;
-mod metrics;
-mod proto;
-mod server;
-mod state;
-mod sync;
-mod target;
-mod varint;
-mod voice;
-
-use crate::clean::clean_loop;
-use crate::http::create_http_server;
-use crate::proto::mumble::Version;
-use crate::server::{create_tcp_server, create_udp_server};
-use crate::state::ServerState;
-use crate::sync::RwLock;
-use clap::Parser;
-use rustls_pemfile::{certs, pkcs8_private_keys};
-use std::fs::File;
-use std::io;
-use std::io::BufReader;
-use std::path::Path;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueSource};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::net::{TcpListener, UdpSocket};
-use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use std::time::Duration;
 use tokio_rustls::TlsAcceptor;
+use zumble::ban::BanList;
+use zumble::channel::{ChannelCapacityConfig, ChannelRangeConfig};
+use zumble::clean::clean_loop;
+use zumble::connection_limit::ConnectionLimiter;
+use zumble::context_action::ContextActionConfig;
+use zumble::http::{create_http_server, HttpCredentials};
+use zumble::permission::RestrictedChannelConfig;
+use zumble::proto::mumble::{SuggestConfig, Version};
+use zumble::recorder::Recorder;
+use zumble::registry::Registry;
+use zumble::server::{bind_tcp, bind_udp, create_tcp_server, create_udp_server};
+use zumble::state::{
+    ServerState, DEFAULT_CLIENT_QUEUE_CAPACITY, DEFAULT_CRYPT_REKEY_INTERVAL, DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_MAX_BANDWIDTH,
+    DEFAULT_MAX_CHANNELS_PER_CLIENT, DEFAULT_MAX_CHANNEL_DEPTH, DEFAULT_MAX_POSITION_INFO_SIZE,
+};
+use zumble::sync::RwLock;
+use zumble::talking::talking_loop;
+use zumble::tls::{create_tls_config, generate_self_signed_cert, get_fingerprint, load_certs, load_keys, parse_cipher_suites, TlsVersion};
+use zumble::webhook::Webhook;
+use zumble::welcome::{InitStep, InitStepKind, TokenChannelConfig, VoiceTargetConfig};
 
 /// Zumble, a mumble server implementation for FiveM
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Serialize, Deserialize)]
 #[clap(author, version, about, long_about = None)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 struct Args {
-    /// Listen address for TCP and UDP connections for mumble voip clients (or other clients that support the mumble protocol)
+    /// Path to a json or toml config file, keys are the long names of the flags, flags given on the command line override its values
+    #[clap(long, value_parser)]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Listen address for TCP and UDP connections for mumble voip clients (or other clients that support the mumble protocol), an ipv6 address like [::]:64738 also accepts ipv4 clients
     #[clap(short, long, value_parser, default_value = "0.0.0.0:64738")]
     listen: String,
     /// Listen address for HTTP connections for the admin api
@@ -50,32 +45,232 @@ struct Args {
     #[clap(long, value_parser, default_value = "admin")]
     http_user: String,
     /// Password for the http server api basic authentification
-    #[clap(long, value_parser)]
-    http_password: String,
+    #[clap(long, value_parser, required_unless_present = "config")]
+    http_password: Option<String>,
+    /// User for the http server api basic authentification that is only allowed to read (GET requests), e.g. for monitoring
+    #[clap(long, value_parser, requires = "http-readonly-password")]
+    http_readonly_user: Option<String>,
+    /// Password of the read only http user
+    #[clap(long, value_parser, requires = "http-readonly-user")]
+    http_readonly_password: Option<String>,
     /// Use TLS for the http server (https), will use the same certificate as the mumble server
     #[clap(long)]
     https: bool,
     /// Log http requests to stdout
     #[clap(long)]
     http_log: bool,
-    /// Path to the key file for the TLS certificate
+    /// Serve the prometheus metrics endpoint without basic authentification, other endpoints still require it
+    #[clap(long)]
+    metrics_public: bool,
+    /// Path to the key file for the TLS certificate, repeat it with --cert to serve several certificates by server name
     #[clap(long, value_parser, default_value = "key.pem")]
-    key: String,
-    /// Path to the certificate file for the TLS certificate
+    #[serde(deserialize_with = "one_or_many")]
+    key: Vec<String>,
+    /// Path to the certificate file for the TLS certificate, the first one is used for clients not sending a matching server name
     #[clap(long, value_parser, default_value = "cert.pem")]
-    cert: String,
+    #[serde(deserialize_with = "one_or_many")]
+    cert: Vec<String>,
+    /// Generate a self signed certificate and key at the configured paths if one of them is missing
+    #[clap(long)]
+    generate_cert: bool,
+    /// Minimum tls version accepted for the mumble and http servers
+    #[clap(long, value_enum, default_value = "1.2")]
+    tls_min_version: TlsVersion,
+    /// Comma separated list of allowed tls cipher suites (e.g. TLS13_AES_256_GCM_SHA384), all rustls suites are allowed if not set
+    #[clap(long, value_parser, use_value_delimiter = true)]
+    tls_cipher_suites: Vec<String>,
+    /// Path to a json file used to persist the ban list, bans are only kept in memory if not set
+    #[clap(long, value_parser)]
+    ban_file: Option<PathBuf>,
+    /// Path to a json file of registered users, each with a user_id, a client certificate fingerprint or token, and an optional default_channel
+    #[clap(long, value_parser)]
+    registry_file: Option<PathBuf>,
+    /// Name of the channel where new clients are put, the root channel is used while it does not exist
+    #[clap(long, value_parser)]
+    default_channel: Option<String>,
+    /// Timeout in milliseconds of a tcp write to a client, the client is disconnected when it is reached
+    #[clap(long, value_parser, default_value = "1000")]
+    write_timeout: u64,
+    /// Timeout in milliseconds of the tls handshake and of the mumble handshake (version and authenticate), the connection is dropped when it is reached
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = DEFAULT_HANDSHAKE_TIMEOUT.as_millis() as u64)]
+    handshake_timeout: u64,
+    /// Interval in seconds between two crypt key renewals of a client, 0 disables them
+    #[clap(long, value_parser, default_value_t = DEFAULT_CRYPT_REKEY_INTERVAL.as_secs())]
+    crypt_rekey_interval: u64,
+    /// Combine the opus packets of a speaker waiting in the queue of a lagging client into a single packet
+    #[clap(long)]
+    voice_batching: bool,
+    /// Strip the positional data of voice sent to clients whose positional audio plugin context differs from the speaker one
+    #[clap(long)]
+    positional_context: bool,
+    /// Max size in bytes of the positional data of a voice packet, longer positional data is stripped so it is not copied to every listener
+    #[clap(long, value_parser, default_value_t = DEFAULT_MAX_POSITION_INFO_SIZE)]
+    max_position_info_size: usize,
+    /// Number of messages (voice packets included) waiting to be sent to a client before new ones are dropped, a larger queue loses less audio on a slow client but lets its latency grow
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = DEFAULT_CLIENT_QUEUE_CAPACITY as u64)]
+    client_queue_capacity: u64,
+    /// Never send nor accept voice over the tcp tunnel, clients without a working udp connection do not get any audio
+    #[clap(long)]
+    no_tcp_tunnel: bool,
+    /// Tell clients they are not allowed to record the voice, clients honoring it do not offer recording
+    #[clap(long)]
+    no_recording: bool,
+    /// Max number of connected clients, new clients are rejected as server full once it is reached, 0 means unlimited
+    #[clap(long, value_parser, default_value = "0")]
+    max_clients: u32,
+    /// Max connection attempts of an ip per minute, the next ones are closed before the tls handshake, 0 means unlimited
+    #[clap(long, value_parser, default_value = "0")]
+    max_connections_per_ip: u32,
+    /// Max number of parents of a temporary channel created by a client, deeper channels are refused, 0 means unlimited
+    #[clap(long, value_parser, default_value_t = DEFAULT_MAX_CHANNEL_DEPTH)]
+    max_channel_depth: usize,
+    /// Max number of temporary channels a client can have created at the same time, 0 means unlimited
+    #[clap(long, value_parser, default_value_t = DEFAULT_MAX_CHANNELS_PER_CLIENT)]
+    max_channels_per_client: usize,
+    /// Max number of channels on the server, the root channel included, new channels are refused once it is reached, 0 means unlimited
+    #[clap(long, value_parser, default_value = "0")]
+    max_channels: usize,
+    /// Max number of temporary channels on the server, counted apart from --max-channels, 0 means unlimited
+    #[clap(long, value_parser, default_value = "0")]
+    max_temporary_channels: usize,
+    /// Max bandwidth per client in bits per second
+    #[clap(long, value_parser, default_value_t = DEFAULT_MAX_BANDWIDTH)]
+    max_bandwidth: u32,
+    /// Suggest clients to enable or disable positional audio on connect
+    #[clap(long, value_parser)]
+    suggest_positional: Option<bool>,
+    /// Suggest clients to enable or disable push to talk on connect
+    #[clap(long, value_parser)]
+    suggest_push_to_talk: Option<bool>,
+    /// Context menu action registered on clients as context:action:text, context is a + separated list of server, channel and user (e.g. user:dispatch:Send to dispatch)
+    #[clap(long = "context-action", value_parser)]
+    context_actions: Vec<ContextActionConfig>,
+    /// Channel joined on connect by clients presenting an access token, as token:channel_name, the first matching token is used
+    #[clap(long = "token-channel", value_parser)]
+    token_channels: Vec<TokenChannelConfig>,
+    /// Channel only clients presenting the token can enter, speak and listen in, as token:channel_name, repeat it to allow several tokens
+    #[clap(long = "restricted-channel", value_parser)]
+    restricted_channels: Vec<RestrictedChannelConfig>,
+    /// Distance beyond which clients of a channel do not hear a positional speaker of the same channel, as channel_name:range in the unit of the game positions
+    #[clap(long = "channel-range", value_parser)]
+    channel_ranges: Vec<ChannelRangeConfig>,
+    /// Max number of clients in a channel as channel_name:capacity, clients cannot join it once full, repeat it for several channels
+    #[clap(long = "channel-capacity", value_parser)]
+    channel_capacities: Vec<ChannelCapacityConfig>,
+    /// Name of a listen only channel, its clients hear it but their voice to the channel is dropped unless they are admins, repeat it for several channels
+    #[clap(long = "listen-only-channel", value_parser)]
+    listen_only_channels: Vec<String>,
+    /// Name of a channel whose clients are moved out after --idle-timeout without speaking, repeat it for several channels, listen only channels are never concerned
+    #[clap(long = "idle-channel", value_parser)]
+    idle_channels: Vec<String>,
+    /// Time in seconds without speaking after which a client of an idle channel is moved out of it, 0 disables it
+    #[clap(long, value_parser, default_value = "0")]
+    idle_timeout: u64,
+    /// Name of the channel where idle clients are moved, --default-channel is used while it does not exist
+    #[clap(long, value_parser)]
+    idle_move_channel: Option<String>,
+    /// Access token giving the admin permissions to the clients presenting it, admins can enter every channel and read the acls
+    #[clap(long = "admin-token", value_parser)]
+    admin_tokens: Vec<String>,
+    /// Text message sent to each client once connected
+    #[clap(long, value_parser)]
+    greeting: Option<String>,
+    /// Voice target set on connect as target_id:channel_name, until the client sets the target itself, repeat it to target several channels
+    #[clap(long = "voice-target", value_parser)]
+    voice_targets: Vec<VoiceTargetConfig>,
+    /// Comma separated order of the steps run for each client once connected, steps left out are not run
+    #[clap(
+        long = "init-steps",
+        value_enum,
+        use_value_delimiter = true,
+        default_value = "token-channel,voice-target,greeting"
+    )]
+    init_steps: Vec<InitStepKind>,
+    /// Url where events (client connect and disconnect, client start and stop talking, triggered context actions) are posted as json
+    #[clap(long, value_parser)]
+    webhook_url: Option<String>,
+    /// Id of the channel to monitor, all the voice routed in this channel is mixed to the monitor output
+    #[cfg(feature = "monitor")]
+    #[clap(long, value_parser, requires = "monitor-output")]
+    monitor_channel: Option<u32>,
+    /// Output for the monitored channel as 48kHz mono s16le pcm, either a file path or an udp://host:port address
+    #[cfg(feature = "monitor")]
+    #[clap(long, value_parser, requires = "monitor-channel")]
+    monitor_output: Option<String>,
+    /// Id of the channel to record, the opus stream of each speaker is written to a file in the record directory
+    #[clap(long, value_parser, requires = "record-dir")]
+    record_channel: Option<u32>,
+    /// Directory where the recordings of the recorded channel are written
+    #[clap(long, value_parser, requires = "record-channel")]
+    record_dir: Option<PathBuf>,
+    /// Bitrate in bits per second at which the opus voice of every speaker is transcoded before being sent, disabled by default
+    #[cfg(feature = "transcode")]
+    #[clap(long, value_parser = clap::value_parser!(u32).range(500..=512000))]
+    transcode_bitrate: Option<u32>,
+    /// Opus encoder complexity of the transcoded voice, from 0 (fastest) to 10 (best quality)
+    #[cfg(feature = "transcode")]
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=10), default_value = "5")]
+    transcode_complexity: u8,
 }
 
-fn load_certs<P: AsRef<Path>>(path: P) -> io::Result<Vec<Certificate>> {
-    certs(&mut BufReader::new(File::open(path)?))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))
-        .map(|mut certs| certs.drain(..).map(Certificate).collect())
+/// Accept a single string for list values in the config file, as they were single values before
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
 }
 
-fn load_keys<P: AsRef<Path>>(path: P) -> io::Result<Vec<PrivateKey>> {
-    pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
-        .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
+impl Args {
+    /// Parse the command line, and merge it with the config file if one is given
+    fn load() -> Result<Self, anyhow::Error> {
+        Self::load_from(Args::command().get_matches())
+    }
+
+    fn load_from(matches: ArgMatches) -> Result<Self, anyhow::Error> {
+        let args = Args::from_arg_matches(&matches)?;
+
+        let path = match &args.config {
+            Some(path) => path.clone(),
+            None => return Ok(args),
+        };
+
+        let content = std::fs::read_to_string(&path)?;
+        let file_values: serde_json::Map<String, serde_json::Value> = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content)?,
+            _ => serde_json::from_str(&content)?,
+        };
+
+        let command = Args::command();
+        let mut values = match serde_json::to_value(&args)? {
+            serde_json::Value::Object(values) => values,
+            _ => return Err(anyhow::anyhow!("cannot merge config file")),
+        };
+
+        for (key, value) in file_values {
+            // Repeatable flags have a singular long name, e.g. admin-token, while their id is the plural field name
+            let id = match command.get_arguments().find(|arg| arg.get_long() == Some(key.as_str())) {
+                Some(arg) => arg.get_id().to_string(),
+                None => return Err(anyhow::anyhow!("unknown config key {}", key)),
+            };
+
+            if matches.value_source(&id) != Some(ValueSource::CommandLine) {
+                values.insert(id, value);
+            }
+        }
+
+        let mut merged: Args = serde_json::from_value(serde_json::Value::Object(values))?;
+        merged.config = Some(path);
+
+        Ok(merged)
+    }
 }
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -84,30 +279,113 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let args = Args::parse();
+    let args = match Args::load() {
+        Ok(args) => args,
+        Err(e) => {
+            tracing::error!("cannot load config: {}", e);
+            return;
+        }
+    };
+
+    let http_password = match args.http_password.clone() {
+        Some(http_password) => http_password,
+        None => {
+            tracing::error!("http password must be set with --http-password or in the config file");
+            return;
+        }
+    };
+
+    if args.cert.len() != args.key.len() {
+        tracing::error!(
+            "each --cert must have a matching --key, got {} certificates and {} keys",
+            args.cert.len(),
+            args.key.len()
+        );
+        return;
+    }
+
+    let mut pairs = Vec::with_capacity(args.cert.len());
+
+    for (cert_path, key_path) in args.cert.iter().zip(args.key.iter()) {
+        if args.generate_cert && !(Path::new(cert_path).exists() && Path::new(key_path).exists()) {
+            match generate_self_signed_cert(cert_path.as_str(), key_path.as_str()) {
+                Ok(_) => tracing::info!(
+                    "generated self signed certificate at path {} with key at path {}",
+                    cert_path,
+                    key_path
+                ),
+                Err(e) => {
+                    tracing::error!("cannot generate self signed certificate: {}", e);
+                    return;
+                }
+            }
+        }
+
+        let certs = match load_certs(cert_path.as_str()) {
+            Ok(certs) => certs,
+            Err(e) => {
+                tracing::error!(
+                    "cannot load certificate at path {}: {}, use --generate-cert to create a self signed one",
+                    cert_path,
+                    e
+                );
+                return;
+            }
+        };
+
+        if let Some(cert) = certs.first() {
+            tracing::info!("using certificate {} with sha256 fingerprint {}", cert_path, get_fingerprint(cert));
+        }
+
+        let mut keys = match load_keys(key_path.as_str()) {
+            Ok(k) => k,
+            Err(e) => {
+                tracing::error!("cannot load key at path {}: {}", key_path, e);
+                return;
+            }
+        };
 
-    let certs = match load_certs(args.cert.as_str()) {
-        Ok(certs) => certs,
+        if keys.is_empty() {
+            tracing::error!("no pkcs8 private key found at path {}", key_path);
+            return;
+        }
+
+        pairs.push((certs, keys.remove(0)));
+    }
+
+    let cipher_suites = match parse_cipher_suites(&args.tls_cipher_suites) {
+        Ok(cipher_suites) => cipher_suites,
         Err(e) => {
-            tracing::error!("cannot load certificate at path {}: {}", args.cert, e);
+            tracing::error!("cannot create tls config: {}", e);
             return;
         }
     };
 
-    let mut keys = match load_keys(args.key.as_str()) {
-        Ok(k) => k,
+    let registry = match &args.registry_file {
+        Some(path) => match Registry::load(path) {
+            Ok(registry) => {
+                tracing::info!("loaded {} registered users from {}", registry.len(), path.display());
+
+                Some(registry)
+            }
+            Err(e) => {
+                tracing::error!("cannot load registry at path {}: {}", path.display(), e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let config = match create_tls_config(pairs.clone(), args.tls_min_version, &cipher_suites, false) {
+        Ok(config) => config,
         Err(e) => {
-            tracing::error!("cannot load key at path {}: {}", args.key, e);
+            tracing::error!("cannot create tls config: {}", e);
             return;
         }
     };
 
-    let config = match rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, keys.remove(0))
-        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
-    {
+    // Client certificates are only asked by the mumble listener, browsers reaching the http api are not prompted for one
+    let mumble_config = match create_tls_config(pairs, args.tls_min_version, &cipher_suites, registry.is_some()) {
         Ok(config) => config,
         Err(e) => {
             tracing::error!("cannot create tls config: {}", e);
@@ -115,7 +393,7 @@ async fn main() {
         }
     };
 
-    let acceptor = TlsAcceptor::from(Arc::new(config.clone()));
+    let acceptor = TlsAcceptor::from(Arc::new(mumble_config));
 
     tracing::info!("tcp/udp server start listening on {}", args.listen);
     tracing::info!("http server start listening on {}", args.http_listen);
@@ -129,13 +407,165 @@ async fn main() {
     server_version.set_release(VERSION.to_string());
     server_version.set_version(version);
 
-    let udp_socket = Arc::new(UdpSocket::bind(&args.listen).await.unwrap());
-    let state = Arc::new(RwLock::new(ServerState::new(udp_socket.clone())));
-    let udp_state = state.clone();
+    let bans = match BanList::load(args.ban_file.clone()) {
+        Ok(bans) => bans,
+        Err(e) => {
+            tracing::error!("cannot load ban list: {}", e);
+            return;
+        }
+    };
 
-    actix_rt::spawn(async move {
-        create_udp_server(version, udp_socket, udp_state).await;
-    });
+    let listen = match tokio::net::lookup_host(&args.listen).await.map(|mut addresses| addresses.next()) {
+        Ok(Some(listen)) => listen,
+        Ok(None) => {
+            tracing::error!("cannot resolve listen address {}", args.listen);
+            return;
+        }
+        Err(e) => {
+            tracing::error!("cannot resolve listen address {}: {}", args.listen, e);
+            return;
+        }
+    };
+
+    let udp_socket = match bind_udp(listen) {
+        Ok(udp_socket) => Arc::new(udp_socket),
+        Err(e) => {
+            tracing::error!("cannot bind udp socket on {}: {}", listen, e);
+            return;
+        }
+    };
+    let mut server_state = ServerState::new(udp_socket.clone(), bans);
+    server_state.registry = registry;
+
+    #[cfg(feature = "monitor")]
+    if let (Some(channel_id), Some(output)) = (args.monitor_channel, args.monitor_output.as_deref()) {
+        server_state.monitor = match zumble::monitor::Monitor::create(channel_id, output).await {
+            Ok(monitor) => Some(Arc::new(monitor)),
+            Err(e) => {
+                tracing::error!("cannot create monitor on {}: {}", output, e);
+                return;
+            }
+        };
+
+        tracing::info!("monitoring channel {} to {}", channel_id, output);
+    }
+
+    #[cfg(feature = "transcode")]
+    if let Some(bitrate) = args.transcode_bitrate {
+        server_state.transcoder = Some(Arc::new(zumble::transcoder::Transcoder::new(bitrate, args.transcode_complexity)));
+
+        tracing::info!("transcoding voice to {} bps", bitrate);
+    }
+
+    if let (Some(channel_id), Some(directory)) = (args.record_channel, args.record_dir.clone()) {
+        server_state.recorder = match Recorder::create(channel_id, directory.clone()) {
+            Ok(recorder) => Some(Arc::new(recorder)),
+            Err(e) => {
+                tracing::error!("cannot create recorder in {}: {}", directory.display(), e);
+                return;
+            }
+        };
+
+        tracing::info!("recording channel {} to {}", channel_id, directory.display());
+    }
+
+    server_state.context_actions = args.context_actions.clone();
+    server_state.info.protocol_version = version;
+    server_state.info.listen = Some(listen);
+    server_state.info.http_listen = args.http_listen.clone();
+    server_state.info.https = args.https;
+    server_state.info.tls_min_version = args.tls_min_version;
+    server_state.max_clients = args.max_clients;
+    server_state.connection_limiter = ConnectionLimiter::new(args.max_connections_per_ip);
+    server_state.max_channel_depth = args.max_channel_depth;
+    server_state.max_channels_per_client = args.max_channels_per_client;
+    server_state.max_channels = args.max_channels;
+    server_state.max_temporary_channels = args.max_temporary_channels;
+    server_state.max_bandwidth = args.max_bandwidth;
+    server_state.default_channel_name = args.default_channel.clone();
+    server_state.write_timeout = Duration::from_millis(args.write_timeout);
+    server_state.handshake_timeout = Duration::from_millis(args.handshake_timeout);
+    server_state.crypt_rekey_interval = Duration::from_secs(args.crypt_rekey_interval);
+    server_state.voice_batching = args.voice_batching;
+    server_state.positional_context = args.positional_context;
+    server_state.max_position_info_size = args.max_position_info_size;
+    server_state.client_queue_capacity = args.client_queue_capacity as usize;
+    server_state.tcp_tunnel = !args.no_tcp_tunnel;
+    server_state.recording_allowed = !args.no_recording;
+
+    if args.suggest_positional.is_some() || args.suggest_push_to_talk.is_some() {
+        let mut suggest_config = SuggestConfig::new();
+
+        if let Some(positional) = args.suggest_positional {
+            suggest_config.set_positional(positional);
+        }
+
+        if let Some(push_to_talk) = args.suggest_push_to_talk {
+            suggest_config.set_push_to_talk(push_to_talk);
+        }
+
+        server_state.suggest_config = Some(suggest_config);
+    }
+
+    for restricted_channel in &args.restricted_channels {
+        server_state
+            .restricted_channel_tokens
+            .entry(restricted_channel.channel.clone())
+            .or_default()
+            .insert(restricted_channel.token.clone());
+    }
+
+    server_state.admin_tokens = args.admin_tokens.iter().cloned().collect();
+    server_state.channel_ranges = args
+        .channel_ranges
+        .iter()
+        .map(|channel_range| (channel_range.channel.clone(), channel_range.range))
+        .collect();
+    server_state.channel_capacities = args
+        .channel_capacities
+        .iter()
+        .map(|channel_capacity| (channel_capacity.channel.clone(), channel_capacity.capacity))
+        .collect();
+    server_state.listen_only_channels = args.listen_only_channels.iter().cloned().collect();
+    server_state.idle_channels = args.idle_channels.iter().cloned().collect();
+    server_state.idle_timeout = Duration::from_secs(args.idle_timeout);
+    server_state.idle_move_channel_name = args.idle_move_channel.clone();
+
+    // Only the root channel exists yet, the next ones are configured when created
+    if let Some(root) = server_state.channels.get(&0).cloned() {
+        if let Ok(mut root) = root.write_err().await {
+            server_state.configure_channel(&mut root);
+        }
+    }
+
+    for init_step in &args.init_steps {
+        match init_step {
+            InitStepKind::TokenChannel if !args.token_channels.is_empty() => {
+                server_state
+                    .init_steps
+                    .push(InitStep::JoinTokenChannel(args.token_channels.clone()));
+            }
+            InitStepKind::VoiceTarget if !args.voice_targets.is_empty() => {
+                server_state.init_steps.push(InitStep::VoiceTarget(args.voice_targets.clone()));
+            }
+            InitStepKind::Greeting => {
+                if let Some(greeting) = args.greeting.clone() {
+                    server_state.init_steps.push(InitStep::Greeting(greeting));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if let Some(webhook_url) = args.webhook_url.clone() {
+        tracing::info!("sending events to webhook {}", webhook_url);
+
+        server_state.webhook = Some(Arc::new(Webhook::new(webhook_url)));
+    }
+
+    let state = Arc::new(RwLock::new(server_state));
+    // Run along the tcp and http servers, a fatal socket error stops all of them
+    let udp_server = create_udp_server(version, udp_socket, state.clone());
 
     let clean_state = state.clone();
 
@@ -143,7 +573,21 @@ async fn main() {
         clean_loop(clean_state).await;
     });
 
-    let tcp_listener = TcpListener::bind(args.listen.clone()).await.unwrap();
+    if args.webhook_url.is_some() {
+        let talking_state = state.clone();
+
+        actix_rt::spawn(async move {
+            talking_loop(talking_state).await;
+        });
+    }
+
+    let tcp_listener = match bind_tcp(listen) {
+        Ok(tcp_listener) => tcp_listener,
+        Err(e) => {
+            tracing::error!("cannot bind tcp listener on {}: {}", listen, e);
+            return;
+        }
+    };
 
     let mut waiting_list = Vec::new();
 
@@ -156,19 +600,120 @@ async fn main() {
         config,
         args.https,
         state.clone(),
-        args.http_user,
-        args.http_password,
+        HttpCredentials {
+            user: args.http_user,
+            password: http_password,
+            readonly: args.http_readonly_user.zip(args.http_readonly_password),
+        },
         args.http_log,
+        args.metrics_public,
     );
 
     if let Some(http_server) = http_server {
         waiting_list.push(http_server);
     }
 
-    match futures::future::try_join_all(waiting_list).await {
-        Ok(_) => (),
-        Err(e) => {
-            tracing::error!("agent error: {}", e);
+    // Both listeners are bound, the server can be reported as ready until a shutdown signal is received
+    match state.read_err().await {
+        Ok(state) => state.ready.store(true, Ordering::Relaxed),
+        Err(e) => tracing::error!("cannot mark server as ready: {}", e),
+    }
+
+    let shutdown_state = state.clone();
+
+    actix_rt::spawn(async move {
+        shutdown_signal().await;
+
+        if let Ok(state) = shutdown_state.read_err().await {
+            state.ready.store(false, Ordering::Relaxed);
+        }
+    });
+
+    let handles = waiting_list.iter().map(|server| server.handle()).collect::<Vec<_>>();
+
+    tokio::select! {
+        result = futures::future::try_join_all(waiting_list) => {
+            if let Err(e) = result {
+                tracing::error!("agent error: {}", e);
+            }
         }
+        result = udp_server => {
+            if let Err(e) = result {
+                tracing::error!("udp server stopped, shutting down: {}", e);
+            }
+
+            if let Ok(state) = state.read_err().await {
+                state.ready.store(false, Ordering::Relaxed);
+            }
+
+            for handle in handles {
+                handle.stop(true).await;
+            }
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                tracing::error!("cannot listen for terminate signal: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_keys_are_long_flag_names() {
+        let path = std::env::temp_dir().join(format!("zumble-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "http-password = \"secret\"\nadmin-token = [\"a\", \"b\"]\nrestricted-channel = [\"police:Police station\"]\n",
+        )
+        .unwrap();
+
+        let matches = Args::command().get_matches_from(["zumble", "--config", path.to_str().unwrap(), "--admin-token", "c"]);
+        let args = Args::load_from(matches);
+        std::fs::remove_file(&path).unwrap();
+        let args = args.unwrap();
+
+        // Flags given on the command line override the config file
+        assert_eq!(args.admin_tokens, vec!["c".to_string()]);
+        assert_eq!(
+            args.restricted_channels,
+            vec!["police:Police station".parse::<RestrictedChannelConfig>().unwrap()]
+        );
+        assert_eq!(args.http_password, Some("secret".to_string()));
+    }
+
+    #[test]
+    fn init_steps_order_is_configurable() {
+        let args = Args::load_from(Args::command().get_matches_from(["zumble", "--http-password", "secret"])).unwrap();
+
+        assert_eq!(
+            args.init_steps,
+            vec![InitStepKind::TokenChannel, InitStepKind::VoiceTarget, InitStepKind::Greeting]
+        );
+
+        let matches = Args::command().get_matches_from(["zumble", "--http-password", "secret", "--init-steps", "greeting,token-channel"]);
+        let args = Args::load_from(matches).unwrap();
+
+        assert_eq!(args.init_steps, vec![InitStepKind::Greeting, InitStepKind::TokenChannel]);
     }
 }
@@ -1,11 +1,226 @@
+use crate::error::{DecryptError, MumbleError};
 use crate::proto::MessageKind;
 use crate::voice::{Clientbound, VoicePacket};
 use bytes::Bytes;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
 
 #[derive(Debug, Clone)]
 pub enum ClientMessage {
     RouteVoicePacket(VoicePacket<Clientbound>),
     SendVoicePacket(VoicePacket<Clientbound>),
     SendMessage { kind: MessageKind, payload: Bytes },
-    Disconnect,
+    Disconnect(DisconnectReason),
+}
+
+/// Why a client was disconnected, used as the label of the disconnect metric
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client closed the connection
+    Eof,
+    /// No ping was received from the client for too long
+    PingTimeout,
+    /// Disconnected by the server, e.g. from the http api
+    Force,
+    /// Reading from or writing to the client failed or timed out
+    IoError,
+    /// A voice packet of the client could not be decoded
+    Decrypt,
+    /// Any other error while handling the client
+    Error,
+}
+
+impl DisconnectReason {
+    /// Find the reason in the result of a client run
+    pub fn from_result(result: &Result<(), anyhow::Error>) -> Self {
+        let err = match result {
+            Ok(_) => return DisconnectReason::Eof,
+            Err(err) => err,
+        };
+
+        for cause in err.chain() {
+            if let Some(err) = cause.downcast_ref::<MumbleError>() {
+                match err {
+                    MumbleError::ForceDisconnect(reason) => return *reason,
+                    MumbleError::Decrypt(_) => return DisconnectReason::Decrypt,
+                    MumbleError::Io(_) | MumbleError::Timeout => return DisconnectReason::IoError,
+                    _ => (),
+                }
+            }
+
+            if cause.is::<DecryptError>() {
+                return DisconnectReason::Decrypt;
+            }
+
+            if cause.is::<std::io::Error>() {
+                return DisconnectReason::IoError;
+            }
+        }
+
+        DisconnectReason::Error
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::Eof => "eof",
+            DisconnectReason::PingTimeout => "ping_timeout",
+            DisconnectReason::Force => "force",
+            DisconnectReason::IoError => "io_error",
+            DisconnectReason::Decrypt => "decrypt",
+            DisconnectReason::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl ClientMessage {
+    fn kind_name(&self) -> &'static str {
+        match self {
+            ClientMessage::RouteVoicePacket(_) => "route_voice_packet",
+            ClientMessage::SendVoicePacket(_) => "send_voice_packet",
+            ClientMessage::SendMessage { .. } => "send_message",
+            ClientMessage::Disconnect(_) => "disconnect",
+        }
+    }
+}
+
+/// Create the message queue of a client, tokio mpsc does not expose its length so it is tracked on both sides
+pub fn channel(buffer: usize) -> (Publisher, Consumer) {
+    let (sender, receiver) = mpsc::channel(buffer);
+    let queue_length = Arc::new(AtomicUsize::new(0));
+
+    (
+        Publisher {
+            sender,
+            queue_length: queue_length.clone(),
+        },
+        Consumer {
+            receiver,
+            queue_length,
+            pending: None,
+        },
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct Publisher {
+    sender: Sender<ClientMessage>,
+    queue_length: Arc<AtomicUsize>,
+}
+
+impl Publisher {
+    pub fn try_send(&self, message: ClientMessage) -> Result<(), TrySendError<ClientMessage>> {
+        // Increment before sending so the consumer never decrements below zero
+        let queue_length = self.queue_length.fetch_add(1, Ordering::Relaxed) + 1;
+
+        match self.sender.try_send(message) {
+            Ok(_) => {
+                crate::metrics::CLIENT_QUEUE_LENGTH.observe(queue_length as f64);
+
+                Ok(())
+            }
+            Err(err) => {
+                self.queue_length.fetch_sub(1, Ordering::Relaxed);
+
+                Err(err)
+            }
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Number of messages waiting to be handled by the client
+    pub fn queue_length(&self) -> usize {
+        self.queue_length.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Consumer {
+    receiver: Receiver<ClientMessage>,
+    queue_length: Arc<AtomicUsize>,
+    /// Message read ahead and put back, returned before the queued ones
+    pending: Option<ClientMessage>,
+}
+
+impl Consumer {
+    pub async fn recv(&mut self) -> Option<ClientMessage> {
+        if let Some(message) = self.pending.take() {
+            return Some(message);
+        }
+
+        let message = self.receiver.recv().await;
+
+        if message.is_some() {
+            self.queue_length.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        message
+    }
+
+    /// Get the next message if one is already queued, never waits
+    pub fn try_recv(&mut self) -> Option<ClientMessage> {
+        if let Some(message) = self.pending.take() {
+            return Some(message);
+        }
+
+        let message = self.receiver.try_recv().ok();
+
+        if message.is_some() {
+            self.queue_length.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        message
+    }
+
+    /// Put back a message read with `try_recv`, only one message can be put back
+    pub fn put_back(&mut self, message: ClientMessage) {
+        self.pending = Some(message);
+    }
+
+    /// Close the queue and discard the remaining messages, returns how many were dropped
+    pub fn drain(&mut self) -> usize {
+        self.receiver.close();
+
+        let mut dropped = 0;
+
+        while let Some(message) = self.try_recv() {
+            crate::metrics::CLIENT_DROPPED_MESSAGES_TOTAL
+                .with_label_values(&[message.kind_name()])
+                .inc();
+
+            dropped += 1;
+        }
+
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn disconnect_reason_is_found_behind_the_context() {
+        let force = Err(MumbleError::ForceDisconnect(DisconnectReason::PingTimeout)).context("force disconnect");
+        let io = Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset)).context("read message");
+        let decrypt = Err(MumbleError::Decrypt(DecryptError::Mac)).context("handle voice packet");
+        let other = Err(MumbleError::UnexpectedMessageKind(99)).context("handle message");
+
+        assert_eq!(DisconnectReason::from_result(&Ok(())), DisconnectReason::Eof);
+        assert_eq!(DisconnectReason::from_result(&force), DisconnectReason::PingTimeout);
+        assert_eq!(DisconnectReason::from_result(&io), DisconnectReason::IoError);
+        assert_eq!(DisconnectReason::from_result(&decrypt), DisconnectReason::Decrypt);
+        assert_eq!(DisconnectReason::from_result(&other), DisconnectReason::Error);
+    }
 }
@@ -1,6 +1,6 @@
 use lazy_static::lazy_static;
-use prometheus::{opts, register_int_counter_vec, register_int_gauge};
-use prometheus::{IntCounterVec, IntGauge};
+use prometheus::{histogram_opts, opts, register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge};
+use prometheus::{Histogram, IntCounter, IntCounterVec, IntGauge};
 
 lazy_static! {
     pub static ref MESSAGES_TOTAL: IntCounterVec = register_int_counter_vec!(
@@ -13,4 +13,98 @@ lazy_static! {
             .expect("can't create a metric");
     pub static ref CLIENTS_TOTAL: IntGauge =
         register_int_gauge!(opts!("zumble_clients_total", "Total number of clients")).expect("can't create a metric");
+    pub static ref POSITIONAL_CLIENTS_TOTAL: IntGauge = register_int_gauge!(opts!(
+        "zumble_positional_clients_total",
+        "number of clients whose last voice packet had positional data"
+    ))
+    .expect("can't create a metric");
+    pub static ref INVALID_VOICE_TARGETS_TOTAL: IntCounter = register_int_counter!(opts!(
+        "zumble_invalid_voice_targets_total",
+        "number of voice target configurations rejected because of an invalid id"
+    ))
+    .expect("can't create a metric");
+    pub static ref POSITION_INFO_OVERSIZED_TOTAL: IntCounter = register_int_counter!(opts!(
+        "zumble_position_info_oversized_total",
+        "number of voice packets whose positional data was stripped because it was too long"
+    ))
+    .expect("can't create a metric");
+    pub static ref CONNECTIONS_RATE_LIMITED_TOTAL: IntCounter = register_int_counter!(opts!(
+        "zumble_connections_rate_limited_total",
+        "number of connections closed because their ip made too many connection attempts"
+    ))
+    .expect("can't create a metric");
+    pub static ref UDP_SOCKET_COLLISIONS_TOTAL: IntCounter = register_int_counter!(opts!(
+        "zumble_udp_socket_collisions_total",
+        "number of udp addresses claimed by a client while registered to another one"
+    ))
+    .expect("can't create a metric");
+    pub static ref CRYPT_LATE_TOTAL: IntCounter =
+        register_int_counter!(opts!("zumble_crypt_late_total", "number of late udp voice packets")).expect("can't create a metric");
+    pub static ref CRYPT_LOST_TOTAL: IntCounter =
+        register_int_counter!(opts!("zumble_crypt_lost_total", "number of lost udp voice packets")).expect("can't create a metric");
+    pub static ref CRYPT_REPEAT_TOTAL: IntCounter =
+        register_int_counter!(opts!("zumble_crypt_repeat_total", "number of repeated udp voice packets")).expect("can't create a metric");
+    pub static ref CRYPT_MAC_FAIL_TOTAL: IntCounter = register_int_counter!(opts!(
+        "zumble_crypt_mac_fail_total",
+        "number of udp voice packets failing the mac check"
+    ))
+    .expect("can't create a metric");
+    pub static ref CRYPT_RESET_TOTAL: IntCounter =
+        register_int_counter!(opts!("zumble_crypt_reset_total", "number of crypt state resets")).expect("can't create a metric");
+    pub static ref CRYPT_REKEY_TOTAL: IntCounter =
+        register_int_counter!(opts!("zumble_crypt_rekey_total", "number of crypt key rotations")).expect("can't create a metric");
+    pub static ref VOICE_BATCHED_PACKETS_TOTAL: IntCounter = register_int_counter!(opts!(
+        "zumble_voice_batched_packets_total",
+        "number of voice packets merged into a previous packet of the same speaker"
+    ))
+    .expect("can't create a metric");
+    pub static ref TCP_TUNNEL_DROPPED_PACKETS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "zumble_tcp_tunnel_dropped_packets_total",
+            "number of voice packets dropped because the tcp tunnel is disabled"
+        ),
+        &["direction"]
+    )
+    .expect("can't create a metric");
+    pub static ref UDP_RECV_ERRORS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!("zumble_udp_recv_errors_total", "number of errors receiving from the udp socket"),
+        &["kind"]
+    )
+    .expect("can't create a metric");
+    pub static ref BROADCAST_SEND_ERRORS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "zumble_broadcast_send_errors_total",
+            "number of broadcast messages that could not be queued for a client"
+        ),
+        &["reason"]
+    )
+    .expect("can't create a metric");
+    pub static ref DISCONNECTS_TOTAL: IntCounterVec =
+        register_int_counter_vec!(opts!("zumble_disconnects_total", "number of client disconnections"), &["reason"])
+            .expect("can't create a metric");
+    pub static ref LOCK_TIMEOUTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!("zumble_lock_timeouts_total", "number of locks that could not be acquired in time"),
+        &["lock", "mode"]
+    )
+    .expect("can't create a metric");
+    pub static ref CLIENT_QUEUE_LENGTH: Histogram = register_histogram!(histogram_opts!(
+        "zumble_client_queue_length",
+        "number of pending messages in a client queue when a message is queued",
+        vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0]
+    ))
+    .expect("can't create a metric");
+    pub static ref TRANSCODE_DURATION_SECONDS: Histogram = register_histogram!(histogram_opts!(
+        "zumble_transcode_duration_seconds",
+        "time spent to transcode an opus frame",
+        vec![0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025]
+    ))
+    .expect("can't create a metric");
+    pub static ref CLIENT_DROPPED_MESSAGES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "zumble_client_dropped_messages_total",
+            "number of messages still queued for a client when it disconnects"
+        ),
+        &["kind"]
+    )
+    .expect("can't create a metric");
 }
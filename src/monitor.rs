@@ -0,0 +1,178 @@
+//! Channel monitor, decode and mix all the voice routed in a channel to a raw pcm sink
+
+use crate::voice::{Clientbound, VoicePacket, VoicePacketPayload};
+use audiopus::coder::Decoder;
+use audiopus::packet::Packet;
+use audiopus::{Channels, MutSignals, SampleRate};
+use bytes::Bytes;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+const SAMPLE_RATE: usize = 48000;
+/// Mix 20ms of audio at each tick
+const MIX_FRAME_SIZE: usize = SAMPLE_RATE / 50;
+/// Max duration of an opus frame is 120ms
+const MAX_DECODE_FRAME_SIZE: usize = SAMPLE_RATE / 1000 * 120;
+/// Do not buffer more than 1s of audio per speaker, older samples are dropped
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE;
+const SPEAKER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tap on a channel, voice packets are pushed to a mixer task which continuously writes 48kHz mono s16le pcm to the output
+pub struct Monitor {
+    channel_id: u32,
+    sender: Sender<VoicePacket<Clientbound>>,
+}
+
+impl Monitor {
+    /// Create a monitor for the given channel, output can be a file path or an `udp://host:port` address
+    pub async fn create(channel_id: u32, output: &str) -> Result<Self, anyhow::Error> {
+        let sink = Sink::open(output).await?;
+        let (sender, receiver) = mpsc::channel(1024);
+
+        tokio::spawn(mix_loop(receiver, sink));
+
+        Ok(Self { channel_id, sender })
+    }
+
+    pub fn channel_id(&self) -> u32 {
+        self.channel_id
+    }
+
+    /// Push a voice packet to the mixer, packets are dropped if the mixer is lagging behind
+    pub fn push(&self, packet: &VoicePacket<Clientbound>) {
+        if let Err(err) = self.sender.try_send(packet.clone()) {
+            tracing::trace!("monitor dropped voice packet: {}", err);
+        }
+    }
+}
+
+enum Sink {
+    File(tokio::fs::File),
+    Udp(UdpSocket, SocketAddr),
+}
+
+impl Sink {
+    async fn open(output: &str) -> Result<Self, anyhow::Error> {
+        if let Some(address) = output.strip_prefix("udp://") {
+            let address = tokio::net::lookup_host(address)
+                .await?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("cannot resolve monitor output {}", output))?;
+            let bind = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+
+            return Ok(Sink::Udp(UdpSocket::bind(bind).await?, address));
+        }
+
+        Ok(Sink::File(tokio::fs::File::create(output).await?))
+    }
+
+    async fn write(&mut self, samples: &[i16]) -> Result<(), anyhow::Error> {
+        let bytes = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect::<Vec<u8>>();
+
+        match self {
+            Sink::File(file) => file.write_all(&bytes).await?,
+            Sink::Udp(socket, address) => {
+                socket.send_to(&bytes, *address).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Speaker {
+    decoder: Decoder,
+    samples: VecDeque<i16>,
+    ended: bool,
+    last_packet: Instant,
+}
+
+impl Speaker {
+    fn new() -> Result<Self, audiopus::Error> {
+        Ok(Self {
+            decoder: Decoder::new(SampleRate::Hz48000, Channels::Mono)?,
+            samples: VecDeque::new(),
+            ended: false,
+            last_packet: Instant::now(),
+        })
+    }
+
+    fn decode(&mut self, frame: &Bytes) -> Result<(), audiopus::Error> {
+        let mut output = vec![0i16; MAX_DECODE_FRAME_SIZE];
+        let packet = Packet::try_from(frame.as_ref())?;
+        let size = self.decoder.decode(Some(packet), MutSignals::try_from(&mut output)?, false)?;
+
+        self.samples.extend(&output[..size]);
+
+        while self.samples.len() > MAX_BUFFERED_SAMPLES {
+            self.samples.pop_front();
+        }
+
+        Ok(())
+    }
+}
+
+async fn mix_loop(mut receiver: Receiver<VoicePacket<Clientbound>>, mut sink: Sink) {
+    let mut speakers: HashMap<u32, Speaker> = HashMap::new();
+    let mut interval = tokio::time::interval(Duration::from_millis(20));
+
+    loop {
+        tokio::select! {
+            packet = receiver.recv() => {
+                let packet = match packet {
+                    Some(packet) => packet,
+                    None => return,
+                };
+
+                if let VoicePacket::Audio { session_id, payload: VoicePacketPayload::Opus(frame, end), .. } = packet {
+                    let speaker = match speakers.entry(session_id) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => match Speaker::new() {
+                            Ok(speaker) => entry.insert(speaker),
+                            Err(err) => {
+                                tracing::error!("monitor cannot create opus decoder: {}", err);
+
+                                continue;
+                            }
+                        },
+                    };
+
+                    if !frame.is_empty() {
+                        if let Err(err) = speaker.decode(&frame) {
+                            tracing::debug!("monitor cannot decode opus frame of {}: {}", session_id, err);
+                        }
+                    }
+
+                    speaker.ended = end;
+                    speaker.last_packet = Instant::now();
+                }
+            }
+            _ = interval.tick() => {
+                let mut mix = [0i16; MIX_FRAME_SIZE];
+
+                for speaker in speakers.values_mut() {
+                    for sample in mix.iter_mut() {
+                        match speaker.samples.pop_front() {
+                            Some(value) => *sample = sample.saturating_add(value),
+                            None => break,
+                        }
+                    }
+                }
+
+                // Forget speakers that ended their transmission, or vanished without sending the end bit
+                speakers.retain(|_, speaker| {
+                    !(speaker.ended && speaker.samples.is_empty()) && speaker.last_packet.elapsed() < SPEAKER_TIMEOUT
+                });
+
+                if let Err(err) = sink.write(&mix).await {
+                    tracing::error!("monitor cannot write to output: {}", err);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Minimal opus packet parsing and packing (RFC 6716 section 3), no decoding is done here
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Max duration of an opus packet, 120ms at 48kHz
+pub const MAX_PACKET_SAMPLES: u64 = 5760;
+/// Max number of frames in an opus packet
+pub const MAX_PACKET_FRAMES: usize = 48;
+
+/// Number of samples at 48kHz of each frame of an opus packet, read from its TOC byte
+pub fn toc_frame_samples(toc: u8) -> u64 {
+    let config = toc >> 3;
+
+    match config {
+        // SILK: 10, 20, 40, 60ms
+        0..=11 => [480, 960, 1920, 2880][(config % 4) as usize],
+        // Hybrid: 10, 20ms
+        12..=15 => [480, 960][(config % 2) as usize],
+        // CELT: 2.5, 5, 10, 20ms
+        _ => [120, 240, 480, 960][(config % 4) as usize],
+    }
+}
+
+/// Number of samples at 48kHz in an opus packet
+pub fn packet_samples(packet: &[u8]) -> u64 {
+    let toc = match packet.first() {
+        Some(toc) => *toc,
+        None => return 0,
+    };
+
+    let frame_count = match toc & 0b11 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => packet.get(1).map(|count| (count & 0b11_1111) as u64).unwrap_or(0),
+    };
+
+    toc_frame_samples(toc) * frame_count
+}
+
+/// Whether the packet contains a single frame (code 0), only those can be combined
+pub fn is_single_frame(packet: &[u8]) -> bool {
+    matches!(packet.first(), Some(toc) if toc & 0b11 == 0)
+}
+
+/// Whether two single frame packets have the same mode, bandwidth, frame size and channel count
+pub fn same_configuration(a: &[u8], b: &[u8]) -> bool {
+    match (a.first(), b.first()) {
+        (Some(a), Some(b)) => a >> 2 == b >> 2,
+        _ => false,
+    }
+}
+
+/// Combine single frame packets sharing the same configuration into a variable bitrate code 3 packet
+///
+/// Caller must ensure the packets are single frame, share the same configuration and fit in `MAX_PACKET_SAMPLES`
+pub fn combine_packets(packets: &[&[u8]]) -> Bytes {
+    if packets.len() == 1 {
+        return Bytes::copy_from_slice(packets[0]);
+    }
+
+    let mut combined = BytesMut::with_capacity(packets.iter().map(|packet| packet.len() + 1).sum::<usize>() + 2);
+    combined.put_u8((packets[0][0] & !0b11) | 0b11);
+    // VBR flag, no padding, frame count
+    combined.put_u8(0b1000_0000 | packets.len() as u8);
+
+    // Every frame length is written except for the last one
+    for packet in &packets[..packets.len() - 1] {
+        let length = packet.len() - 1;
+
+        if length < 252 {
+            combined.put_u8(length as u8);
+        } else {
+            let first = 252 + ((length - 252) & 0b11);
+            combined.put_u8(first as u8);
+            combined.put_u8(((length - first) / 4) as u8);
+        }
+    }
+
+    for packet in packets {
+        combined.put_slice(&packet[1..]);
+    }
+
+    combined.freeze()
+}
@@ -0,0 +1,125 @@
+//! Channel permissions of clients, computed from their access tokens
+
+use crate::ServerState;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// const PERM_NONE: u32 = 0x0;
+pub const PERM_WRITE: u32 = 0x1;
+pub const PERM_TRAVERSE: u32 = 0x2;
+pub const PERM_ENTER: u32 = 0x4;
+pub const PERM_SPEAK: u32 = 0x8;
+pub const PERM_MUTEDEAFEN: u32 = 0x10;
+pub const PERM_MOVE: u32 = 0x20;
+// const PERM_MAKECHANNEL: u32 = 0x40;
+// const PERM_LINKCHANNEL: u32 = 0x80;
+pub const PERM_WHISPER: u32 = 0x100;
+pub const PERM_TEXTMESSAGE: u32 = 0x200;
+pub const PERM_MAKETEMPCHANNEL: u32 = 0x400;
+pub const PERM_LISTEN: u32 = 0x800;
+pub const PERM_KICK: u32 = 0x10000;
+pub const PERM_BAN: u32 = 0x20000;
+// const PERM_REGISTER: u32 = 0x40000;
+// const PERM_SELFREGISTER: u32 = 0x80000;
+// const PERM_CACHED: u32 = 0x8000000;
+// const PERM_ALL: u32 = 0xf0fff;
+
+pub const PERM_DEFAULT: u32 =
+    PERM_TRAVERSE | PERM_ENTER | PERM_SPEAK | PERM_WHISPER | PERM_TEXTMESSAGE | PERM_MAKETEMPCHANNEL | PERM_LISTEN;
+/// Write lets the client open the acl editor, acls are read only
+pub const PERM_ADMIN: u32 = PERM_DEFAULT | PERM_WRITE | PERM_MUTEDEAFEN | PERM_MOVE | PERM_KICK | PERM_BAN;
+/// Permissions removed in a restricted channel for clients without one of its tokens
+pub const PERM_RESTRICTED: u32 = PERM_ENTER | PERM_SPEAK | PERM_WHISPER | PERM_MAKETEMPCHANNEL | PERM_LISTEN;
+
+/// Whether one of the tokens is an admin token
+pub fn is_admin(state: &ServerState, tokens: &[String]) -> bool {
+    tokens.iter().any(|token| state.admin_tokens.contains(token))
+}
+
+/// Whether a client with these tokens may enter, speak and listen in the channel
+pub fn can_enter(state: &ServerState, tokens: &[String], channel_id: u32) -> bool {
+    match state.restricted_channels.get(&channel_id) {
+        Some(channel_tokens) => is_admin(state, tokens) || tokens.iter().any(|token| channel_tokens.contains(token)),
+        None => true,
+    }
+}
+
+pub fn get_channel_permissions(state: &ServerState, tokens: &[String], channel_id: u32) -> u32 {
+    let permissions = if is_admin(state, tokens) { PERM_ADMIN } else { PERM_DEFAULT };
+
+    if can_enter(state, tokens, channel_id) {
+        permissions
+    } else {
+        permissions & !PERM_RESTRICTED
+    }
+}
+
+/// Access token allowed in a restricted channel, written as `token:channel_name`
+///
+/// Channels are given by name as ids are reused once a temporary channel is removed, e.g. `police:Police station`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RestrictedChannelConfig {
+    pub token: String,
+    pub channel: String,
+}
+
+impl FromStr for RestrictedChannelConfig {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some((token, channel)) if !token.is_empty() && !channel.is_empty() => Ok(Self {
+                token: token.to_string(),
+                channel: channel.to_string(),
+            }),
+            _ => Err(format!("invalid restricted channel {}, expected token:channel_name", value)),
+        }
+    }
+}
+
+impl TryFrom<String> for RestrictedChannelConfig {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for RestrictedChannelConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.token, self.channel)
+    }
+}
+
+impl From<RestrictedChannelConfig> for String {
+    fn from(value: RestrictedChannelConfig) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ban::BanList;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn permissions_follow_tokens() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut state = ServerState::new(Arc::new(socket), BanList::load(None).unwrap());
+        state.admin_tokens.insert("admin".to_string());
+        state.restricted_channels.insert(1, HashSet::from(["police".to_string()]));
+
+        let tokens = |tokens: &[&str]| tokens.iter().map(|token| token.to_string()).collect::<Vec<String>>();
+
+        assert_eq!(get_channel_permissions(&state, &tokens(&[]), 0), PERM_DEFAULT);
+        assert_eq!(get_channel_permissions(&state, &tokens(&[]), 1), PERM_DEFAULT & !PERM_RESTRICTED);
+        assert_eq!(get_channel_permissions(&state, &tokens(&["medic", "police"]), 1), PERM_DEFAULT);
+        assert_eq!(get_channel_permissions(&state, &tokens(&["admin"]), 1), PERM_ADMIN);
+        assert!(!can_enter(&state, &tokens(&["medic"]), 1));
+    }
+}
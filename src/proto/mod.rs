@@ -1,14 +1,23 @@
 use crate::error::MumbleError;
 use crate::handler::Handler;
+use crate::proto::mumble::{ServerConfig, UserState};
 use bytes::{BufMut, Bytes, BytesMut};
-use protobuf::Message;
+use protobuf::{CodedInputStream, Message};
 use std::fmt;
-use std::future::Future;
-use std::pin::Pin;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+#[allow(renamed_and_removed_lints, unused_parens, mismatched_lifetime_syntaxes)]
 pub mod mumble;
 
+/// Field number of `UserState.listening_volume_adjustment`, which is not part of our generated proto
+const USER_STATE_LISTENING_VOLUME_ADJUSTMENT: u32 = 23;
+/// Field number of `ServerConfig.recording_allowed`, which is not part of our generated proto
+const SERVER_CONFIG_RECORDING_ALLOWED: u32 = 7;
+/// Max number of udp tunnel messages dropped while waiting for an expected message
+const MAX_SKIPPED_TUNNEL_MESSAGES: usize = 10;
+/// Max total size of the udp tunnel messages dropped while waiting for an expected message
+const MAX_SKIPPED_TUNNEL_BYTES: usize = 16 * 1024;
+
 #[derive(Debug, Clone, Copy)]
 pub enum MessageKind {
     Version = 0,
@@ -118,6 +127,16 @@ pub fn message_to_bytes<T: Message>(kind: MessageKind, message: &T) -> Result<By
     Ok(buffer.freeze())
 }
 
+/// Frame a voice packet in an udp tunnel message, the packet is sent as is and not as a protobuf message
+pub fn tunnel_to_bytes(packet: &[u8]) -> Bytes {
+    let mut buffer = BytesMut::new();
+    buffer.put_u16(MessageKind::UDPTunnel as u16);
+    buffer.put_u32(packet.len() as u32);
+    buffer.put_slice(packet);
+
+    buffer.freeze()
+}
+
 pub async fn send_message<T: Message, S: AsyncWrite + Unpin>(kind: MessageKind, message: &T, stream: &mut S) -> Result<(), MumbleError> {
     tracing::trace!("send message: {:?}, {:?}", std::any::type_name::<T>(), message);
 
@@ -136,29 +155,34 @@ pub async fn send_message<T: Message, S: AsyncWrite + Unpin>(kind: MessageKind,
     Ok(())
 }
 
-pub fn expected_message<T: Message + Handler, S: AsyncRead + Unpin>(
-    kind: MessageKind,
-    stream: &mut S,
-    retry: u8,
-) -> Pin<Box<dyn Future<Output = Result<T, MumbleError>> + '_>> {
-    Box::pin(async move {
+/// Read the next message of the given kind, udp tunnel messages sent before it are skipped within a small budget
+pub async fn expected_message<T: Message + Handler, S: AsyncRead + Unpin>(kind: MessageKind, stream: &mut S) -> Result<T, MumbleError> {
+    let mut skipped_messages = 0;
+    let mut skipped_bytes = 0;
+
+    loop {
         let message_kind = stream.read_u16().await?;
 
-        if message_kind != kind as u16 {
-            let size = stream.read_u32().await?;
-            let mut data = vec![0; size as usize];
-            stream.read_exact(&mut data).await?;
+        if message_kind == kind as u16 {
+            return get_message(stream).await;
+        }
 
-            // a udp tunnel message can come earlier than the expected message, so we retry and drop this message
-            if message_kind == MessageKind::UDPTunnel as u16 && retry < 10 {
-                return expected_message::<T, S>(kind, stream, retry + 1).await;
-            }
+        let size = stream.read_u32().await? as usize;
 
+        // a udp tunnel message can come earlier than the expected message, anything else or too much of them is a protocol violation
+        if message_kind != MessageKind::UDPTunnel as u16
+            || skipped_messages >= MAX_SKIPPED_TUNNEL_MESSAGES
+            || skipped_bytes + size > MAX_SKIPPED_TUNNEL_BYTES
+        {
             return Err(MumbleError::UnexpectedMessageKind(message_kind));
         }
 
-        get_message(stream).await
-    })
+        let mut data = vec![0; size];
+        stream.read_exact(&mut data).await?;
+
+        skipped_messages += 1;
+        skipped_bytes += size;
+    }
 }
 
 pub async fn get_message<T: Message + Handler, S: AsyncRead + Unpin>(stream: &mut S) -> Result<T, MumbleError> {
@@ -172,3 +196,132 @@ pub async fn get_message<T: Message + Handler, S: AsyncRead + Unpin>(stream: &mu
 
     Ok(message)
 }
+
+/// Volume adjustments of listened channels sent by newer clients, as `(channel_id, volume)` pairs
+///
+/// The field is read from the unknown fields of the message, as a repeated `VolumeAdjustment { listening_channel = 1, volume_adjustment = 2 }`
+pub fn get_listening_volume_adjustments(user_state: &UserState) -> Result<Vec<(u32, f32)>, MumbleError> {
+    let mut adjustments = Vec::new();
+
+    let values = match user_state.get_unknown_fields().get(USER_STATE_LISTENING_VOLUME_ADJUSTMENT) {
+        Some(values) => values,
+        None => return Ok(adjustments),
+    };
+
+    for bytes in &values.length_delimited {
+        let mut stream = CodedInputStream::from_bytes(bytes);
+        let mut channel_id = None;
+        let mut volume = None;
+
+        while !stream.eof()? {
+            let (field_number, wire_type) = stream.read_tag_unpack()?;
+
+            match field_number {
+                1 => channel_id = Some(stream.read_uint32()?),
+                2 => volume = Some(stream.read_float()?),
+                _ => stream.skip_field(wire_type)?,
+            }
+        }
+
+        if let (Some(channel_id), Some(volume)) = (channel_id, volume) {
+            adjustments.push((channel_id, volume));
+        }
+    }
+
+    Ok(adjustments)
+}
+
+/// Set `ServerConfig.recording_allowed`, written as an unknown field
+pub fn set_recording_allowed(server_config: &mut ServerConfig, allowed: bool) {
+    server_config
+        .mut_unknown_fields()
+        .add_varint(SERVER_CONFIG_RECORDING_ALLOWED, allowed as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::mumble::Authenticate;
+    use protobuf::CodedOutputStream;
+
+    fn frame(kind: MessageKind, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(kind as u16).to_be_bytes());
+        bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(payload);
+
+        bytes
+    }
+
+    fn authenticate_frame() -> Vec<u8> {
+        let mut authenticate = Authenticate::new();
+        authenticate.set_username("test".to_string());
+
+        frame(MessageKind::Authenticate, &authenticate.write_to_bytes().unwrap())
+    }
+
+    #[tokio::test]
+    async fn expected_message_skips_a_few_tunnel_messages() {
+        let mut bytes = Vec::new();
+
+        for _ in 0..MAX_SKIPPED_TUNNEL_MESSAGES {
+            bytes.extend(frame(MessageKind::UDPTunnel, &[0; 64]));
+        }
+
+        bytes.extend(authenticate_frame());
+
+        let authenticate: Authenticate = expected_message(MessageKind::Authenticate, &mut bytes.as_slice()).await.unwrap();
+
+        assert_eq!(authenticate.get_username(), "test");
+    }
+
+    #[tokio::test]
+    async fn expected_message_stops_on_a_flood_of_tunnel_messages() {
+        let mut bytes = Vec::new();
+
+        for _ in 0..1000 {
+            bytes.extend(frame(MessageKind::UDPTunnel, &[0; 64]));
+        }
+
+        bytes.extend(authenticate_frame());
+
+        let mut stream = bytes.as_slice();
+        let result = expected_message::<Authenticate, _>(MessageKind::Authenticate, &mut stream).await;
+
+        assert!(matches!(result, Err(MumbleError::UnexpectedMessageKind(kind)) if kind == MessageKind::UDPTunnel as u16));
+        // only the skipped messages and the header of the rejected one are consumed
+        assert_eq!(
+            bytes.len() - stream.len(),
+            (MAX_SKIPPED_TUNNEL_MESSAGES + 1) * 6 + MAX_SKIPPED_TUNNEL_MESSAGES * 64
+        );
+    }
+
+    #[tokio::test]
+    async fn expected_message_rejects_oversized_tunnel_messages() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(MessageKind::UDPTunnel as u16).to_be_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+
+        let result = expected_message::<Authenticate, _>(MessageKind::Authenticate, &mut bytes.as_slice()).await;
+
+        assert!(matches!(result, Err(MumbleError::UnexpectedMessageKind(_))));
+    }
+
+    #[test]
+    fn recording_allowed_is_written_as_field_7() {
+        let mut server_config = ServerConfig::new();
+        server_config.set_max_users(64);
+        set_recording_allowed(&mut server_config, false);
+
+        let mut expected = Vec::new();
+
+        {
+            let mut stream = CodedOutputStream::vec(&mut expected);
+            stream.write_uint32(6, 64).unwrap();
+            stream.write_bool(7, false).unwrap();
+            stream.flush().unwrap();
+        }
+
+        assert_eq!(server_config.write_to_bytes().unwrap(), expected);
+    }
+}
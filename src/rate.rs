@@ -0,0 +1,78 @@
+//! Packet counter with a per second rate, the rate is updated each time the counter is sampled
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+#[derive(Default, Debug)]
+pub struct PacketRate {
+    total: AtomicU64,
+    /// Total at the previous sample
+    sampled_total: AtomicU64,
+    /// Packets per second between the two last samples, stored as f64 bits
+    rate: AtomicU64,
+}
+
+impl PacketRate {
+    pub fn inc(&self) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    pub fn rate(&self) -> f64 {
+        f64::from_bits(self.rate.load(Ordering::Relaxed))
+    }
+
+    /// Sample a counter kept elsewhere, a counter going down since the previous sample (e.g. reset) gives a zero rate
+    pub fn sample_total(&self, total: u64, elapsed: Duration) {
+        self.total.store(total, Ordering::Relaxed);
+        self.sample(elapsed);
+    }
+
+    /// Compute the rate from the packets counted since the previous sample, taken `elapsed` ago
+    pub fn sample(&self, elapsed: Duration) {
+        let total = self.total();
+        let previous = self.sampled_total.swap(total, Ordering::Relaxed);
+
+        if elapsed.is_zero() {
+            return;
+        }
+
+        let rate = total.saturating_sub(previous) as f64 / elapsed.as_secs_f64();
+
+        self.rate.store(rate.to_bits(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_is_computed_between_samples() {
+        let rate = PacketRate::default();
+
+        for _ in 0..100 {
+            rate.inc();
+        }
+
+        rate.sample(Duration::from_secs(2));
+        assert_eq!(rate.rate(), 50.0);
+
+        rate.inc();
+        rate.sample(Duration::from_millis(500));
+        assert_eq!(rate.rate(), 2.0);
+        assert_eq!(rate.total(), 101);
+
+        rate.sample(Duration::from_secs(5));
+        assert_eq!(rate.rate(), 0.0);
+
+        rate.sample_total(111, Duration::from_secs(1));
+        assert_eq!(rate.rate(), 10.0);
+
+        rate.sample_total(3, Duration::from_secs(1));
+        assert_eq!(rate.rate(), 0.0);
+    }
+}
@@ -0,0 +1,201 @@
+//! Channel recorder, write the raw opus stream of each speaker of a channel to ogg opus files
+
+use crate::opus;
+use crate::voice::{Clientbound, VoicePacket, VoicePacketPayload};
+use bytes::Bytes;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Mumble sequence numbers are incremented for each 10ms of audio, which is 480 samples at 48kHz
+const SAMPLES_PER_SEQ: u64 = 480;
+/// A segment without any packet for this duration is closed, in case the end of transmission was lost
+const SEGMENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Record the opus frames of every speaker in a channel, one file per transmission segment
+pub struct Recorder {
+    channel_id: u32,
+    sender: SyncSender<VoicePacket<Clientbound>>,
+}
+
+impl Recorder {
+    /// Create a recorder for the given channel, files are written in a dedicated thread to never block voice routing
+    pub fn create(channel_id: u32, directory: PathBuf) -> Result<Self, anyhow::Error> {
+        std::fs::create_dir_all(&directory)?;
+
+        let (sender, receiver) = mpsc::sync_channel(1024);
+
+        std::thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || record_loop(receiver, directory))?;
+
+        Ok(Self { channel_id, sender })
+    }
+
+    pub fn channel_id(&self) -> u32 {
+        self.channel_id
+    }
+
+    /// Push a voice packet to the recorder, packets are dropped if the writer is lagging behind
+    pub fn push(&self, packet: &VoicePacket<Clientbound>) {
+        if let Err(err) = self.sender.try_send(packet.clone()) {
+            tracing::trace!("recorder dropped voice packet: {}", err);
+        }
+    }
+}
+
+struct Segment {
+    writer: PacketWriter<BufWriter<File>>,
+    path: PathBuf,
+    serial: u32,
+    first_seq_num: u64,
+    last_granule: u64,
+    last_packet: Instant,
+}
+
+impl Segment {
+    fn create(directory: &Path, session_id: u32, seq_num: u64) -> Result<Self, std::io::Error> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let path = directory.join(format!("{}-{}.opus", session_id, timestamp));
+        let mut writer = PacketWriter::new(BufWriter::new(File::create(&path)?));
+        let serial = timestamp as u32 ^ session_id;
+
+        writer.write_packet(opus_head(), serial, PacketWriteEndInfo::EndPage, 0)?;
+        writer.write_packet(opus_tags(), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        Ok(Self {
+            writer,
+            path,
+            serial,
+            first_seq_num: seq_num,
+            last_granule: 0,
+            last_packet: Instant::now(),
+        })
+    }
+
+    fn write(&mut self, seq_num: u64, frame: &Bytes, end: bool) -> Result<(), std::io::Error> {
+        let start = seq_num.saturating_sub(self.first_seq_num) * SAMPLES_PER_SEQ;
+        let granule = (start + opus::packet_samples(frame)).max(self.last_granule);
+        let info = if end {
+            PacketWriteEndInfo::EndStream
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+
+        self.writer
+            .write_packet(frame.to_vec().into_boxed_slice(), self.serial, info, granule)?;
+        self.last_granule = granule;
+        self.last_packet = Instant::now();
+
+        Ok(())
+    }
+
+    fn close(mut self) {
+        // An empty packet with the end of stream flag terminates the logical stream properly
+        let result = self
+            .writer
+            .write_packet(Box::new([]), self.serial, PacketWriteEndInfo::EndStream, self.last_granule);
+
+        if let Err(err) = result {
+            tracing::error!("recorder cannot close {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+fn record_loop(receiver: Receiver<VoicePacket<Clientbound>>, directory: PathBuf) {
+    let mut segments: HashMap<u32, Segment> = HashMap::new();
+
+    loop {
+        let packet = match receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(packet) => Some(packet),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if let Some(VoicePacket::Audio {
+            session_id,
+            seq_num,
+            payload: VoicePacketPayload::Opus(frame, end),
+            ..
+        }) = packet
+        {
+            // Do not start a new segment with an empty terminator frame
+            if frame.is_empty() && !segments.contains_key(&session_id) {
+                continue;
+            }
+
+            let segment = match segments.entry(session_id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match Segment::create(&directory, session_id, seq_num) {
+                    Ok(segment) => {
+                        tracing::debug!("recorder start segment {}", segment.path.display());
+
+                        entry.insert(segment)
+                    }
+                    Err(err) => {
+                        tracing::error!("recorder cannot create segment for {}: {}", session_id, err);
+
+                        continue;
+                    }
+                },
+            };
+
+            if let Err(err) = segment.write(seq_num, &frame, end) {
+                tracing::error!("recorder cannot write to {}: {}", segment.path.display(), err);
+            }
+
+            // The end of transmission bit closes the current segment, next frame will start a new file
+            if end {
+                segments.remove(&session_id);
+            }
+        }
+
+        let expired = segments
+            .iter()
+            .filter(|(_, segment)| segment.last_packet.elapsed() > SEGMENT_TIMEOUT)
+            .map(|(session_id, _)| *session_id)
+            .collect::<Vec<u32>>();
+
+        for session_id in expired {
+            if let Some(segment) = segments.remove(&session_id) {
+                segment.close();
+            }
+        }
+    }
+
+    for (_, segment) in segments.drain() {
+        segment.close();
+    }
+}
+
+fn opus_head() -> Box<[u8]> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1); // version
+    head.push(1); // channel count
+    head.extend_from_slice(&0u16.to_le_bytes()); // pre-skip
+    head.extend_from_slice(&48000u32.to_le_bytes()); // input sample rate
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family
+
+    head.into_boxed_slice()
+}
+
+fn opus_tags() -> Box<[u8]> {
+    let vendor = concat!("zumble ", env!("CARGO_PKG_VERSION"));
+    let mut tags = Vec::new();
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor.as_bytes());
+    tags.extend_from_slice(&0u32.to_le_bytes()); // no user comment
+
+    tags.into_boxed_slice()
+}
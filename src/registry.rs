@@ -0,0 +1,114 @@
+//! Registered users with a stable id, recognized by their client certificate or a token
+//!
+//! Clients matching no registered user still connect anonymously.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RegisteredUser {
+    pub user_id: u32,
+    /// SHA-256 fingerprint of the client certificate, as colon separated hex bytes
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// Token sent by the client in its authenticate message
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Channel joined on connect instead of the server default channel
+    #[serde(default)]
+    pub default_channel: Option<u32>,
+}
+
+#[derive(Default, Debug)]
+pub struct Registry {
+    users: Vec<RegisteredUser>,
+}
+
+impl Registry {
+    pub fn new(users: Vec<RegisteredUser>) -> Result<Self, anyhow::Error> {
+        let mut user_ids = HashSet::new();
+
+        for user in &users {
+            if user.fingerprint.is_none() && user.token.is_none() {
+                return Err(anyhow::anyhow!(
+                    "registered user {} has neither a fingerprint nor a token",
+                    user.user_id
+                ));
+            }
+
+            if !user_ids.insert(user.user_id) {
+                return Err(anyhow::anyhow!("registered user id {} is used several times", user.user_id));
+            }
+        }
+
+        Ok(Self { users })
+    }
+
+    /// Load the registered users from a json array
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        Self::new(serde_json::from_reader(std::fs::File::open(path)?)?)
+    }
+
+    /// Find the user of a client, the certificate fingerprint is tried before the tokens
+    pub fn find(&self, fingerprint: Option<&str>, tokens: &[String]) -> Option<&RegisteredUser> {
+        if let Some(fingerprint) = fingerprint {
+            let user = self.users.iter().find(|user| match &user.fingerprint {
+                Some(registered) => registered.eq_ignore_ascii_case(fingerprint),
+                None => false,
+            });
+
+            if user.is_some() {
+                return user;
+            }
+        }
+
+        self.users
+            .iter()
+            .find(|user| matches!(&user.token, Some(token) if tokens.contains(token)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(user_id: u32, fingerprint: Option<&str>, token: Option<&str>) -> RegisteredUser {
+        RegisteredUser {
+            user_id,
+            fingerprint: fingerprint.map(str::to_string),
+            token: token.map(str::to_string),
+            default_channel: None,
+        }
+    }
+
+    #[test]
+    fn users_are_found_by_fingerprint_then_token() {
+        let registry = Registry::new(vec![
+            user(1, Some("AB:CD"), None),
+            user(2, None, Some("secret")),
+            user(3, Some("EF:01"), Some("other")),
+        ])
+        .unwrap();
+        let tokens = vec!["secret".to_string()];
+
+        assert_eq!(registry.find(Some("ab:cd"), &tokens).map(|user| user.user_id), Some(1));
+        assert_eq!(registry.find(Some("00:00"), &tokens).map(|user| user.user_id), Some(2));
+        assert_eq!(registry.find(None, &["other".to_string()]).map(|user| user.user_id), Some(3));
+        assert!(registry.find(Some("00:00"), &[]).is_none());
+    }
+
+    #[test]
+    fn invalid_users_are_rejected() {
+        assert!(Registry::new(vec![user(1, None, None)]).is_err());
+        assert!(Registry::new(vec![user(1, None, Some("a")), user(1, None, Some("b"))]).is_err());
+    }
+}
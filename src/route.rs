@@ -0,0 +1,170 @@
+//! Clients reached by the voice of a speaker, shared by the voice handler and the debug api so both route the same way
+
+use crate::channel::is_in_audio_range;
+use crate::client::Client;
+use crate::error::MumbleError;
+use crate::permission::is_admin;
+use crate::sync::RwLock;
+use crate::target::VOICE_TARGET_COUNT;
+use crate::ServerState;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Voice target of the channel of the speaker
+pub const CHANNEL_TARGET: u8 = 0;
+
+#[derive(Default)]
+pub struct Route {
+    /// Clients the voice is sent to, deaf clients and the speaker itself are skipped when sending
+    pub listeners: HashMap<u32, Arc<RwLock<Client>>>,
+    /// Channels the voice is routed to, recorded and monitored channels only get the voice routed to them
+    pub channels: HashSet<u32>,
+}
+
+/// Reason the voice of a speaker is dropped before being routed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoiceDropped {
+    /// The speaker is muted, by an admin or by itself
+    Muted,
+    /// Channel voice of a client in a listen only channel, admins can still speak in it
+    ListenOnly { channel_id: u32 },
+}
+
+/// Whether the voice of a speaker sent to a target is dropped, checked by the voice handler before routing it
+pub async fn get_voice_dropped(
+    state: &Arc<RwLock<ServerState>>,
+    client: &Arc<RwLock<Client>>,
+    target: u8,
+) -> Result<Option<VoiceDropped>, MumbleError> {
+    let (muted, channel_id, tokens) = {
+        let client_read = client.read_err().await?;
+
+        (
+            client_read.is_muted(),
+            client_read.channel_id.load(Ordering::Relaxed),
+            client_read.tokens.clone(),
+        )
+    };
+
+    if muted {
+        return Ok(Some(VoiceDropped::Muted));
+    }
+
+    if target != CHANNEL_TARGET {
+        return Ok(None);
+    }
+
+    let (channel, admin) = {
+        let state_read = state.read_err().await?;
+
+        (state_read.channels.get(&channel_id).cloned(), is_admin(&state_read, &tokens))
+    };
+
+    let speak_allowed = match channel {
+        Some(channel) => channel.read_err().await?.speak_allowed,
+        None => true,
+    };
+
+    if !speak_allowed && !admin {
+        return Ok(Some(VoiceDropped::ListenOnly { channel_id }));
+    }
+
+    Ok(None)
+}
+
+/// Route the voice of a speaker sent to a target, `None` if the target is neither the channel nor a whisper target
+///
+/// `position` is the one of the speaker, used for the audio range of its channel.
+pub async fn route_voice(
+    state: &Arc<RwLock<ServerState>>,
+    client: &Arc<RwLock<Client>>,
+    target: u8,
+    position: Option<[f32; 3]>,
+) -> Result<Option<Route>, MumbleError> {
+    let mut route = Route::default();
+
+    match target {
+        CHANNEL_TARGET => {
+            let channel_id = { client.read_err().await?.channel_id.load(Ordering::Relaxed) };
+            let channel_result = { state.read_err().await?.channels.get(&channel_id).cloned() };
+
+            if let Some(channel) = channel_result {
+                let (listeners, audio_range) = {
+                    let channel_read = channel.read_err().await?;
+
+                    (channel_read.get_listeners(state.clone()).await, channel_read.audio_range)
+                };
+
+                for (listener_id, listener) in listeners {
+                    if let (Some(range), Some(position)) = (audio_range, position) {
+                        let listener_read = listener.read_err().await?;
+
+                        // Clients listening to the channel from elsewhere hear it whatever the distance
+                        if listener_read.channel_id.load(Ordering::Relaxed) == channel_id
+                            && !is_in_audio_range(range, position, listener_read.get_position())
+                        {
+                            continue;
+                        }
+                    }
+
+                    route.listeners.insert(listener_id, listener);
+                }
+
+                route.channels.insert(channel_id);
+
+                let linked_channels = { state.read_err().await?.get_linked_channels(channel_id).await? };
+
+                // Linked channels hear the speaker whatever the audio range, like listeners
+                for (linked_id, linked_channel) in linked_channels {
+                    {
+                        route
+                            .listeners
+                            .extend(linked_channel.read_err().await?.get_listeners(state.clone()).await);
+                    }
+
+                    route.channels.insert(linked_id);
+                }
+            }
+        }
+        // Voice target (whisper)
+        1..=VOICE_TARGET_COUNT => {
+            let target = { client.read_err().await?.get_target((target - 1) as usize) };
+
+            if let Some(target) = target {
+                let target = target.read_err().await?;
+
+                for client_id in &target.sessions {
+                    let client_result = { state.read_err().await?.clients.get(client_id).cloned() };
+
+                    if let Some(client) = client_result {
+                        route.listeners.insert(*client_id, client);
+                    }
+                }
+
+                for channel_id in &target.channels {
+                    let channel_result = { state.read_err().await?.channels.get(channel_id).cloned() };
+
+                    if let Some(channel) = channel_result {
+                        {
+                            route.listeners.extend(channel.read_err().await?.get_listeners(state.clone()).await);
+                        }
+
+                        route.channels.insert(*channel_id);
+                    }
+                }
+            }
+        }
+        _ => return Ok(None),
+    }
+
+    let session_id = { client.read_err().await?.session_id };
+
+    {
+        route
+            .listeners
+            .extend(state.read_err().await?.get_user_listeners(session_id).await?);
+    }
+
+    Ok(Some(route))
+}
@@ -1,5 +1,7 @@
+mod socket;
 mod tcp;
 mod udp;
 
+pub use socket::{bind_tcp, bind_udp};
 pub use tcp::create_tcp_server;
 pub use udp::create_udp_server;
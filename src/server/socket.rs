@@ -0,0 +1,39 @@
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Create a socket for the address, ipv6 sockets also accept ipv4 peers as mapped addresses (`[::ffff:a.b.c.d]`)
+///
+/// Dual stack is the default on linux but not on windows, so it is always set explicitly.
+fn create_socket(address: &SocketAddr, socket_type: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(*address), socket_type, Some(protocol))?;
+
+    if address.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+
+    socket.set_nonblocking(true)?;
+
+    Ok(socket)
+}
+
+pub fn bind_tcp(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = create_socket(&address, Type::STREAM, Protocol::TCP)?;
+
+    // Same as tokio, allow restarting while previous connections are in time wait
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    socket.bind(&SockAddr::from(address))?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+pub fn bind_udp(address: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = create_socket(&address, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&SockAddr::from(address))?;
+
+    UdpSocket::from_std(socket.into())
+}
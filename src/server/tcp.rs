@@ -1,19 +1,22 @@
 use crate::client::Client;
+use crate::error::MumbleError;
 use crate::handler::MessageHandler;
-use crate::message::ClientMessage;
-use crate::proto::mumble::Version;
-use crate::proto::MessageKind;
+use crate::message::{self, Consumer, DisconnectReason};
+use crate::proto::mumble::{Reject, Reject_RejectType, Version};
+use crate::proto::{send_message, MessageKind};
 use crate::sync::RwLock;
 use crate::ServerState;
 use actix_server::Server;
 use actix_service::fn_service;
 use anyhow::Context;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::io;
 use tokio::io::ReadHalf;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
 use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
 pub fn create_tcp_server(
@@ -51,63 +54,184 @@ pub fn create_tcp_server(
         .run()
 }
 
-async fn handle_new_client(acceptor: TlsAcceptor,
-                     server_version: Version,
-                     state: Arc<RwLock<ServerState>>, stream: TcpStream) -> Result<(), anyhow::Error> {
+async fn handle_new_client(
+    acceptor: TlsAcceptor,
+    server_version: Version,
+    state: Arc<RwLock<ServerState>>,
+    stream: TcpStream,
+) -> Result<(), anyhow::Error> {
     stream.set_nodelay(true).context("set stream no delay")?;
+    let addr = stream.peer_addr().context("get peer address")?;
+
+    let allowed = {
+        state
+            .read_err()
+            .await
+            .context("check connection limit")?
+            .connection_limiter
+            .allow(addr.ip(), Instant::now())
+    };
+
+    // Closed before the tls handshake, so a reconnect storm does not cost a handshake for each attempt
+    if !allowed {
+        crate::metrics::CONNECTIONS_RATE_LIMITED_TOTAL.inc();
+
+        tracing::debug!("client from {} rejected: too many connection attempts", addr);
+
+        return Ok(());
+    }
+
+    let handshake_timeout = { state.read_err().await.context("get handshake timeout")?.handshake_timeout };
+
+    let mut stream = match timeout(handshake_timeout, acceptor.accept(stream)).await {
+        Ok(stream) => stream.context("accept tls")?,
+        Err(_) => {
+            tracing::info!("client from {} dropped: no tls handshake within {:?}", addr, handshake_timeout);
+
+            return Ok(());
+        }
+    };
+
+    let maintenance = {
+        state
+            .read_err()
+            .await
+            .context("check maintenance")?
+            .maintenance
+            .load(Ordering::Relaxed)
+    };
+
+    if maintenance {
+        let mut reject = Reject::new();
+        reject.set_field_type(Reject_RejectType::None);
+        reject.set_reason("maintenance".to_string());
+
+        send_message(MessageKind::Reject, &reject, &mut stream)
+            .await
+            .context("send maintenance reject")?;
+
+        tracing::info!("client from {} rejected: server is in maintenance", addr);
 
-    let mut stream = acceptor.accept(stream).await.context("accept tls")?;
-    let (version, authenticate, crypt_state) = Client::init(&mut stream, server_version).await.context("init client")?;
+        return Ok(());
+    }
+
+    let (version, authenticate, crypt_state, registered) = match Client::init(&mut stream, server_version, &state).await {
+        Ok(init) => init,
+        Err(MumbleError::Timeout) => {
+            tracing::info!(
+                "client from {} dropped: no version and authenticate within {:?}",
+                addr,
+                handshake_timeout
+            );
+
+            return Ok(());
+        }
+        Err(e) => return Err(e).context("init client"),
+    };
 
     let (read, write) = io::split(stream);
-    let (tx, rx) = mpsc::channel(128);
+    let queue_capacity = { state.read_err().await.context("get client queue capacity")?.client_queue_capacity };
+    let (tx, rx) = message::channel(queue_capacity);
 
     let username = authenticate.get_username().to_string();
     let client = {
-        state.write_err().await.context("add client to server")?.add_client(
-            version,
-            authenticate,
-            crypt_state,
-            write,
-            tx,
-        )
+        state
+            .write_err()
+            .await
+            .context("add client to server")?
+            .add_client(version, authenticate, crypt_state, write, addr, tx, registered)
     };
 
     crate::metrics::CLIENTS_TOTAL.inc();
 
-    tracing::info!("new client {} connected", username);
+    tracing::info!("new client {} connected from {}", username, addr);
+
+    let (rx, result) = client_run(read, rx, state.clone(), client.clone()).await;
 
-    match client_run(read, rx, state.clone(), client.clone()).await {
+    let reason = DisconnectReason::from_result(&result);
+
+    match result {
         Ok(_) => (),
         Err(e) => tracing::error!("client {} error: {:?}", username, e),
     }
 
-    tracing::info!("client {} disconnected", username);
+    crate::metrics::DISCONNECTS_TOTAL.with_label_values(&[reason.as_str()]).inc();
+
+    tracing::info!(reason = reason.as_str(), "client {} disconnected", username);
 
     let (client_id, channel_id) = {
-        state.write_err().await.context("wait state for disconnect user")?.disconnect(client).await.context("disconnect user")?
+        state
+            .write_err()
+            .await
+            .context("wait state for disconnect user")?
+            .disconnect(client)
+            .await
+            .context("disconnect user")?
     };
 
     crate::metrics::CLIENTS_TOTAL.dec();
 
-    {
+    // The client is removed from the state, queued messages will never be handled
+    let dropped = rx.map(|mut rx| rx.drain()).unwrap_or_default();
+
+    if dropped > 0 {
+        tracing::warn!("client {} disconnected with {} queued messages dropped", username, dropped);
+    }
+
+    let leave_channel_id = {
         state
             .read_err()
             .await
             .context("wait state for remove client")?
             .remove_client(client_id, channel_id)
-            .await.context("remove client")?;
+            .await
+            .context("remove client")?
+    };
+
+    if let Some(leave_channel_id) = leave_channel_id {
+        state
+            .write_err()
+            .await
+            .context("wait state for remove channel")?
+            .remove_channel(leave_channel_id);
     }
 
     Ok(())
 }
 
+/// Run a connected client until it disconnects, returns its message queue unless the send task panicked
+///
+/// Queued messages are handled by a send task of their own, so a slow write to the client does not stop reading from it.
 pub async fn client_run(
-    mut read: ReadHalf<TlsStream<TcpStream>>,
-    mut receiver: Receiver<ClientMessage>,
+    read: ReadHalf<TlsStream<TcpStream>>,
+    receiver: Consumer,
     state: Arc<RwLock<ServerState>>,
     client: Arc<RwLock<Client>>,
-) -> Result<(), anyhow::Error> {
+) -> (Option<Consumer>, Result<(), anyhow::Error>) {
+    if let Err(e) = client_init(state.clone(), client.clone()).await {
+        return (Some(receiver), Err(e));
+    }
+
+    let (stop, stopped) = oneshot::channel();
+    let mut send_task = actix_rt::spawn(client_send_run(receiver, stopped, state.clone(), client.clone()));
+
+    let (read_result, send_output) = tokio::select! {
+        read_result = client_read_run(read, state, client) => {
+            let _ = stop.send(());
+
+            (read_result, (&mut send_task).await)
+        }
+        send_output = &mut send_task => (Ok(()), send_output),
+    };
+
+    match send_output {
+        Ok((receiver, send_result)) => (Some(receiver), read_result.and(send_result)),
+        Err(e) => (None, Err(e).context("send task")),
+    }
+}
+
+/// Send the server state to a new client and run the init steps
+async fn client_init(state: Arc<RwLock<ServerState>>, client: Arc<RwLock<Client>>) -> Result<(), anyhow::Error> {
     let codec_version = { state.read_err().await?.check_codec().await? };
 
     if let Some(codec_version) = codec_version {
@@ -129,8 +253,28 @@ pub async fn client_run(
             e
         })?;
         client_sync.send_my_user_state().await?;
-        client_sync.send_server_sync().await?;
-        client_sync.send_server_config().await?;
+        let (max_bandwidth, max_clients, recording_allowed, suggest_config, context_actions) = {
+            let state_read = state.read_err().await?;
+
+            (
+                state_read.max_bandwidth,
+                state_read.max_clients,
+                state_read.recording_allowed,
+                state_read.suggest_config.clone(),
+                state_read.context_actions.clone(),
+            )
+        };
+
+        client_sync.send_server_sync(max_bandwidth).await?;
+        client_sync
+            .send_server_config(max_bandwidth, max_clients, recording_allowed)
+            .await?;
+
+        if let Some(suggest_config) = suggest_config {
+            client_sync.send_suggest_config(&suggest_config).await?;
+        }
+
+        client_sync.send_context_actions(&context_actions).await?;
     }
 
     let user_state = { client.read_err().await?.get_user_state() };
@@ -142,8 +286,24 @@ pub async fn client_run(
         }
     }
 
+    let init_steps = { state.read_err().await?.init_steps.clone() };
+
+    for init_step in &init_steps {
+        if let Err(e) = init_step.run(&state, &client).await {
+            tracing::error!("failed to run init step {:?}: {:?}", init_step, e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn client_read_run(
+    mut read: ReadHalf<TlsStream<TcpStream>>,
+    state: Arc<RwLock<ServerState>>,
+    client: Arc<RwLock<Client>>,
+) -> Result<(), anyhow::Error> {
     loop {
-        match MessageHandler::handle(&mut read, &mut receiver, state.clone(), client.clone()).await {
+        match MessageHandler::handle(&mut read, state.clone(), client.clone()).await {
             Ok(_) => (),
             Err(e) => {
                 if e.is::<io::Error>() {
@@ -162,3 +322,29 @@ pub async fn client_run(
         }
     }
 }
+
+/// Handle the queued messages of a client until it is stopped, a message fails or the client is disconnected
+///
+/// The queue is returned so the messages left in it can be counted once the client is removed.
+async fn client_send_run(
+    mut receiver: Consumer,
+    mut stopped: oneshot::Receiver<()>,
+    state: Arc<RwLock<ServerState>>,
+    client: Arc<RwLock<Client>>,
+) -> (Consumer, Result<(), anyhow::Error>) {
+    loop {
+        let message = tokio::select! {
+            _ = &mut stopped => return (receiver, Ok(())),
+            message = receiver.recv() => message,
+        };
+
+        let message = match message {
+            Some(message) => message,
+            None => return (receiver, Ok(())),
+        };
+
+        if let Err(e) = MessageHandler::handle_queued(message, &mut receiver, state.clone(), client.clone()).await {
+            return (receiver, Err(e));
+        }
+    }
+}
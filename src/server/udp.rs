@@ -6,27 +6,96 @@ use crate::ServerState;
 use anyhow::Context;
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use bytes::BytesMut;
-use std::io::Cursor;
+use std::io::{self, Cursor};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::UdpSocket;
 
-pub async fn create_udp_server(protocol_version: u32, socket: Arc<UdpSocket>, state: Arc<RwLock<ServerState>>) {
+/// Backoff after a transient receive error, doubled on each consecutive error
+const MIN_RECV_ERROR_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_RECV_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Server figures of the anonymous ping reply, read without locking the state
+struct PingInfo {
+    users: Arc<AtomicUsize>,
+    /// 0 when the number of clients is unlimited
+    max_users: u32,
+    max_bandwidth: u32,
+}
+
+/// Reply to an anonymous ping: version, echoed timestamp, users, max users and max bandwidth per client
+fn ping_reply(protocol_version: u32, timestamp: u64, users: u32, max_users: u32, max_bandwidth: u32) -> Result<Vec<u8>, io::Error> {
+    let mut reply = Cursor::new(vec![0u8; 24]);
+    reply.write_u32::<byteorder::BigEndian>(protocol_version)?;
+    reply.write_u64::<byteorder::LittleEndian>(timestamp)?;
+    reply.write_u32::<byteorder::BigEndian>(users)?;
+    reply.write_u32::<byteorder::BigEndian>(max_users)?;
+    reply.write_u32::<byteorder::BigEndian>(max_bandwidth)?;
+
+    Ok(reply.into_inner())
+}
+
+/// Receive udp packets until the socket fails with an error that retrying cannot fix
+pub async fn create_udp_server(protocol_version: u32, socket: Arc<UdpSocket>, state: Arc<RwLock<ServerState>>) -> Result<(), io::Error> {
+    let mut backoff = MIN_RECV_ERROR_BACKOFF;
+
+    // The limits are set before the servers start, only the number of users changes
+    let ping_info = {
+        let state_read = state.read_err().await.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        Arc::new(PingInfo {
+            users: state_read.client_count.clone(),
+            max_users: state_read.max_clients,
+            max_bandwidth: state_read.max_bandwidth,
+        })
+    };
+
     loop {
-        match udp_server_run(protocol_version, socket.clone(), state.clone()).await {
-            Ok(_) => (),
-            Err(e) => tracing::error!("udp server error: {:?}", e),
+        match udp_server_run(protocol_version, socket.clone(), state.clone(), ping_info.clone()).await {
+            Ok(_) => backoff = MIN_RECV_ERROR_BACKOFF,
+            Err(e) if is_transient_recv_error(&e) => {
+                crate::metrics::UDP_RECV_ERRORS_TOTAL.with_label_values(&["transient"]).inc();
+
+                tracing::warn!("udp receive error, retrying in {:?}: {}", backoff, e);
+
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_RECV_ERROR_BACKOFF);
+            }
+            Err(e) => {
+                crate::metrics::UDP_RECV_ERRORS_TOTAL.with_label_values(&["fatal"]).inc();
+
+                return Err(e);
+            }
         }
     }
 }
 
-async fn udp_server_run(protocol_version: u32, socket: Arc<UdpSocket>, state: Arc<RwLock<ServerState>>) -> Result<(), anyhow::Error> {
+/// Errors of a single receive, e.g. an icmp port unreachable reported by some platforms, the socket is still usable
+fn is_transient_recv_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::TimedOut
+    )
+}
+
+async fn udp_server_run(
+    protocol_version: u32,
+    socket: Arc<UdpSocket>,
+    state: Arc<RwLock<ServerState>>,
+    ping_info: Arc<PingInfo>,
+) -> Result<(), io::Error> {
     let mut buffer = BytesMut::zeroed(1024);
     let (size, addr) = socket.recv_from(&mut buffer).await?;
     buffer.resize(size, 0);
 
     tokio::spawn(async move {
-        match handle_packet(buffer, size, addr, protocol_version, socket, state).await {
+        match handle_packet(buffer, size, addr, protocol_version, socket, state, &ping_info).await {
             Ok(_) => (),
             Err(e) => tracing::error!("udp server handle packet error: {:?}", e),
         }
@@ -35,21 +104,25 @@ async fn udp_server_run(protocol_version: u32, socket: Arc<UdpSocket>, state: Ar
     Ok(())
 }
 
-async fn handle_packet(mut buffer: BytesMut, size: usize, addr: SocketAddr, protocol_version: u32, socket: Arc<UdpSocket>, state: Arc<RwLock<ServerState>>) -> Result<(), anyhow::Error> {
+async fn handle_packet(
+    mut buffer: BytesMut,
+    size: usize,
+    addr: SocketAddr,
+    protocol_version: u32,
+    socket: Arc<UdpSocket>,
+    state: Arc<RwLock<ServerState>>,
+    ping_info: &PingInfo,
+) -> Result<(), anyhow::Error> {
     let mut cursor = Cursor::new(&buffer[..size]);
     let kind = cursor.read_u32::<byteorder::BigEndian>()?;
 
     if size == 12 && kind == 0 {
         let timestamp = cursor.read_u64::<byteorder::LittleEndian>()?;
 
-        let mut send = Cursor::new(vec![0u8; 24]);
-        send.write_u32::<byteorder::BigEndian>(protocol_version)?;
-        send.write_u64::<byteorder::LittleEndian>(timestamp)?;
-        send.write_u32::<byteorder::BigEndian>(0)?;
-        send.write_u32::<byteorder::BigEndian>(250)?;
-        send.write_u32::<byteorder::BigEndian>(72000)?;
+        let users = ping_info.users.load(Ordering::Relaxed) as u32;
+        let reply = ping_reply(protocol_version, timestamp, users, ping_info.max_users, ping_info.max_bandwidth)?;
 
-        socket.send_to(send.get_ref().as_slice(), addr).await?;
+        socket.send_to(&reply, addr).await?;
 
         crate::metrics::MESSAGES_TOTAL
             .with_label_values(&["udp", "input", "PingAnonymous"])
@@ -80,7 +153,13 @@ async fn handle_packet(mut buffer: BytesMut, size: usize, addr: SocketAddr, prot
             };
 
             match decrypt_result {
-                Ok(p) => (client, p),
+                Ok(p) => {
+                    {
+                        client.read_err().await?.reset_crypt_mac_failures();
+                    }
+
+                    (client, p)
+                }
                 Err(err) => {
                     let username = { client.read_err().await?.authenticate.get_username().to_string() };
                     tracing::warn!("client {} decrypt error: {}", username, err);
@@ -93,26 +172,59 @@ async fn handle_packet(mut buffer: BytesMut, size: usize, addr: SocketAddr, prot
                         .with_label_values(&["udp", "input", "VoicePacket"])
                         .inc_by(size as u64);
 
+                    let (late, can_reset, mac_failures_sustained) = {
+                        let client_read = client.read_err().await?;
+                        let mac_failures_sustained = matches!(err, DecryptError::Mac) && client_read.crypt_mac_failure();
+                        let crypt_state = client_read.crypt_state.read_err().await?;
+
+                        (crypt_state.late, crypt_state.can_reset(), mac_failures_sustained)
+                    };
+
                     let restart_crypt = match err {
                         DecryptError::Late => {
-                            let late = { client.read_err().await?.crypt_state.read_err().await?.late };
+                            crate::metrics::CRYPT_LATE_TOTAL.inc();
 
                             late > 100
                         }
-                        DecryptError::Repeat => false,
+                        DecryptError::Repeat => {
+                            crate::metrics::CRYPT_REPEAT_TOTAL.inc();
+
+                            false
+                        }
+                        DecryptError::Mac => {
+                            crate::metrics::CRYPT_MAC_FAIL_TOTAL.inc();
+
+                            // A single corrupted or spoofed datagram must not tear down a working udp session
+                            mac_failures_sustained
+                        }
                         _ => true,
                     };
 
+                    if restart_crypt && !can_reset {
+                        tracing::debug!(
+                            "client {} udp decrypt error: {}, crypt setup was reset recently, skip",
+                            username,
+                            err
+                        );
+
+                        return Ok(());
+                    }
+
                     if restart_crypt {
                         tracing::error!("client {} udp decrypt error: {}, reset crypt setup", username, err);
 
-                        let send_crypt_setup = { client.read_err().await?.send_crypt_setup(true).await };
+                        let send_crypt_setup = {
+                            let client_read = client.read_err().await?;
+                            client_read.reset_crypt_mac_failures();
+
+                            client_read.send_crypt_setup(true).await
+                        };
 
                         if let Err(e) = send_crypt_setup {
                             tracing::error!("failed to send crypt setup: {:?}", e);
                         }
 
-                        let client_address = { client.read_err().await?.udp_socket_addr.clone() };
+                        let client_address = { client.read_err().await?.udp_socket_addr };
 
                         // Remove socket address from client
                         if let Some(address) = client_address {
@@ -121,8 +233,8 @@ async fn handle_packet(mut buffer: BytesMut, size: usize, addr: SocketAddr, prot
                                     .write_err()
                                     .await
                                     .context("remove client by socket")?
-                                    .remove_client_by_socket(&address)
-                            };
+                                    .remove_client_by_socket(&address);
+                            }
 
                             {
                                 client.write_err().await.context("set udp socket to null")?.udp_socket_addr = None;
@@ -135,7 +247,7 @@ async fn handle_packet(mut buffer: BytesMut, size: usize, addr: SocketAddr, prot
             }
         }
         None => {
-            let (client_opt, packet_opt, address_to_remove) = { state.read_err().await?.find_client_for_packet(&mut buffer).await? };
+            let (client_opt, packet_opt, address_to_remove) = { state.read_err().await?.find_client_for_packet(&mut buffer, addr).await? };
 
             for address in address_to_remove {
                 {
@@ -143,8 +255,8 @@ async fn handle_packet(mut buffer: BytesMut, size: usize, addr: SocketAddr, prot
                         .write_err()
                         .await
                         .context("remove client by socket when searching for one")?
-                        .remove_client_by_socket(&address)
-                };
+                        .remove_client_by_socket(&address);
+                }
             }
 
             match (client_opt, packet_opt) {
@@ -228,6 +340,16 @@ async fn handle_packet(mut buffer: BytesMut, size: usize, addr: SocketAddr, prot
                 }
             }
         }
+        // Nothing to route, and a valid packet so the crypt state is fine
+        _ if client_packet.is_keepalive() => {
+            crate::metrics::MESSAGES_TOTAL
+                .with_label_values(&["udp", "input", "VoiceKeepalive"])
+                .inc();
+
+            crate::metrics::MESSAGES_BYTES
+                .with_label_values(&["udp", "input", "VoiceKeepalive"])
+                .inc_by(size as u64);
+        }
         _ => {
             crate::metrics::MESSAGES_TOTAL
                 .with_label_values(&["udp", "input", "VoicePacket"])
@@ -238,11 +360,10 @@ async fn handle_packet(mut buffer: BytesMut, size: usize, addr: SocketAddr, prot
                 .inc_by(size as u64);
 
             let send_client_packet = {
-                client
-                    .read_err()
-                    .await?
-                    .publisher
-                    .try_send(ClientMessage::RouteVoicePacket(client_packet))
+                let client_read = client.read_err().await?;
+                client_read.voice_packets_in.inc();
+
+                client_read.publisher.try_send(ClientMessage::RouteVoicePacket(client_packet))
             };
 
             match send_client_packet {
@@ -256,3 +377,21 @@ async fn handle_packet(mut buffer: BytesMut, size: usize, addr: SocketAddr, prot
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_reply_carries_the_server_figures() {
+        let reply = ping_reply(0x010400, 0x0102030405060708, 3, 64, 144000).unwrap();
+        let mut cursor = Cursor::new(&reply);
+
+        assert_eq!(reply.len(), 24);
+        assert_eq!(cursor.read_u32::<byteorder::BigEndian>().unwrap(), 0x010400);
+        assert_eq!(cursor.read_u64::<byteorder::LittleEndian>().unwrap(), 0x0102030405060708);
+        assert_eq!(cursor.read_u32::<byteorder::BigEndian>().unwrap(), 3);
+        assert_eq!(cursor.read_u32::<byteorder::BigEndian>().unwrap(), 64);
+        assert_eq!(cursor.read_u32::<byteorder::BigEndian>().unwrap(), 144000);
+    }
+}
@@ -1,22 +1,34 @@
+use crate::ban::BanList;
 use crate::channel::Channel;
 use crate::client::Client;
+use crate::connection_limit::ConnectionLimiter;
+use crate::context_action::ContextActionConfig;
 use crate::crypt::CryptState;
 use crate::error::MumbleError;
-use crate::message::ClientMessage;
-use crate::proto::mumble::{Authenticate, ChannelRemove, ChannelState, CodecVersion, UserRemove, Version};
+use crate::message::{ClientMessage, DisconnectReason, Publisher};
+use crate::permission::{can_enter, PERM_ENTER};
+use crate::proto::mumble::{
+    Authenticate, ChannelRemove, ChannelState, CodecVersion, PermissionDenied_DenyType, SuggestConfig, UserRemove, Version,
+};
 use crate::proto::{message_to_bytes, MessageKind};
+use crate::recorder::Recorder;
+use crate::registry::{RegisteredUser, Registry};
 use crate::sync::RwLock;
-use crate::voice::{Serverbound, VoicePacket};
+use crate::tls::TlsVersion;
+use crate::voice::{Clientbound, Serverbound, VoicePacket, VoicePacketPayload};
+use crate::webhook::{self, Webhook, WebhookEvent};
+use crate::welcome::InitStep;
 use bytes::BytesMut;
 use protobuf::Message;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::marker::PhantomData;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::sync::atomic::Ordering;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::WriteHalf;
 use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio_rustls::server::TlsStream;
 
 pub struct CodecState {
@@ -57,16 +69,164 @@ impl CodecState {
     }
 }
 
+/// Allocate ids from a monotonic counter, freed ids are reused first, lowest one first
+#[derive(Debug)]
+struct IdAllocator {
+    next: u32,
+    free: BTreeSet<u32>,
+}
+
+impl IdAllocator {
+    fn new(first: u32) -> Self {
+        Self {
+            next: first,
+            free: BTreeSet::new(),
+        }
+    }
+
+    fn allocate(&mut self) -> u32 {
+        if let Some(id) = self.free.pop_first() {
+            return id;
+        }
+
+        let id = self.next;
+        self.next += 1;
+
+        id
+    }
+
+    fn release(&mut self, id: u32) {
+        if id >= self.next {
+            return;
+        }
+
+        // Give back the tail of the range to the counter, so the free list only holds gaps
+        if id == self.next - 1 {
+            self.next = id;
+
+            while self.next > 0 && self.free.remove(&(self.next - 1)) {
+                self.next -= 1;
+            }
+        } else {
+            self.free.insert(id);
+        }
+    }
+}
+
+/// Startup configuration of the server, only reported by the http api
+pub struct ServerInfo {
+    /// Mumble protocol version announced to clients
+    pub protocol_version: u32,
+    pub listen: Option<SocketAddr>,
+    pub http_listen: String,
+    pub https: bool,
+    pub tls_min_version: TlsVersion,
+    pub started_at: SystemTime,
+}
+
+/// Session id of the voice sent by the server itself, never given to a client
+pub const SERVER_SESSION_ID: u32 = 0;
+
+pub const DEFAULT_MAX_BANDWIDTH: u32 = 144000;
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+pub const DEFAULT_CRYPT_REKEY_INTERVAL: Duration = Duration::from_secs(3600);
+pub const DEFAULT_CLIENT_QUEUE_CAPACITY: usize = 128;
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Three little endian floats for the position, with some slack for plugins sending more
+pub const DEFAULT_MAX_POSITION_INFO_SIZE: usize = 32;
+pub const DEFAULT_MAX_CHANNEL_DEPTH: usize = 8;
+pub const DEFAULT_MAX_CHANNELS_PER_CLIENT: usize = 4;
+
 pub struct ServerState {
     pub clients: HashMap<u32, Arc<RwLock<Client>>>,
     pub clients_by_socket: HashMap<SocketAddr, Arc<RwLock<Client>>>,
+    /// Number of connected clients, shared with the udp server so anonymous pings are answered without locking the state
+    pub client_count: Arc<AtomicUsize>,
     pub channels: HashMap<u32, Arc<RwLock<Channel>>>,
     pub codec_state: RwLock<CodecState>,
     pub socket: Arc<UdpSocket>,
+    pub bans: BanList,
+    /// Registered users recognized on connect, every client is anonymous without it
+    pub registry: Option<Registry>,
+    #[cfg(feature = "monitor")]
+    pub monitor: Option<Arc<crate::monitor::Monitor>>,
+    pub recorder: Option<Arc<Recorder>>,
+    #[cfg(feature = "transcode")]
+    pub transcoder: Option<Arc<crate::transcoder::Transcoder>>,
+    /// Channel where new clients are put, resolved from `default_channel_name` when the channel is created, root channel until then
+    pub default_channel: u32,
+    /// Name of the channel where new clients are put
+    pub default_channel_name: Option<String>,
+    pub context_actions: Vec<ContextActionConfig>,
+    pub write_timeout: Duration,
+    /// Max duration of the tls handshake, and of the mumble handshake up to the crypt setup
+    pub handshake_timeout: Duration,
+    /// Age of a client crypt key after which a new one is generated, zero disables rekeying
+    pub crypt_rekey_interval: Duration,
+    pub voice_batching: bool,
+    /// Positional data is only routed between clients sharing the same plugin context
+    pub positional_context: bool,
+    /// Positional data longer than this is stripped from voice packets before they are routed
+    pub max_position_info_size: usize,
+    /// Number of messages that can wait in the queue of a client before new ones are dropped
+    pub client_queue_capacity: usize,
+    /// Voice can be sent and received over tcp when udp is not available
+    pub tcp_tunnel: bool,
+    /// Max number of connected clients, new clients are rejected once it is reached, 0 means unlimited
+    pub max_clients: u32,
+    /// Max number of channels between a channel created by a client and the root channel, 0 means unlimited
+    pub max_channel_depth: usize,
+    /// Max number of temporary channels created by a client existing at the same time, 0 means unlimited
+    pub max_channels_per_client: usize,
+    /// Max number of channels on the server, the root channel included, 0 means unlimited
+    pub max_channels: usize,
+    /// Max number of temporary channels on the server, 0 means unlimited
+    pub max_temporary_channels: usize,
+    /// Connection attempts of each ip, checked before the tls handshake
+    pub connection_limiter: ConnectionLimiter,
+    /// Max bandwidth per client in bits per second, sent on sync
+    pub max_bandwidth: u32,
+    /// Whether clients may record the voice themselves, only reported to them in the server config
+    pub recording_allowed: bool,
+    /// Recommended client settings sent on sync, if any
+    pub suggest_config: Option<SuggestConfig>,
+    pub webhook: Option<Arc<Webhook>>,
+    /// Steps run in order for each client once it is synced
+    pub init_steps: Vec<InitStep>,
+    /// Access tokens allowing to enter, speak and listen in each existing restricted channel, by channel id
+    pub restricted_channels: HashMap<u32, HashSet<String>>,
+    /// Access tokens of restricted channels given by name, applied to `restricted_channels` when the channel is created
+    pub restricted_channel_tokens: HashMap<String, HashSet<String>>,
+    /// Clients presenting one of these access tokens get the admin permissions
+    pub admin_tokens: HashSet<String>,
+    /// Audio range of channels given by name, applied when the channel is created
+    pub channel_ranges: HashMap<String, f32>,
+    /// Max number of clients of channels given by name, applied when the channel is created
+    pub channel_capacities: HashMap<String, usize>,
+    /// Listen only channels given by name, applied when the channel is created
+    pub listen_only_channels: HashSet<String>,
+    /// Channels given by name whose clients are moved out once idle for `idle_timeout`, applied when the channel is created
+    pub idle_channels: HashSet<String>,
+    /// Time without audio after which a client of an idle channel is moved, zero disables it
+    pub idle_timeout: Duration,
+    /// Channel where idle clients are moved, resolved from `idle_move_channel_name` when the channel is created, the default channel is used until then
+    pub idle_move_channel: Option<u32>,
+    /// Name of the channel where idle clients are moved
+    pub idle_move_channel_name: Option<String>,
+    /// Server mute and deaf of users by name, applied again when they reconnect
+    pub moderations: HashMap<String, (bool, bool)>,
+    /// Channel of users by name imported from another instance, used once on their next connection
+    pub channel_placements: HashMap<String, u32>,
+    pub info: ServerInfo,
+    pub maintenance: AtomicBool,
+    /// Set once the tcp and udp listeners are bound, and unset on shutdown
+    pub ready: AtomicBool,
+    session_ids: IdAllocator,
+    channel_ids: IdAllocator,
 }
 
 impl ServerState {
-    pub fn new(socket: Arc<UdpSocket>) -> Self {
+    pub fn new(socket: Arc<UdpSocket>, bans: BanList) -> Self {
         let mut channels = HashMap::new();
         channels.insert(
             0,
@@ -81,54 +241,396 @@ impl ServerState {
 
         Self {
             clients: HashMap::new(),
+            client_count: Arc::new(AtomicUsize::new(0)),
             clients_by_socket: HashMap::new(),
             channels,
             codec_state: RwLock::new(CodecState::default()),
             socket,
+            bans,
+            registry: None,
+            #[cfg(feature = "monitor")]
+            monitor: None,
+            recorder: None,
+            #[cfg(feature = "transcode")]
+            transcoder: None,
+            default_channel: 0,
+            default_channel_name: None,
+            context_actions: Vec::new(),
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            crypt_rekey_interval: DEFAULT_CRYPT_REKEY_INTERVAL,
+            voice_batching: false,
+            positional_context: false,
+            max_position_info_size: DEFAULT_MAX_POSITION_INFO_SIZE,
+            client_queue_capacity: DEFAULT_CLIENT_QUEUE_CAPACITY,
+            tcp_tunnel: true,
+            max_clients: 0,
+            max_channel_depth: DEFAULT_MAX_CHANNEL_DEPTH,
+            max_channels_per_client: DEFAULT_MAX_CHANNELS_PER_CLIENT,
+            max_channels: 0,
+            max_temporary_channels: 0,
+            connection_limiter: ConnectionLimiter::default(),
+            max_bandwidth: DEFAULT_MAX_BANDWIDTH,
+            recording_allowed: true,
+            suggest_config: None,
+            webhook: None,
+            init_steps: Vec::new(),
+            restricted_channels: HashMap::new(),
+            restricted_channel_tokens: HashMap::new(),
+            admin_tokens: HashSet::new(),
+            channel_ranges: HashMap::new(),
+            channel_capacities: HashMap::new(),
+            listen_only_channels: HashSet::new(),
+            idle_channels: HashSet::new(),
+            idle_timeout: Duration::ZERO,
+            idle_move_channel: None,
+            idle_move_channel_name: None,
+            moderations: HashMap::new(),
+            channel_placements: HashMap::new(),
+            info: ServerInfo {
+                protocol_version: 0,
+                listen: None,
+                http_listen: String::new(),
+                https: false,
+                tls_min_version: TlsVersion::Tls12,
+                started_at: SystemTime::now(),
+            },
+            maintenance: AtomicBool::new(false),
+            ready: AtomicBool::new(false),
+            session_ids: IdAllocator::new(1),
+            // Root channel is always 0
+            channel_ids: IdAllocator::new(1),
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn add_client(
         &mut self,
         version: Version,
         authenticate: Authenticate,
         crypt_state: CryptState,
         write: WriteHalf<TlsStream<TcpStream>>,
-        publisher: Sender<ClientMessage>,
+        tcp_socket_addr: SocketAddr,
+        publisher: Publisher,
+        registered: Option<RegisteredUser>,
     ) -> Arc<RwLock<Client>> {
-        let session_id = self.get_free_session_id();
+        let session_id = self.session_ids.allocate();
+
+        if let Some(webhook) = &self.webhook {
+            webhook.send(WebhookEvent::Connect {
+                username: authenticate.get_username().to_string(),
+                session_id,
+                timestamp: webhook::now(),
+            });
+        }
+
+        let default_channel = self
+            .channel_placements
+            .remove(authenticate.get_username())
+            .or_else(|| registered.as_ref().and_then(|user| user.default_channel))
+            .unwrap_or(self.default_channel);
 
-        let client = Arc::new(RwLock::new(Client::new(
+        let channel_id = if self.channels.contains_key(&default_channel) {
+            default_channel
+        } else {
+            tracing::warn!("default channel {} does not exist, using root channel", default_channel);
+
+            0
+        };
+
+        let moderation = self.moderations.get(authenticate.get_username()).copied();
+
+        let mut client = Client::new(
             version,
             authenticate,
             session_id,
-            0,
+            channel_id,
             crypt_state,
             write,
+            tcp_socket_addr,
             self.socket.clone(),
             publisher,
-        )));
+            self.write_timeout,
+            self.voice_batching,
+            self.tcp_tunnel,
+        );
+
+        client.user_id = registered.map(|user| user.user_id);
+
+        if let Some((mute, deaf)) = moderation {
+            client.server_mute(mute);
+            client.server_deaf(deaf);
+        }
+
+        let client = Arc::new(RwLock::new(client));
 
         self.clients.insert(session_id, client.clone());
+        self.client_count.fetch_add(1, Ordering::Relaxed);
 
         client
     }
 
+    pub fn set_moderation_mute(&mut self, username: &str, mute: bool) {
+        self.update_moderation(username, |moderation| moderation.0 = mute);
+    }
+
+    pub fn set_moderation_deaf(&mut self, username: &str, deaf: bool) {
+        self.update_moderation(username, |moderation| moderation.1 = deaf);
+    }
+
+    /// Users neither muted nor deafened are forgotten
+    fn update_moderation(&mut self, username: &str, update: impl FnOnce(&mut (bool, bool))) {
+        let mut moderation = self.moderations.get(username).copied().unwrap_or_default();
+        update(&mut moderation);
+
+        if moderation == (false, false) {
+            self.moderations.remove(username);
+        } else {
+            self.moderations.insert(username.to_string(), moderation);
+        }
+    }
+
     pub fn add_channel(&mut self, state: &ChannelState) -> Arc<RwLock<Channel>> {
-        let channel_id = self.get_free_channel_id();
-        let channel = Arc::new(RwLock::new(Channel::new(
+        let channel_id = self.channel_ids.allocate();
+        let mut channel = Channel::new(
             channel_id,
             Some(state.get_parent()),
             state.get_name().to_string(),
             state.get_description().to_string(),
             state.get_temporary(),
-        )));
+        );
+        self.configure_channel(&mut channel);
+
+        let channel = Arc::new(RwLock::new(channel));
 
         self.channels.insert(channel_id, channel.clone());
 
         channel
     }
 
+    /// Apply the configuration given for the name of a channel
+    ///
+    /// The configuration follows names rather than ids, ids are reused once a temporary channel is removed.
+    pub fn configure_channel(&mut self, channel: &mut Channel) {
+        channel.audio_range = self.channel_ranges.get(&channel.name).copied();
+        channel.max_occupancy = self.channel_capacities.get(&channel.name).copied();
+        channel.speak_allowed = !self.listen_only_channels.contains(&channel.name);
+        channel.idle = self.idle_channels.contains(&channel.name);
+
+        if self.default_channel_name.as_ref() == Some(&channel.name) {
+            self.default_channel = channel.id;
+        }
+
+        if self.idle_move_channel_name.as_ref() == Some(&channel.name) {
+            self.idle_move_channel = Some(channel.id);
+        }
+
+        match self.restricted_channel_tokens.get(&channel.name) {
+            Some(tokens) => self.restricted_channels.insert(channel.id, tokens.clone()),
+            None => self.restricted_channels.remove(&channel.id),
+        };
+    }
+
+    /// Remove a channel and free its id, the root channel is never removed
+    pub fn remove_channel(&mut self, channel_id: u32) -> Option<Arc<RwLock<Channel>>> {
+        if channel_id == 0 {
+            return None;
+        }
+
+        let channel = self.channels.remove(&channel_id)?;
+        self.channel_ids.release(channel_id);
+        self.restricted_channels.remove(&channel_id);
+
+        if self.default_channel == channel_id {
+            self.default_channel = 0;
+        }
+
+        if self.idle_move_channel == Some(channel_id) {
+            self.idle_move_channel = None;
+        }
+
+        Some(channel)
+    }
+
+    /// Channels reachable from the given one through links, excluding it
+    ///
+    /// Links are followed transitively, each channel is visited once so linked channels forming a cycle are fine.
+    /// A channel id reused after a removal starts without links, the stale side left on its old peers is ignored
+    pub async fn get_linked_channels(&self, channel_id: u32) -> Result<Vec<(u32, Arc<RwLock<Channel>>)>, MumbleError> {
+        let mut visited = HashSet::from([channel_id]);
+        let mut pending = vec![channel_id];
+        let mut linked = Vec::new();
+
+        while let Some(current_id) = pending.pop() {
+            let links = match self.channels.get(&current_id) {
+                Some(channel) => channel.read_err().await?.links.clone(),
+                None => continue,
+            };
+
+            for link_id in links {
+                if visited.contains(&link_id) {
+                    continue;
+                }
+
+                let link = match self.channels.get(&link_id) {
+                    Some(link) => link,
+                    None => continue,
+                };
+
+                if !link.read_err().await?.links.contains(&current_id) {
+                    continue;
+                }
+
+                visited.insert(link_id);
+                pending.push(link_id);
+                linked.push((link_id, link.clone()));
+            }
+        }
+
+        Ok(linked)
+    }
+
+    /// Number of parents between a channel and the root channel, the root channel has a depth of 0
+    pub async fn get_channel_depth(&self, channel_id: u32) -> Result<usize, MumbleError> {
+        let mut depth = 0;
+        let mut current_id = channel_id;
+
+        // The walk is bounded by the number of channels so a broken parent chain cannot loop forever
+        while current_id != 0 && depth <= self.channels.len() {
+            let parent_id = match self.channels.get(&current_id) {
+                Some(channel) => channel.read_err().await?.parent_id,
+                None => break,
+            };
+
+            depth += 1;
+
+            match parent_id {
+                Some(parent_id) => current_id = parent_id,
+                None => break,
+            }
+        }
+
+        Ok(depth)
+    }
+
+    /// Whether a channel has reached its max occupancy, a channel without one is never full
+    pub async fn is_channel_full(&self, channel_id: u32) -> Result<bool, MumbleError> {
+        let max_occupancy = match self.channels.get(&channel_id) {
+            Some(channel) => channel.read_err().await?.max_occupancy,
+            None => None,
+        };
+
+        let max_occupancy = match max_occupancy {
+            Some(max_occupancy) => max_occupancy,
+            None => return Ok(false),
+        };
+
+        let mut occupancy = 0;
+
+        for client in self.clients.values() {
+            if client.read_err().await?.channel_id.load(Ordering::Relaxed) == channel_id {
+                occupancy += 1;
+            }
+        }
+
+        Ok(occupancy >= max_occupancy)
+    }
+
+    /// Whether a new channel would exceed the server channel limits
+    pub async fn is_channel_limit_reached(&self, temporary: bool) -> Result<bool, MumbleError> {
+        if self.max_channels > 0 && self.channels.len() >= self.max_channels {
+            return Ok(true);
+        }
+
+        if !temporary || self.max_temporary_channels == 0 {
+            return Ok(false);
+        }
+
+        let mut temporary_channels = 0;
+
+        for channel in self.channels.values() {
+            if channel.read_err().await?.temporary {
+                temporary_channels += 1;
+            }
+        }
+
+        Ok(temporary_channels >= self.max_temporary_channels)
+    }
+
+    /// Number of existing temporary channels created by a client
+    pub async fn count_channels_created_by(&self, session_id: u32) -> Result<usize, MumbleError> {
+        let mut count = 0;
+
+        for channel in self.channels.values() {
+            let channel_read = channel.read_err().await?;
+
+            if channel_read.temporary && channel_read.creator == Some(session_id) {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Send server generated audio to the clients in a channel, its listeners and linked channels, returns the number of clients it was queued for
+    ///
+    /// An injected stream is numbered by the caller: `seq_num` starts at 0 for each new stream and grows by the number of 10 ms frames
+    /// of each packet, and the last packet carries the opus end of transmission bit so clients close the stream. The voice is sent as
+    /// [`SERVER_SESSION_ID`], clients playing only the voice of sessions they know may need a user with that session to play it.
+    pub async fn send_audio_to_channel(&self, channel_id: u32, seq_num: u64, payload: VoicePacketPayload) -> Result<usize, MumbleError> {
+        let channel = self
+            .channels
+            .get(&channel_id)
+            .ok_or_else(|| MumbleError::NotFound(format!("channel {}", channel_id)))?;
+
+        let mut listening_clients = { channel.read_err().await?.get_listeners_in(self).await };
+
+        for (_, linked_channel) in self.get_linked_channels(channel_id).await? {
+            listening_clients.extend(linked_channel.read_err().await?.get_listeners_in(self).await);
+        }
+
+        let packet = server_audio_packet(0, seq_num, payload);
+        let mut sent = 0;
+
+        for client in listening_clients.values() {
+            if queue_server_audio(client, packet.clone()).await? {
+                sent += 1;
+            }
+        }
+
+        Ok(sent)
+    }
+
+    /// Send server generated audio to a single client as a whisper, returns whether it was queued
+    ///
+    /// Streams are numbered like in [`ServerState::send_audio_to_channel`].
+    pub async fn send_audio_to_session(&self, session_id: u32, seq_num: u64, payload: VoicePacketPayload) -> Result<bool, MumbleError> {
+        let client = self
+            .clients
+            .get(&session_id)
+            .ok_or_else(|| MumbleError::NotFound(format!("session {}", session_id)))?;
+
+        // Target 2 tells the client the voice is whispered to it
+        queue_server_audio(client, server_audio_packet(2, seq_num, payload)).await
+    }
+
+    /// Clients listening to the given session, whatever the channel it speaks in
+    pub async fn get_user_listeners(&self, session_id: u32) -> Result<HashMap<u32, Arc<RwLock<Client>>>, MumbleError> {
+        let mut listening_clients = HashMap::new();
+
+        for client in self.clients.values() {
+            {
+                let client_read = client.read_err().await?;
+
+                if client_read.listened_users.contains(&session_id) {
+                    listening_clients.insert(client_read.session_id, client.clone());
+                }
+            }
+        }
+
+        Ok(listening_clients)
+    }
+
     pub async fn get_client_by_name(&self, name: &str) -> Result<Option<Arc<RwLock<Client>>>, MumbleError> {
         for client in self.clients.values() {
             {
@@ -156,7 +658,26 @@ impl ServerState {
             client.write_err().await?.udp_socket_addr = Some(addr);
         }
 
-        self.clients_by_socket.insert(addr, client);
+        if let Some(displaced) = self.clients_by_socket.insert(addr, client.clone()) {
+            if !Arc::ptr_eq(&displaced, &client) {
+                crate::metrics::UDP_SOCKET_COLLISIONS_TOTAL.inc();
+
+                let username = { client.read_err().await?.authenticate.get_username().to_string() };
+                let mut displaced_write = displaced.write_err().await?;
+
+                tracing::warn!(
+                    "client {} claims the udp address {} of client {}, the latter falls back to the tcp tunnel",
+                    username,
+                    addr,
+                    displaced_write.authenticate.get_username()
+                );
+
+                // The address now belongs to another client, the displaced one gets voice over tcp until it sends udp again
+                if displaced_write.udp_socket_addr == Some(addr) {
+                    displaced_write.udp_socket_addr = None;
+                }
+            }
+        }
 
         Ok(())
     }
@@ -166,23 +687,57 @@ impl ServerState {
 
         let bytes = message_to_bytes(kind, message)?;
 
+        // Only hold each client lock long enough to clone its publisher, sending is done without any client lock
+        let mut publishers = Vec::with_capacity(self.clients.len());
+
+        for client in self.clients.values() {
+            match client.read_err().await {
+                Ok(client_read) => publishers.push((client_read.authenticate.get_username().to_string(), client_read.publisher.clone())),
+                Err(err) => tracing::error!("failed to lock client for broadcast: {}", err),
+            }
+        }
+
+        // Broadcasts are best effort, a full or closed queue of one client must not deprive the others of the message
+        for (username, publisher) in publishers {
+            if let Err(err) = publisher.try_send(ClientMessage::SendMessage {
+                kind,
+                payload: bytes.clone(),
+            }) {
+                let reason = match err {
+                    TrySendError::Full(_) => "full",
+                    TrySendError::Closed(_) => "closed",
+                };
+
+                crate::metrics::BROADCAST_SEND_ERRORS_TOTAL.with_label_values(&[reason]).inc();
+
+                tracing::error!("failed to send message to {}: {}", username, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn disconnect_all(&self) -> Result<usize, MumbleError> {
+        let mut count = 0;
+
         for client in self.clients.values() {
             {
                 let client_read = client.read_err().await?;
 
-                match client_read.publisher.try_send(ClientMessage::SendMessage {
-                    kind,
-                    payload: bytes.clone(),
-                }) {
-                    Ok(_) => {}
+                match client_read.publisher.try_send(ClientMessage::Disconnect(DisconnectReason::Force)) {
+                    Ok(_) => count += 1,
                     Err(err) => {
-                        tracing::error!("failed to send message to {}: {}", client_read.authenticate.get_username(), err);
+                        tracing::error!(
+                            "error sending disconnect signal to {}: {}",
+                            client_read.authenticate.get_username(),
+                            err
+                        );
                     }
                 }
             }
         }
 
-        Ok(())
+        Ok(count)
     }
 
     async fn check_leave_channel(&self, leave_channel_id: u32) -> Result<Option<u32>, MumbleError> {
@@ -239,6 +794,58 @@ impl ServerState {
         Ok(Some(leave_channel_id))
     }
 
+    /// Remove the temporary channels without any member, child channel or listener, and notify the clients
+    ///
+    /// Leaving a channel already removes it when it becomes empty, this catches the channels missed by that path.
+    /// Channels younger than `min_age` are kept, as their creator joins them right after creating them.
+    pub async fn remove_empty_temporary_channels(&mut self, min_age: Duration) -> Result<Vec<u32>, MumbleError> {
+        let mut removed = Vec::new();
+
+        // Removing a channel can empty its parent, so repeat until nothing changes
+        loop {
+            let mut used = HashSet::new();
+
+            for client in self.clients.values() {
+                used.insert(client.read_err().await?.channel_id.load(Ordering::Relaxed));
+            }
+
+            let mut empty = Vec::new();
+
+            for channel in self.channels.values() {
+                let channel = channel.read_err().await?;
+
+                if let Some(parent_id) = channel.parent_id {
+                    used.insert(parent_id);
+                }
+
+                if channel.temporary && channel.listeners.is_empty() && channel.created_at.elapsed() >= min_age {
+                    empty.push(channel.id);
+                }
+            }
+
+            empty.retain(|channel_id| !used.contains(channel_id));
+
+            if empty.is_empty() {
+                return Ok(removed);
+            }
+
+            for channel_id in empty {
+                if self.remove_channel(channel_id).is_none() {
+                    continue;
+                }
+
+                let mut channel_remove = ChannelRemove::new();
+                channel_remove.set_channel_id(channel_id);
+
+                if let Err(e) = self.broadcast_message(MessageKind::ChannelRemove, &channel_remove).await {
+                    tracing::error!("failed to send channel remove: {:?}", e);
+                }
+
+                removed.push(channel_id);
+            }
+        }
+    }
+
     pub async fn set_client_channel(&self, client: Arc<RwLock<Client>>, channel_id: u32) -> Result<Option<u32>, MumbleError> {
         let leave_channel_id = { client.read_err().await?.join_channel(channel_id) };
 
@@ -251,12 +858,46 @@ impl ServerState {
                 Err(e) => tracing::error!("failed to send user state: {:?}", e),
             }
 
-            return Ok(self.check_leave_channel(leave_channel_id).await?);
+            return self.check_leave_channel(leave_channel_id).await;
         }
 
         Ok(None)
     }
 
+    /// Move a client into a channel it asked to join, the client is told why when it cannot enter the channel or the channel is full
+    ///
+    /// Moves done by the server use `set_client_channel`, they can fill a channel past its occupancy. A denied client is not moved,
+    /// the mumble client keeps showing it in its current channel.
+    pub async fn join_client_channel(&self, client: Arc<RwLock<Client>>, channel_id: u32) -> Result<Option<u32>, MumbleError> {
+        let (tokens, current_channel_id) = {
+            let client_read = client.read_err().await?;
+
+            (client_read.tokens.clone(), client_read.channel_id.load(Ordering::Relaxed))
+        };
+
+        if !can_enter(self, &tokens, channel_id) {
+            client
+                .read_err()
+                .await?
+                .send_channel_permission_denied(PERM_ENTER, channel_id)
+                .await?;
+
+            return Ok(None);
+        }
+
+        if channel_id != current_channel_id && self.is_channel_full(channel_id).await? {
+            client
+                .read_err()
+                .await?
+                .send_permission_denied(PermissionDenied_DenyType::ChannelFull, "Channel is full")
+                .await?;
+
+            return Ok(None);
+        }
+
+        self.set_client_channel(client, channel_id).await
+    }
+
     pub async fn get_channel_by_name(&self, name: &str) -> Result<Option<Arc<RwLock<Channel>>>, MumbleError> {
         for channel in self.channels.values() {
             {
@@ -271,9 +912,8 @@ impl ServerState {
         Ok(None)
     }
 
-    pub async fn check_codec(&self) -> Result<Option<CodecVersion>, MumbleError> {
-        let current_version = { self.codec_state.read_err().await?.get_version() };
-        let mut new_version = current_version;
+    /// Number of connected clients supporting each celt version
+    pub async fn get_codec_votes(&self) -> Result<HashMap<i32, usize>, MumbleError> {
         let mut versions = HashMap::new();
 
         for client in self.clients.values() {
@@ -286,9 +926,15 @@ impl ServerState {
             }
         }
 
+        Ok(versions)
+    }
+
+    pub async fn check_codec(&self) -> Result<Option<CodecVersion>, MumbleError> {
+        let current_version = { self.codec_state.read_err().await?.get_version() };
+        let mut new_version = current_version;
         let mut max = 0;
 
-        for (version, count) in versions {
+        for (version, count) in self.get_codec_votes().await? {
             if count > max {
                 new_version = version;
                 max = count;
@@ -322,11 +968,27 @@ impl ServerState {
         Ok(None)
     }
 
-    pub fn get_client_by_socket(&self, socket_addr: &SocketAddr) -> Option<Arc<RwLock<Client>>> {
-        match self.clients_by_socket.get(socket_addr) {
-            Some(client) => Some(client.clone()),
-            None => None,
+    /// Forget the udp address of a client, voice is then sent through the tcp tunnel until the client sends udp again
+    pub async fn disable_client_udp(&mut self, client: Arc<RwLock<Client>>) -> Result<(), MumbleError> {
+        let mut client_write = client.write_err().await?;
+
+        if let Some(address) = client_write.udp_socket_addr.take() {
+            tracing::warn!(
+                "client {} udp sends to {} keep failing, falling back to tcp tunnel",
+                client_write.authenticate.get_username(),
+                address
+            );
+
+            self.clients_by_socket.remove(&address);
         }
+
+        client_write.udp_send_failures.store(0, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn get_client_by_socket(&self, socket_addr: &SocketAddr) -> Option<Arc<RwLock<Client>>> {
+        self.clients_by_socket.get(socket_addr).cloned()
     }
 
     pub fn remove_client_by_socket(&mut self, socket_addr: &SocketAddr) {
@@ -336,6 +998,7 @@ impl ServerState {
     pub async fn find_client_for_packet(
         &self,
         bytes: &mut BytesMut,
+        addr: SocketAddr,
     ) -> Result<(Option<Arc<RwLock<Client>>>, Option<VoicePacket<Serverbound>>, Vec<SocketAddr>), MumbleError> {
         let mut address_to_remove = Vec::new();
 
@@ -346,27 +1009,49 @@ impl ServerState {
 
             match decrypt_result {
                 Ok(p) => {
+                    // client changed its udp address (nat rebinding, network change), drop the stale mapping
+                    let (username, previous_address) = {
+                        let client_read = c.read_err().await?;
+
+                        (client_read.authenticate.get_username().to_string(), client_read.udp_socket_addr)
+                    };
+
+                    if let Some(previous_address) = previous_address {
+                        if previous_address != addr {
+                            tracing::info!("client {} changed udp address from {} to {}", username, previous_address, addr);
+
+                            address_to_remove.push(previous_address);
+                        }
+                    }
+
                     return Ok((Some(c.clone()), Some(p), address_to_remove));
                 }
                 Err(err) => {
-                    let duration = { Instant::now().duration_since(crypt_state.read_err().await?.last_good).as_millis() };
+                    let (duration, can_reset) = {
+                        let crypt_state_read = crypt_state.read_err().await?;
+
+                        (
+                            Instant::now().duration_since(crypt_state_read.last_good).as_millis(),
+                            crypt_state_read.can_reset(),
+                        )
+                    };
 
                     // last good packet was more than 5sec ago, reset
-                    if duration > 5000 {
+                    if duration > 5000 && can_reset {
                         let send_crypt_setup = { c.read_err().await?.send_crypt_setup(true).await };
 
                         if let Err(e) = send_crypt_setup {
                             tracing::error!("failed to send crypt setup: {:?}", e);
                         }
 
-                        let address_option = { c.read_err().await?.udp_socket_addr.clone() };
+                        let address_option = { c.read_err().await?.udp_socket_addr };
 
                         if let Some(address) = address_option {
                             address_to_remove.push(address);
 
                             {
-                                c.write_err().await?.udp_socket_addr = None
-                            };
+                                c.write_err().await?.udp_socket_addr = None;
+                            }
                         }
                     }
 
@@ -379,11 +1064,48 @@ impl ServerState {
     }
 
     pub async fn disconnect(&mut self, client: Arc<RwLock<Client>>) -> Result<(u32, u32), MumbleError> {
-        let client_id = { client.read_err().await?.session_id };
+        let (client_id, username) = {
+            let client_read = client.read_err().await?;
+
+            (client_read.session_id, client_read.authenticate.get_username().to_string())
+        };
 
-        self.clients.remove(&client_id);
+        // Clean loop and tcp handler can both disconnect the same client, only notify once
+        if self.clients.remove(&client_id).is_some() {
+            self.client_count.fetch_sub(1, Ordering::Relaxed);
+            self.session_ids.release(client_id);
+            client.read_err().await?.set_positional(false);
+
+            // The session id can be reused by the next client, which must not inherit the listeners
+            for other in self.clients.values() {
+                match other.write_err().await {
+                    Ok(mut other_write) => {
+                        other_write.listened_users.remove(&client_id);
+                    }
+                    Err(err) => tracing::error!("failed to remove listened user {}: {}", client_id, err),
+                }
+            }
+
+            for channel in self.channels.values() {
+                match channel.write_err().await {
+                    Ok(mut channel_write) => {
+                        channel_write.listeners.remove(&client_id);
+                        channel_write.listener_volumes.remove(&client_id);
+                    }
+                    Err(err) => tracing::error!("failed to remove channel listener {}: {}", client_id, err),
+                }
+            }
+
+            if let Some(webhook) = &self.webhook {
+                webhook.send(WebhookEvent::Disconnect {
+                    username,
+                    session_id: client_id,
+                    timestamp: webhook::now(),
+                });
+            }
+        }
 
-        let socket_addr = { client.read_err().await?.udp_socket_addr.clone() };
+        let socket_addr = { client.read_err().await?.udp_socket_addr };
 
         if let Some(socket_addr) = socket_addr {
             self.clients_by_socket.remove(&socket_addr);
@@ -394,43 +1116,322 @@ impl ServerState {
         Ok((client_id, channel_id))
     }
 
-    pub async fn remove_client(&self, client_id: u32, channel_id: u32) -> Result<(), MumbleError> {
+    /// Notify the removal of a client, returns the channel it left if that channel must be removed
+    pub async fn remove_client(&self, client_id: u32, channel_id: u32) -> Result<Option<u32>, MumbleError> {
         let mut remove = UserRemove::new();
         remove.set_session(client_id);
         remove.set_reason("disconnected".to_string());
 
         self.broadcast_message(MessageKind::UserRemove, &remove).await?;
 
-        self.check_leave_channel(channel_id).await?;
+        // The session id is reused by a later client, which must not inherit the channels of this one
+        for channel in self.channels.values() {
+            let mut channel_write = channel.write_err().await?;
 
-        Ok(())
+            if channel_write.creator == Some(client_id) {
+                channel_write.creator = None;
+            }
+        }
+
+        self.check_leave_channel(channel_id).await
     }
+}
 
-    fn get_free_session_id(&self) -> u32 {
-        let mut session_id = 1;
+fn server_audio_packet(target: u8, seq_num: u64, payload: VoicePacketPayload) -> VoicePacket<Clientbound> {
+    VoicePacket::Audio {
+        _dst: PhantomData,
+        target,
+        session_id: SERVER_SESSION_ID,
+        seq_num,
+        payload,
+        position_info: None,
+    }
+}
 
-        loop {
-            if self.clients.contains_key(&session_id) {
-                session_id += 1;
-            } else {
-                break;
+/// Queue server audio for a client through its publisher like routed voice, deaf clients are skipped
+async fn queue_server_audio(client: &Arc<RwLock<Client>>, packet: VoicePacket<Clientbound>) -> Result<bool, MumbleError> {
+    let client_read = client.read_err().await?;
+
+    if client_read.is_deaf() {
+        return Ok(false);
+    }
+
+    match client_read.publisher.try_send(ClientMessage::SendVoicePacket(packet)) {
+        Ok(_) => Ok(true),
+        Err(err) => {
+            tracing::error!("error sending server audio to {}: {}", client_read.authenticate.get_username(), err);
+
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn create_state() -> ServerState {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        ServerState::new(Arc::new(socket), BanList::load(None).unwrap())
+    }
+
+    fn temporary_channel() -> ChannelState {
+        let mut channel_state = ChannelState::new();
+        channel_state.set_parent(0);
+        channel_state.set_name("temporary".to_string());
+        channel_state.set_temporary(true);
+
+        channel_state
+    }
+
+    #[tokio::test]
+    async fn moderations_forget_users_neither_muted_nor_deafened() {
+        let mut state = create_state().await;
+
+        state.set_moderation_mute("player", true);
+        state.set_moderation_deaf("player", true);
+        assert_eq!(state.moderations.get("player"), Some(&(true, true)));
+
+        state.set_moderation_mute("player", false);
+        assert_eq!(state.moderations.get("player"), Some(&(false, true)));
+
+        state.set_moderation_deaf("player", false);
+        assert!(state.moderations.is_empty());
+
+        state.set_moderation_mute("other", false);
+        assert!(state.moderations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn channel_depth_follows_the_parents() {
+        let mut state = create_state().await;
+        let mut parent_id = 0;
+
+        assert_eq!(state.get_channel_depth(0).await.unwrap(), 0);
+
+        for depth in 1..=3 {
+            let mut channel_state = temporary_channel();
+            channel_state.set_parent(parent_id);
+            parent_id = state.add_channel(&channel_state).read_err().await.unwrap().id;
+
+            assert_eq!(state.get_channel_depth(parent_id).await.unwrap(), depth);
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_limits_count_all_and_temporary_channels() {
+        let mut state = create_state().await;
+        state.max_channels = 3;
+        state.max_temporary_channels = 1;
+
+        assert!(!state.is_channel_limit_reached(true).await.unwrap());
+
+        state.add_channel(&temporary_channel());
+
+        // The temporary limit does not apply to permanent channels
+        assert!(state.is_channel_limit_reached(true).await.unwrap());
+        assert!(!state.is_channel_limit_reached(false).await.unwrap());
+
+        let mut permanent_state = temporary_channel();
+        permanent_state.set_temporary(false);
+        state.add_channel(&permanent_state);
+
+        assert!(state.is_channel_limit_reached(false).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn channel_configuration_follows_the_name_not_the_reused_id() {
+        let mut state = create_state().await;
+        state.channel_ranges.insert("hospital".to_string(), 15.0);
+        state
+            .restricted_channel_tokens
+            .insert("hospital".to_string(), HashSet::from(["medic".to_string()]));
+
+        let mut channel_state = temporary_channel();
+        channel_state.set_name("hospital".to_string());
+        let hospital_id = state.add_channel(&channel_state).read_err().await.unwrap().id;
+
+        assert!(state.restricted_channels.contains_key(&hospital_id));
+
+        state.remove_channel(hospital_id);
+
+        let channel = state.add_channel(&temporary_channel());
+        let channel_read = channel.read_err().await.unwrap();
+
+        assert_eq!(channel_read.id, hospital_id);
+        assert_eq!(channel_read.audio_range, None);
+        assert!(!state.restricted_channels.contains_key(&hospital_id));
+    }
+
+    #[tokio::test]
+    async fn default_and_idle_move_channels_follow_their_names() {
+        let mut state = create_state().await;
+        state.default_channel_name = Some("lobby".to_string());
+        state.idle_move_channel_name = Some("afk".to_string());
+
+        state.add_channel(&temporary_channel());
+        assert_eq!(state.default_channel, 0);
+        assert_eq!(state.idle_move_channel, None);
+
+        let mut channel_state = temporary_channel();
+        channel_state.set_name("lobby".to_string());
+        let lobby_id = state.add_channel(&channel_state).read_err().await.unwrap().id;
+        channel_state.set_name("afk".to_string());
+        let afk_id = state.add_channel(&channel_state).read_err().await.unwrap().id;
+
+        assert_eq!(state.default_channel, lobby_id);
+        assert_eq!(state.idle_move_channel, Some(afk_id));
+
+        // Removed channels are not replaced by a channel reusing their id
+        state.remove_channel(lobby_id);
+        state.remove_channel(afk_id);
+        state.add_channel(&temporary_channel());
+
+        assert_eq!(state.default_channel, 0);
+        assert_eq!(state.idle_move_channel, None);
+    }
+
+    #[test]
+    fn id_allocator_reuses_lowest_free_id() {
+        let mut ids = IdAllocator::new(1);
+
+        assert_eq!((1..=4).map(|_| ids.allocate()).collect::<Vec<u32>>(), vec![1, 2, 3, 4]);
+
+        ids.release(3);
+        ids.release(2);
+
+        assert_eq!(ids.allocate(), 2);
+        assert_eq!(ids.allocate(), 3);
+        assert_eq!(ids.allocate(), 5);
+
+        // Releasing the tail rewinds the counter over the freed gaps
+        ids.release(2);
+        ids.release(3);
+        ids.release(4);
+        ids.release(5);
+
+        assert!(ids.free.is_empty());
+        assert_eq!(ids.allocate(), 2);
+    }
+
+    #[tokio::test]
+    async fn channel_ids_do_not_collide_or_leak() {
+        let mut state = create_state().await;
+        let mut live = HashSet::new();
+        let mut peak = 0;
+
+        for round in 0..100u32 {
+            for _ in 0..20 {
+                let channel_id = { state.add_channel(&temporary_channel()).read_err().await.unwrap().id };
+
+                assert_ne!(channel_id, 0);
+                assert!(live.insert(channel_id), "channel id {} allocated twice", channel_id);
             }
+
+            // Freed ids are reused, so ids never exceed the highest number of channels alive at once
+            peak = peak.max(live.len() as u32);
+            assert!(live.iter().all(|id| *id <= peak));
+
+            // Remove a different subset of channels each round to create gaps
+            let removed = live.iter().copied().filter(|id| (id + round) % 3 != 0).collect::<Vec<u32>>();
+
+            for channel_id in removed {
+                assert!(state.remove_channel(channel_id).is_some());
+                live.remove(&channel_id);
+            }
+
+            assert_eq!(state.channels.len(), live.len() + 1);
         }
 
-        session_id
+        for channel_id in live.drain() {
+            assert!(state.remove_channel(channel_id).is_some());
+        }
+
+        assert!(state.remove_channel(0).is_none());
+        assert_eq!(state.channels.len(), 1);
+        assert_eq!(state.add_channel(&temporary_channel()).read_err().await.unwrap().id, 1);
     }
 
-    fn get_free_channel_id(&self) -> u32 {
-        let mut channel_id = 1;
+    #[tokio::test]
+    async fn empty_temporary_channels_are_reaped() {
+        let mut state = create_state().await;
+
+        // Channels left behind without their members, as after an abnormal disconnect
+        let parent_id = { state.add_channel(&temporary_channel()).read_err().await.unwrap().id };
+        let mut child_state = temporary_channel();
+        child_state.set_parent(parent_id);
+        let child_id = { state.add_channel(&child_state).read_err().await.unwrap().id };
+        let listened_id = { state.add_channel(&temporary_channel()).read_err().await.unwrap().id };
+        let permanent_id = {
+            let mut permanent_state = temporary_channel();
+            permanent_state.set_temporary(false);
+
+            state.add_channel(&permanent_state).read_err().await.unwrap().id
+        };
+
+        state.channels[&listened_id].write_err().await.unwrap().listeners.insert(42);
 
-        loop {
-            if self.channels.contains_key(&channel_id) {
-                channel_id += 1;
-            } else {
-                break;
+        let mut removed = state.remove_empty_temporary_channels(Duration::ZERO).await.unwrap();
+        removed.sort_unstable();
+
+        // The parent is only removed once its child is gone
+        assert_eq!(removed, vec![parent_id, child_id]);
+        assert!(state.channels.contains_key(&listened_id));
+        assert!(state.channels.contains_key(&permanent_id));
+
+        state.channels[&listened_id].write_err().await.unwrap().listeners.clear();
+
+        assert_eq!(
+            state.remove_empty_temporary_channels(Duration::ZERO).await.unwrap(),
+            vec![listened_id]
+        );
+        assert!(state.remove_empty_temporary_channels(Duration::ZERO).await.unwrap().is_empty());
+        assert_eq!(state.channels.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn linked_channels_are_followed_once_and_both_ways() {
+        let mut state = create_state().await;
+
+        let ids: Vec<u32> = {
+            let mut ids = Vec::new();
+
+            for _ in 0..3 {
+                ids.push(state.add_channel(&temporary_channel()).read_err().await.unwrap().id);
             }
+
+            ids
+        };
+
+        // 1 - 2 - 3 - 1 forms a cycle
+        for (channel_id, linked_id) in [(ids[0], ids[1]), (ids[1], ids[2]), (ids[2], ids[0])] {
+            state.channels[&channel_id].write_err().await.unwrap().links.insert(linked_id);
+            state.channels[&linked_id].write_err().await.unwrap().links.insert(channel_id);
         }
 
-        channel_id
+        let mut linked: Vec<u32> = state
+            .get_linked_channels(ids[0])
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        linked.sort_unstable();
+        assert_eq!(linked, vec![ids[1], ids[2]]);
+
+        // The id is reused by a channel without links, the links left on its old peers are not followed
+        state.remove_channel(ids[2]);
+        assert_eq!(state.add_channel(&temporary_channel()).read_err().await.unwrap().id, ids[2]);
+
+        let linked: Vec<u32> = state
+            .get_linked_channels(ids[0])
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(linked, vec![ids[1]]);
+        assert!(state.get_linked_channels(ids[2]).await.unwrap().is_empty());
     }
 }
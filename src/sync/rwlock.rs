@@ -1,5 +1,7 @@
 //! Smart pointer to [`tokio::sync::RwLock`].
 
+use std::future::Future;
+use std::panic::Location;
 use std::time::Duration;
 
 use crate::sync::{Error, Result, DEFAULT_TIMEOUT_DURATION};
@@ -15,42 +17,88 @@ pub struct RwLock<T> {
     inner: tokio::sync::RwLock<T>,
     /// The timeout duration
     timeout: Duration,
+    /// Name of the lock in timeout logs and metrics
+    label: &'static str,
 }
 
 impl<T> RwLock<T> {
-    /// Create new `RwLock` with default timeout of 30 seconds.
+    /// Create new `RwLock` with default timeout of 30 seconds, labeled with
+    /// the name of the wrapped type.
     pub fn new(value: T) -> Self {
+        Self::with_label(value, type_label::<T>())
+    }
+
+    /// Create new `RwLock` with the given label, used when a lock timeout is
+    /// reported.
+    pub fn with_label(value: T, label: &'static str) -> Self {
         Self {
             inner: tokio::sync::RwLock::new(value),
             timeout: DEFAULT_TIMEOUT_DURATION,
+            label,
         }
     }
 
     /// Wrapper around [`tokio::sync::RwLock::read()`]. Will time out if the
     /// lock can't get acquired until the timeout is reached.
     ///
-    /// Returns an error if timeout is reached.
-    pub async fn read_err(&self) -> Result<tokio::sync::RwLockReadGuard<'_, T>> {
-        let read_guard = timeout(self.timeout, self.inner.read())
-            .await
-            .map_err(|_| Error::ReadLockTimeout(self.timeout.as_millis()))?;
+    /// Returns an error if timeout is reached, the timeout is logged with the
+    /// location of the caller and counted by lock label.
+    #[track_caller]
+    pub fn read_err(&self) -> impl Future<Output = Result<tokio::sync::RwLockReadGuard<'_, T>>> + '_ {
+        let caller = Location::caller();
+
+        async move {
+            let read_guard = timeout(self.timeout, self.inner.read()).await.map_err(|_| {
+                self.report_timeout("read", caller);
 
-        Ok(read_guard)
+                Error::ReadLockTimeout(self.timeout.as_millis())
+            })?;
+
+            Ok(read_guard)
+        }
     }
 
     /// Wrapper around [`tokio::sync::RwLock::write()`]. Will time out if
     /// the lock can't get acquired until the timeout is reached.
     ///
-    /// Returns an error if timeout is reached.
-    pub async fn write_err(&self) -> Result<tokio::sync::RwLockWriteGuard<'_, T>> {
-        let write_guard = timeout(self.timeout, self.inner.write())
-            .await
-            .map_err(|_| Error::WriteLockTimeout(self.timeout.as_millis()))?;
+    /// Returns an error if timeout is reached, the timeout is logged with the
+    /// location of the caller and counted by lock label.
+    #[track_caller]
+    pub fn write_err(&self) -> impl Future<Output = Result<tokio::sync::RwLockWriteGuard<'_, T>>> + '_ {
+        let caller = Location::caller();
+
+        async move {
+            let write_guard = timeout(self.timeout, self.inner.write()).await.map_err(|_| {
+                self.report_timeout("write", caller);
+
+                Error::WriteLockTimeout(self.timeout.as_millis())
+            })?;
+
+            Ok(write_guard)
+        }
+    }
+
+    fn report_timeout(&self, mode: &str, caller: &Location) {
+        tracing::warn!(
+            "{} lock on {} timed out after {} ms at {}",
+            mode,
+            self.label,
+            self.timeout.as_millis(),
+            caller
+        );
 
-        Ok(write_guard)
+        crate::metrics::LOCK_TIMEOUTS_TOTAL.with_label_values(&[self.label, mode]).inc();
     }
 }
 
+/// Name of a type without its module path nor generic parameters
+fn type_label<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    let name = name.split('<').next().unwrap_or(name);
+
+    name.rsplit("::").next().unwrap_or(name)
+}
+
 impl<T: Default> Default for RwLock<T> {
     fn default() -> Self {
         Self::new(T::default())
@@ -62,3 +110,26 @@ impl<T> From<T> for RwLock<T> {
         Self::new(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::CodecState;
+
+    #[test]
+    fn locks_are_labeled_with_the_type_name() {
+        assert_eq!(RwLock::new(CodecState::default()).label, "CodecState");
+        assert_eq!(RwLock::new(vec![1u32]).label, "Vec");
+        assert_eq!(RwLock::with_label(0u32, "counter").label, "counter");
+    }
+
+    #[tokio::test]
+    async fn timeouts_are_counted_by_label() {
+        let lock = RwLock::with_label(0u32, "test_timeout");
+        let counter = crate::metrics::LOCK_TIMEOUTS_TOTAL.with_label_values(&["test_timeout", "read"]);
+        let _write_guard = lock.write_err().await.unwrap();
+
+        assert!(matches!(lock.read_err().await, Err(Error::ReadLockTimeout(_))));
+        assert_eq!(counter.get(), 1);
+    }
+}
@@ -0,0 +1,152 @@
+//! Talking state of the clients, derived from their voice so speaking indicators do not rely on each client
+
+use crate::error::MumbleError;
+use crate::state::ServerState;
+use crate::sync::RwLock;
+use crate::webhook::{self, WebhookEvent};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A speaker that sent no audio for this long stopped talking, even without an end of transmission
+pub const TALKING_TIMEOUT: Duration = Duration::from_millis(500);
+/// Delay after an end of transmission before the speaker is considered stopped, so a short pause is not reported
+pub const TALKING_END_DELAY: Duration = Duration::from_millis(200);
+const TALKING_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+struct Talking {
+    last_audio: Instant,
+    /// Last audio packet had the end of transmission bit
+    ended: bool,
+}
+
+#[derive(Default, Debug)]
+pub struct TalkingState {
+    talking: Mutex<Option<Talking>>,
+}
+
+impl TalkingState {
+    /// Record an audio packet received at `now`, returns whether the client started talking
+    ///
+    /// A lone end of transmission does not start a transmission
+    pub fn audio(&self, has_audio: bool, end: bool, now: Instant) -> bool {
+        let mut talking = match self.talking.lock() {
+            Ok(talking) => talking,
+            Err(err) => {
+                tracing::error!("client talking lock poisoned: {}", err);
+
+                return false;
+            }
+        };
+
+        match talking.as_mut() {
+            Some(current) => {
+                current.last_audio = now;
+                current.ended = end;
+
+                false
+            }
+            None if has_audio => {
+                *talking = Some(Talking {
+                    last_audio: now,
+                    ended: end,
+                });
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns whether the client stopped talking at `now`
+    pub fn check(&self, now: Instant) -> bool {
+        let mut talking = match self.talking.lock() {
+            Ok(talking) => talking,
+            Err(_) => return false,
+        };
+
+        let stopped = match talking.as_ref() {
+            Some(current) => {
+                let delay = if current.ended { TALKING_END_DELAY } else { TALKING_TIMEOUT };
+
+                now.saturating_duration_since(current.last_audio) >= delay
+            }
+            None => false,
+        };
+
+        if stopped {
+            *talking = None;
+        }
+
+        stopped
+    }
+
+    pub fn is_talking(&self) -> bool {
+        self.talking.lock().map(|talking| talking.is_some()).unwrap_or_default()
+    }
+}
+
+/// Report the clients that stopped talking, only run when there is a webhook to notify
+pub async fn talking_loop(state: Arc<RwLock<ServerState>>) {
+    loop {
+        match talking_run(&state).await {
+            Ok(_) => (),
+            Err(e) => tracing::error!("error in talking loop: {}", e),
+        }
+
+        tokio::time::sleep(TALKING_CHECK_INTERVAL).await;
+    }
+}
+
+async fn talking_run(state: &Arc<RwLock<ServerState>>) -> Result<(), MumbleError> {
+    let state_read = state.read_err().await?;
+    let now = Instant::now();
+
+    let webhook = match &state_read.webhook {
+        Some(webhook) => webhook,
+        None => return Ok(()),
+    };
+
+    for client in state_read.clients.values() {
+        let client_read = client.read_err().await?;
+
+        if client_read.talking.check(now) {
+            webhook.send(WebhookEvent::TalkingStop {
+                username: client_read.authenticate.get_username().to_string(),
+                session_id: client_read.session_id,
+                timestamp: webhook::now(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn talking_stops_after_end_delay_or_timeout() {
+        let talking = TalkingState::default();
+        let start = Instant::now();
+
+        assert!(!talking.audio(false, true, start));
+        assert!(talking.audio(true, false, start));
+        assert!(!talking.audio(true, false, start + Duration::from_millis(20)));
+        assert!(!talking.check(start + Duration::from_millis(100)));
+        assert!(talking.check(start + Duration::from_millis(20) + TALKING_TIMEOUT));
+        assert!(!talking.is_talking());
+
+        assert!(talking.audio(true, false, start));
+        assert!(!talking.audio(false, true, start + Duration::from_millis(20)));
+        assert!(!talking.check(start + Duration::from_millis(100)));
+
+        // Talking again right after the end of transmission is debounced
+        assert!(!talking.audio(true, false, start + Duration::from_millis(150)));
+        assert!(!talking.check(start + Duration::from_millis(400)));
+        assert!(!talking.audio(false, true, start + Duration::from_millis(400)));
+        assert!(talking.check(start + Duration::from_millis(400) + TALKING_END_DELAY));
+        assert!(!talking.is_talking());
+    }
+}
@@ -1,5 +1,8 @@
 use std::collections::HashSet;
 
+/// Number of configurable voice targets per client, the mumble protocol allows up to 30 (ids 1 to 30)
+pub const VOICE_TARGET_COUNT: u8 = 30;
+
 #[derive(Default, Debug)]
 pub struct VoiceTarget {
     pub sessions: HashSet<u32>,
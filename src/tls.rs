@@ -0,0 +1,198 @@
+use rustls_pemfile::{certs, pkcs8_private_keys};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_rustls::rustls::server::{ClientCertVerified, ClientCertVerifier, ClientHello, NoClientAuth, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{self, Certificate, DistinguishedNames, PrivateKey, SupportedCipherSuite, SupportedProtocolVersion};
+use tokio_rustls::webpki;
+
+static TLS12_VERSIONS: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13, &rustls::version::TLS12];
+static TLS13_VERSIONS: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[clap(name = "1.2")]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[clap(name = "1.3")]
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    /// Protocol versions allowed when this version is the minimum one
+    fn get_versions(&self) -> &'static [&'static SupportedProtocolVersion] {
+        match self {
+            TlsVersion::Tls12 => TLS12_VERSIONS,
+            TlsVersion::Tls13 => TLS13_VERSIONS,
+        }
+    }
+}
+
+pub fn load_certs<P: AsRef<Path>>(path: P) -> io::Result<Vec<Certificate>> {
+    certs(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))
+        .map(|mut certs| certs.drain(..).map(Certificate).collect())
+}
+
+pub fn load_keys<P: AsRef<Path>>(path: P) -> io::Result<Vec<PrivateKey>> {
+    pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
+        .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
+}
+
+/// Generate a self signed certificate and its pkcs8 key, and write them at the given paths
+pub fn generate_self_signed_cert<P: AsRef<Path>>(cert_path: P, key_path: P) -> Result<(), anyhow::Error> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+
+    std::fs::write(cert_path, cert.serialize_pem()?)?;
+    std::fs::write(key_path, cert.serialize_private_key_pem())?;
+
+    Ok(())
+}
+
+/// SHA-256 fingerprint of a certificate, as colon separated hex bytes
+pub fn get_fingerprint(cert: &Certificate) -> String {
+    ring::digest::digest(&ring::digest::SHA256, cert.0.as_ref())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// Find the rustls cipher suites matching the given names (e.g. `TLS13_AES_256_GCM_SHA384`), all suites are used if empty
+pub fn parse_cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>, anyhow::Error> {
+    if names.is_empty() {
+        return Ok(rustls::ALL_CIPHER_SUITES.to_vec());
+    }
+
+    names
+        .iter()
+        .map(|name| {
+            rustls::ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name.trim()))
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("unsupported cipher suite {}", name))
+        })
+        .collect()
+}
+
+/// Pick the certificate matching the server name sent by the client, the first one is used without server name or match
+///
+/// Mumble clients connecting to an ip address do not send any server name, so they always get the first certificate.
+struct SniCertResolver {
+    certified_keys: Vec<Arc<CertifiedKey>>,
+}
+
+impl SniCertResolver {
+    fn new(pairs: Vec<(Vec<Certificate>, PrivateKey)>) -> Result<Self, anyhow::Error> {
+        let certified_keys = pairs
+            .into_iter()
+            .map(|(certs, key)| Ok(Arc::new(CertifiedKey::new(certs, sign::any_supported_type(&key)?))))
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+        Ok(Self { certified_keys })
+    }
+
+    fn is_valid_for(certified_key: &CertifiedKey, server_name: &str) -> bool {
+        let dns_name = match webpki::DnsNameRef::try_from_ascii_str(server_name) {
+            Ok(dns_name) => dns_name,
+            Err(_) => return false,
+        };
+
+        certified_key
+            .end_entity_cert()
+            .ok()
+            .and_then(|cert| webpki::EndEntityCert::try_from(cert.0.as_ref()).ok())
+            .map(|cert| cert.verify_is_valid_for_dns_name(dns_name).is_ok())
+            .unwrap_or(false)
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if let Some(server_name) = client_hello.server_name() {
+            let certified_key = self
+                .certified_keys
+                .iter()
+                .find(|certified_key| Self::is_valid_for(certified_key, server_name));
+
+            if let Some(certified_key) = certified_key {
+                return Some(certified_key.clone());
+            }
+        }
+
+        self.certified_keys.first().cloned()
+    }
+}
+
+/// Ask clients for a certificate without requiring one, any certificate is accepted
+///
+/// Mumble client certificates are self signed, they only identify a user through their fingerprint and are not trusted for anything else.
+struct AnyClientCert;
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        Some(false)
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        Some(Vec::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// Create the server config from certificate chain and key pairs, several pairs are served by server name
+///
+/// Client certificates are only asked for when requested, so their fingerprint can identify registered users
+pub fn create_tls_config(
+    mut pairs: Vec<(Vec<Certificate>, PrivateKey)>,
+    min_version: TlsVersion,
+    cipher_suites: &[SupportedCipherSuite],
+    request_client_certs: bool,
+) -> Result<rustls::ServerConfig, anyhow::Error> {
+    let versions = min_version.get_versions();
+    let usable = cipher_suites.iter().any(|suite| versions.contains(&suite.version()));
+
+    if !usable {
+        return Err(anyhow::anyhow!(
+            "none of the selected cipher suites can be used with a minimum tls version of {:?}",
+            min_version
+        ));
+    }
+
+    let builder = rustls::ServerConfig::builder()
+        .with_cipher_suites(cipher_suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)?
+        .with_client_cert_verifier(if request_client_certs {
+            Arc::new(AnyClientCert)
+        } else {
+            NoClientAuth::new()
+        });
+
+    let config = if pairs.len() == 1 {
+        let (certs, key) = pairs.remove(0);
+
+        builder.with_single_cert(certs, key)?
+    } else {
+        builder.with_cert_resolver(Arc::new(SniCertResolver::new(pairs)?))
+    };
+
+    Ok(config)
+}
@@ -0,0 +1,143 @@
+//! Voice transcoder, re-encode the opus stream of each speaker at a lower bitrate before it is sent to listeners
+
+use crate::message::{ClientMessage, Publisher};
+use crate::voice::{Clientbound, VoicePacket, VoicePacketPayload};
+use audiopus::coder::{Decoder, Encoder};
+use audiopus::packet::Packet;
+use audiopus::{Application, Bitrate, Channels, MutSignals, SampleRate};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Max duration of an opus frame is 120ms at 48kHz
+const MAX_DECODE_FRAME_SIZE: usize = 48000 / 1000 * 120;
+/// Largest opus packet as recommended by the specification
+const MAX_ENCODED_SIZE: usize = 4000;
+/// The task of a speaker stops after this duration without any packet
+const SPEAKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Transcode the opus voice of speakers, each speaker has its own task so a frame is transcoded once whatever the number of listeners
+pub struct Transcoder {
+    bitrate: i32,
+    complexity: u8,
+    speakers: Mutex<HashMap<u32, Sender<Transcode>>>,
+}
+
+struct Transcode {
+    packet: VoicePacket<Clientbound>,
+    /// Publishers of the listeners, and whether they receive the positional data
+    listeners: Vec<(Publisher, bool)>,
+}
+
+impl Transcoder {
+    /// Create a transcoder for the given bitrate in bits per second and opus complexity (0 to 10)
+    pub fn new(bitrate: u32, complexity: u8) -> Self {
+        Self {
+            bitrate: bitrate as i32,
+            complexity,
+            speakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Push a voice packet of a speaker, it is sent to the listeners once transcoded, packets are dropped if the speaker task is lagging behind
+    pub fn push(&self, session_id: u32, packet: VoicePacket<Clientbound>, listeners: Vec<(Publisher, bool)>) {
+        let mut speakers = match self.speakers.lock() {
+            Ok(speakers) => speakers,
+            Err(err) => {
+                tracing::error!("transcoder speakers lock poisoned: {}", err);
+
+                return;
+            }
+        };
+
+        let running = speakers.get(&session_id).map(|sender| !sender.is_closed()).unwrap_or(false);
+
+        if !running {
+            // Forget the tasks of speakers that went silent before starting a new one
+            speakers.retain(|_, sender| !sender.is_closed());
+
+            let speaker = match Speaker::new(self.bitrate, self.complexity) {
+                Ok(speaker) => speaker,
+                Err(err) => {
+                    tracing::error!("transcoder cannot create opus coders: {}", err);
+
+                    return;
+                }
+            };
+
+            let (sender, receiver) = mpsc::channel(64);
+            tokio::spawn(transcode_loop(receiver, speaker));
+            speakers.insert(session_id, sender);
+        }
+
+        let sender = &speakers[&session_id];
+
+        if let Err(err) = sender.try_send(Transcode { packet, listeners }) {
+            tracing::trace!("transcoder dropped voice packet of {}: {}", session_id, err);
+        }
+    }
+}
+
+struct Speaker {
+    decoder: Decoder,
+    encoder: Encoder,
+}
+
+impl Speaker {
+    fn new(bitrate: i32, complexity: u8) -> Result<Self, audiopus::Error> {
+        let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Mono, Application::Voip)?;
+        encoder.set_bitrate(Bitrate::BitsPerSecond(bitrate))?;
+        encoder.set_complexity(complexity)?;
+
+        Ok(Self {
+            decoder: Decoder::new(SampleRate::Hz48000, Channels::Mono)?,
+            encoder,
+        })
+    }
+
+    /// Decode the frame and encode it again with the same duration
+    fn transcode(&mut self, frame: &Bytes) -> Result<Bytes, audiopus::Error> {
+        let mut samples = vec![0i16; MAX_DECODE_FRAME_SIZE];
+        let packet = Packet::try_from(frame.as_ref())?;
+        let size = self.decoder.decode(Some(packet), MutSignals::try_from(&mut samples)?, false)?;
+
+        let mut output = vec![0u8; MAX_ENCODED_SIZE];
+        let length = self.encoder.encode(&samples[..size], &mut output)?;
+        output.truncate(length);
+
+        Ok(Bytes::from(output))
+    }
+}
+
+async fn transcode_loop(mut receiver: Receiver<Transcode>, mut speaker: Speaker) {
+    while let Ok(Some(Transcode { mut packet, listeners })) = tokio::time::timeout(SPEAKER_TIMEOUT, receiver.recv()).await {
+        if let VoicePacket::Audio {
+            session_id,
+            payload: VoicePacketPayload::Opus(frame, _),
+            ..
+        } = &mut packet
+        {
+            // The empty terminator frame is forwarded as is
+            if !frame.is_empty() {
+                let start = Instant::now();
+
+                match speaker.transcode(frame) {
+                    Ok(transcoded) => *frame = transcoded,
+                    Err(err) => tracing::debug!("transcoder cannot transcode opus frame of {}: {}", session_id, err),
+                }
+
+                crate::metrics::TRANSCODE_DURATION_SECONDS.observe(start.elapsed().as_secs_f64());
+            }
+        }
+
+        for (listener, keep_position) in listeners {
+            let packet = if keep_position { packet.clone() } else { packet.without_position() };
+
+            if let Err(err) = listener.try_send(ClientMessage::SendVoicePacket(packet)) {
+                tracing::error!("error sending transcoded voice packet message: {}", err);
+            }
+        }
+    }
+}
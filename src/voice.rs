@@ -1,6 +1,7 @@
 //! Voice channel packets and codecs
 
 use crate::error::DecryptError;
+use crate::opus;
 use byteorder::ReadBytesExt;
 use bytes::Buf;
 use bytes::BufMut;
@@ -14,6 +15,13 @@ use std::marker::PhantomData;
 use super::varint::BufMutExt;
 use super::varint::ReadExt;
 
+/// Maximum number of frames in a CELT or Speex packet, mumble clients send at most a few frames per packet
+const MAX_FRAMES: usize = 32;
+/// Maximum size of the audio data in a packet, the opus length header only has 13 bits
+const MAX_PAYLOAD_SIZE: usize = 0x1FFF;
+/// Sequence numbers are incremented for each 10ms of audio, which is 480 samples at 48kHz
+const SAMPLES_PER_SEQ: u64 = 480;
+
 /// A packet transmitted via Mumble's voice channel.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum VoicePacket<Dst: VoicePacketDst> {
@@ -103,6 +111,75 @@ impl VoicePacketDst for Clientbound {
     }
 }
 
+impl<Dst: VoicePacketDst> VoicePacket<Dst> {
+    /// Whether this packet carries the end of transmission bit
+    pub fn is_end(&self) -> bool {
+        matches!(
+            self,
+            VoicePacket::Audio {
+                payload: VoicePacketPayload::Opus(_, true),
+                ..
+            }
+        )
+    }
+
+    /// Position of the speaker, read from the first three little endian floats of the positional data
+    pub fn position(&self) -> Option<[f32; 3]> {
+        let position_info = match self {
+            VoicePacket::Audio {
+                position_info: Some(position_info),
+                ..
+            } if position_info.len() >= 12 => position_info,
+            _ => return None,
+        };
+
+        let mut position = [0.0; 3];
+
+        for (axis, bytes) in position.iter_mut().zip(position_info.chunks_exact(4)) {
+            *axis = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+
+        Some(position)
+    }
+
+    /// Whether this audio packet carries neither audio nor the end of transmission, clients may send them to keep the udp path alive
+    pub fn is_keepalive(&self) -> bool {
+        match self {
+            VoicePacket::Audio { payload, .. } => match payload {
+                VoicePacketPayload::Opus(frame, end) => frame.is_empty() && !end,
+                VoicePacketPayload::CeltAlpha(frames) | VoicePacketPayload::CeltBeta(frames) | VoicePacketPayload::Speex(frames) => {
+                    frames.is_empty()
+                }
+            },
+            VoicePacket::Ping { .. } => false,
+        }
+    }
+
+    /// Whether this audio packet has the end of transmission bit, only opus payloads carry it
+    pub fn is_end_of_transmission(&self) -> bool {
+        matches!(
+            self,
+            VoicePacket::Audio {
+                payload: VoicePacketPayload::Opus(_, true),
+                ..
+            }
+        )
+    }
+}
+
+impl VoicePacket<Clientbound> {
+    /// Copy of this packet without its positional data
+    pub fn without_position(&self) -> Self {
+        let mut packet = self.clone();
+
+        if let VoicePacket::Audio { position_info, .. } = &mut packet {
+            *position_info = None;
+        }
+
+        packet
+    }
+}
+
 impl VoicePacket<Serverbound> {
     pub fn into_client_bound(self, session_id: u32) -> VoicePacket<Clientbound> {
         match self {
@@ -125,6 +202,98 @@ impl VoicePacket<Serverbound> {
     }
 }
 
+/// Whether `next` can be appended to a batch of opus packets from the same speaker
+///
+/// Only contiguous single frame packets with the same opus configuration are batched, the end of transmission must be the last packet
+pub fn can_batch_voice_packet(batch: &[VoicePacket<Clientbound>], next: &VoicePacket<Clientbound>) -> bool {
+    let (first, last) = match (batch.first(), batch.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return false,
+    };
+
+    if batch.len() >= opus::MAX_PACKET_FRAMES {
+        return false;
+    }
+
+    match (first, last, next) {
+        (
+            VoicePacket::Audio {
+                target,
+                session_id,
+                payload: VoicePacketPayload::Opus(first_frame, _),
+                ..
+            },
+            VoicePacket::Audio {
+                seq_num: last_seq_num,
+                payload: VoicePacketPayload::Opus(last_frame, false),
+                ..
+            },
+            VoicePacket::Audio {
+                target: next_target,
+                session_id: next_session_id,
+                seq_num: next_seq_num,
+                payload: VoicePacketPayload::Opus(next_frame, _),
+                ..
+            },
+        ) => {
+            let frame_samples = opus::packet_samples(first_frame);
+
+            target == next_target
+                && session_id == next_session_id
+                && opus::is_single_frame(next_frame)
+                && opus::same_configuration(first_frame, next_frame)
+                // Sequence numbers count 10ms of audio, a gap must not be collapsed
+                && *next_seq_num == last_seq_num + opus::packet_samples(last_frame) / SAMPLES_PER_SEQ
+                && frame_samples * (batch.len() as u64 + 1) <= opus::MAX_PACKET_SAMPLES
+        }
+        _ => false,
+    }
+}
+
+/// Combine a batch of opus packets accepted by [can_batch_voice_packet] into a single voice packet
+pub fn batch_voice_packets(mut batch: Vec<VoicePacket<Clientbound>>) -> Option<VoicePacket<Clientbound>> {
+    if batch.len() <= 1 {
+        return batch.pop();
+    }
+
+    let mut frames = Vec::with_capacity(batch.len());
+
+    for packet in &batch {
+        match packet {
+            VoicePacket::Audio {
+                payload: VoicePacketPayload::Opus(frame, _),
+                ..
+            } => frames.push(frame.as_ref()),
+            _ => return None,
+        }
+    }
+
+    let payload = VoicePacketPayload::Opus(
+        opus::combine_packets(&frames),
+        batch.last().map(VoicePacket::is_end).unwrap_or(false),
+    );
+
+    match (batch.first(), batch.last()) {
+        (
+            Some(VoicePacket::Audio {
+                target,
+                session_id,
+                seq_num,
+                ..
+            }),
+            Some(VoicePacket::Audio { position_info, .. }),
+        ) => Some(VoicePacket::Audio {
+            _dst: PhantomData,
+            target: *target,
+            session_id: *session_id,
+            seq_num: *seq_num,
+            payload,
+            position_info: position_info.clone(),
+        }),
+        _ => None,
+    }
+}
+
 pub fn decode_voice_packet<DecodeDst: VoicePacketDst>(buf_mut: &mut BytesMut) -> Result<VoicePacket<DecodeDst>, DecryptError> {
     let mut buf = Cursor::new(&buf_mut);
     let header = buf.read_u8()?;
@@ -140,12 +309,21 @@ pub fn decode_voice_packet<DecodeDst: VoicePacketDst>(buf_mut: &mut BytesMut) ->
         let payload = match kind {
             0 | 2 | 3 => {
                 let mut frames = Vec::new();
+                let mut payload_size = 0;
                 let position = buf.position();
                 buf_mut.advance(position as usize);
                 loop {
                     if buf_mut.is_empty() {
+                        // A packet without any frame is a keepalive, only a missing continuation frame is truncated
+                        if frames.is_empty() {
+                            break;
+                        }
+
                         return Err(DecryptError::Eof);
                     }
+                    if frames.len() >= MAX_FRAMES {
+                        return Err(invalid_data("too many frames in voice packet"));
+                    }
                     let header = buf_mut[0];
                     buf_mut.advance(1);
 
@@ -153,6 +331,10 @@ pub fn decode_voice_packet<DecodeDst: VoicePacketDst>(buf_mut: &mut BytesMut) ->
                     if buf_mut.len() < len {
                         return Err(DecryptError::Eof);
                     }
+                    payload_size += len;
+                    if payload_size > MAX_PAYLOAD_SIZE {
+                        return Err(invalid_data("voice packet payload too large"));
+                    }
                     frames.push(buf_mut.split_to(len).freeze());
                     if header & 0x80 != 0x80 {
                         break;
@@ -166,11 +348,19 @@ pub fn decode_voice_packet<DecodeDst: VoicePacketDst>(buf_mut: &mut BytesMut) ->
                 }
             }
             4 => {
-                let header = buf.read_varint()?;
+                // A packet ending after its sequence number is read as an empty frame
+                let header = if buf.position() as usize == buf.get_ref().len() {
+                    0
+                } else {
+                    buf.read_varint()?
+                };
                 let position = buf.position();
                 buf_mut.advance(position as usize);
                 let termination_bit = header & 0x2000 == 0x2000;
                 let len = (header & !0x2000) as usize;
+                if len > MAX_PAYLOAD_SIZE {
+                    return Err(invalid_data("opus frame too large"));
+                }
                 if buf_mut.len() < len {
                     return Err(DecryptError::Eof);
                 }
@@ -178,10 +368,7 @@ pub fn decode_voice_packet<DecodeDst: VoicePacketDst>(buf_mut: &mut BytesMut) ->
                 VoicePacketPayload::Opus(frame, termination_bit)
             }
             _ => {
-                return Err(DecryptError::Io(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "unknown voice packet type",
-                )));
+                return Err(invalid_data("unknown voice packet type"));
             }
         };
         let position_info = if buf_mut.is_empty() { None } else { Some(buf_mut.split().freeze()) };
@@ -197,6 +384,10 @@ pub fn decode_voice_packet<DecodeDst: VoicePacketDst>(buf_mut: &mut BytesMut) ->
     Ok(result)
 }
 
+fn invalid_data(message: &'static str) -> DecryptError {
+    DecryptError::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
 pub fn encode_voice_packet<EncodeDst: VoicePacketDst>(item: &VoicePacket<EncodeDst>, dst: &mut BytesMut) {
     match item {
         VoicePacket::Ping { timestamp } => {
@@ -245,3 +436,275 @@ pub fn encode_voice_packet<EncodeDst: VoicePacketDst>(item: &VoicePacket<EncodeD
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_invalid_data(result: Result<VoicePacket<Serverbound>, DecryptError>) -> bool {
+        matches!(result, Err(DecryptError::Io(err)) if err.kind() == io::ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn decode_rejects_too_many_frames() {
+        // Speex packet, target 0, seq num 0, followed by empty frames all flagged with the continuation bit
+        let mut packet = vec![2 << 5, 0];
+        packet.extend(vec![0x80; MAX_FRAMES + 1]);
+        packet.push(0);
+
+        assert!(is_invalid_data(decode_voice_packet(&mut BytesMut::from(packet.as_slice()))));
+    }
+
+    #[test]
+    fn decode_accepts_max_frames() {
+        let mut packet = vec![0, 0];
+
+        for i in 0..MAX_FRAMES {
+            packet.push(if i + 1 == MAX_FRAMES { 127 } else { 0x80 | 127 });
+            packet.extend(vec![0; 127]);
+        }
+
+        match decode_voice_packet::<Serverbound>(&mut BytesMut::from(packet.as_slice())) {
+            Ok(VoicePacket::Audio {
+                payload: VoicePacketPayload::CeltAlpha(frames),
+                ..
+            }) => assert_eq!(frames.len(), MAX_FRAMES),
+            other => panic!("unexpected decode result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_rejects_too_large_opus_frame() {
+        let mut packet = BytesMut::new();
+        packet.put_u8(4 << 5);
+        packet.put_varint(0);
+        packet.put_varint(0x4000);
+        packet.put_bytes(0, 0x4000);
+
+        assert!(is_invalid_data(decode_voice_packet(&mut packet)));
+    }
+
+    #[test]
+    fn decode_rejects_truncated_packets() {
+        // Opus frame announcing more bytes than available
+        let mut packet = BytesMut::from(&[4 << 5, 0, 10, 1, 2][..]);
+        assert!(matches!(decode_voice_packet::<Serverbound>(&mut packet), Err(DecryptError::Eof)));
+
+        // Celt frame with a continuation bit but no following frame
+        let mut packet = BytesMut::from(&[0, 0, 0x81, 1][..]);
+        assert!(matches!(decode_voice_packet::<Serverbound>(&mut packet), Err(DecryptError::Eof)));
+
+        // Empty packet
+        assert!(decode_voice_packet::<Serverbound>(&mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    fn decode_keepalive_packets() {
+        // Opus packet with an empty frame and without the end of transmission bit
+        let packet = decode_voice_packet::<Serverbound>(&mut BytesMut::from(&[4 << 5, 0, 0][..])).expect("cannot decode keepalive");
+        assert_eq!(packet, audio((), VoicePacketPayload::Opus(Bytes::new(), false), None));
+        assert!(packet.is_keepalive());
+
+        // Header only packets, ending after their sequence number
+        let packet = decode_voice_packet::<Serverbound>(&mut BytesMut::from(&[4 << 5, 0][..])).expect("cannot decode keepalive");
+        assert!(packet.is_keepalive());
+
+        let packet = decode_voice_packet::<Clientbound>(&mut BytesMut::from(&[0, 7, 0][..])).expect("cannot decode keepalive");
+        assert_eq!(packet, audio(7, VoicePacketPayload::CeltAlpha(Vec::new()), None));
+        assert!(packet.is_keepalive());
+
+        // The empty terminator frame and empty celt frames are still meaningful
+        assert!(!audio::<Serverbound>((), VoicePacketPayload::Opus(Bytes::new(), true), None).is_keepalive());
+        assert!(!audio::<Serverbound>((), VoicePacketPayload::Speex(vec![Bytes::new()]), None).is_keepalive());
+    }
+
+    #[test]
+    fn position_is_read_from_positional_data() {
+        let position_info = Bytes::from_static(&[0, 0, 128, 63, 0, 0, 0, 64, 0, 0, 64, 64]);
+        let packet = audio::<Clientbound>(1, VoicePacketPayload::Opus(Bytes::new(), true), Some(position_info));
+        assert_eq!(packet.position(), Some([1.0, 2.0, 3.0]));
+
+        let packet = audio::<Clientbound>(
+            1,
+            VoicePacketPayload::Opus(Bytes::new(), true),
+            Some(Bytes::from_static(&[0, 0, 128, 63])),
+        );
+        assert_eq!(packet.position(), None);
+    }
+
+    #[test]
+    fn decode_rejects_unknown_kind() {
+        assert!(is_invalid_data(decode_voice_packet(&mut BytesMut::from(&[5 << 5, 0, 0][..]))));
+    }
+
+    fn round_trip<D: VoicePacketDst + Debug>(packet: VoicePacket<D>) {
+        let mut buf = BytesMut::new();
+        encode_voice_packet(&packet, &mut buf);
+
+        let decoded = decode_voice_packet::<D>(&mut buf).expect("cannot decode encoded packet");
+
+        assert_eq!(decoded, packet);
+    }
+
+    fn audio<D: VoicePacketDst>(session_id: D::SessionId, payload: VoicePacketPayload, position_info: Option<Bytes>) -> VoicePacket<D> {
+        VoicePacket::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id,
+            seq_num: 0,
+            payload,
+            position_info,
+        }
+    }
+
+    fn payloads() -> Vec<VoicePacketPayload> {
+        let frames = vec![Bytes::from_static(&[1, 2, 3]), Bytes::from_static(&[4]), Bytes::from(vec![5; 127])];
+
+        vec![
+            VoicePacketPayload::Opus(Bytes::from_static(&[1, 2, 3, 4]), false),
+            VoicePacketPayload::Opus(Bytes::from_static(&[1, 2, 3, 4]), true),
+            // Empty terminator frame sent when a client stops talking
+            VoicePacketPayload::Opus(Bytes::new(), true),
+            VoicePacketPayload::Opus(Bytes::from(vec![0xFF; MAX_PAYLOAD_SIZE]), false),
+            VoicePacketPayload::CeltAlpha(frames.clone()),
+            VoicePacketPayload::CeltBeta(frames.clone()),
+            VoicePacketPayload::Speex(frames),
+            VoicePacketPayload::Speex(vec![Bytes::new()]),
+            VoicePacketPayload::CeltAlpha(vec![Bytes::from(vec![0; 127]); MAX_FRAMES]),
+        ]
+    }
+
+    #[test]
+    fn round_trip_ping() {
+        for timestamp in [0, 1, 127, 128, u32::MAX as u64, u64::MAX] {
+            round_trip::<Serverbound>(VoicePacket::Ping { timestamp });
+            round_trip::<Clientbound>(VoicePacket::Ping { timestamp });
+        }
+    }
+
+    #[test]
+    fn round_trip_audio() {
+        let position_infos = [None, Some(Bytes::from_static(&[0, 0, 128, 63, 0, 0, 0, 64, 0, 0, 64, 64]))];
+
+        for payload in payloads() {
+            for position_info in &position_infos {
+                round_trip(audio::<Serverbound>((), payload.clone(), position_info.clone()));
+
+                for session_id in [0, 1, 300, u32::MAX] {
+                    round_trip(audio::<Clientbound>(session_id, payload.clone(), position_info.clone()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_targets_and_sequence_numbers() {
+        for target in 0..32 {
+            for seq_num in [0, 1, 127, 128, 1 << 20, u64::MAX] {
+                round_trip(VoicePacket::<Clientbound>::Audio {
+                    _dst: PhantomData,
+                    target,
+                    session_id: 42,
+                    seq_num,
+                    payload: VoicePacketPayload::Opus(Bytes::from_static(&[0xF8, 0xFF, 0xFE]), false),
+                    position_info: None,
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn into_client_bound_keeps_audio() {
+        let mut buf = BytesMut::new();
+        let packet = audio::<Serverbound>((), VoicePacketPayload::Opus(Bytes::from_static(&[1, 2]), true), None);
+        encode_voice_packet(&packet.into_client_bound(7), &mut buf);
+
+        let decoded = decode_voice_packet::<Clientbound>(&mut buf).expect("cannot decode encoded packet");
+
+        assert_eq!(
+            decoded,
+            audio::<Clientbound>(7, VoicePacketPayload::Opus(Bytes::from_static(&[1, 2]), true), None)
+        );
+    }
+
+    fn opus_packet(session_id: u32, seq_num: u64, frame: Vec<u8>, end: bool) -> VoicePacket<Clientbound> {
+        VoicePacket::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id,
+            seq_num,
+            payload: VoicePacketPayload::Opus(Bytes::from(frame), end),
+            position_info: None,
+        }
+    }
+
+    #[test]
+    fn batch_contiguous_opus_packets() {
+        // CELT only, 20ms, mono, single frame
+        let first = opus_packet(1, 10, vec![0xF8, 1, 2, 3], false);
+        let second = opus_packet(1, 12, [vec![0xF8], vec![4; 300]].concat(), false);
+        let third = opus_packet(1, 14, vec![0xF8, 5], true);
+
+        assert!(can_batch_voice_packet(std::slice::from_ref(&first), &second));
+        assert!(can_batch_voice_packet(&[first.clone(), second.clone()], &third));
+
+        let batched = batch_voice_packets(vec![first, second, third]).expect("cannot batch packets");
+
+        match batched {
+            VoicePacket::Audio {
+                session_id,
+                seq_num,
+                payload: VoicePacketPayload::Opus(frame, end),
+                ..
+            } => {
+                assert_eq!(session_id, 1);
+                assert_eq!(seq_num, 10);
+                assert!(end);
+                assert_eq!(opus::packet_samples(&frame), 2880);
+                // Code 3 toc, vbr with 3 frames, lengths of the first two frames (300 is written on two bytes)
+                assert_eq!(&frame[..5], &[0xFB, 0x83, 3, 252, 12]);
+                assert_eq!(frame.len(), 5 + 3 + 300 + 1);
+            }
+            other => panic!("unexpected batched packet: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn batch_rejects_incompatible_opus_packets() {
+        let first = opus_packet(1, 10, vec![0xF8, 1], false);
+
+        // Gap in the sequence
+        assert!(!can_batch_voice_packet(
+            std::slice::from_ref(&first),
+            &opus_packet(1, 14, vec![0xF8, 1], false)
+        ));
+        // Other speaker
+        assert!(!can_batch_voice_packet(
+            std::slice::from_ref(&first),
+            &opus_packet(2, 12, vec![0xF8, 1], false)
+        ));
+        // Other frame size
+        assert!(!can_batch_voice_packet(
+            std::slice::from_ref(&first),
+            &opus_packet(1, 12, vec![0xF0, 1], false)
+        ));
+        // Multiple frames packet
+        assert!(!can_batch_voice_packet(
+            std::slice::from_ref(&first),
+            &opus_packet(1, 12, vec![0xF9, 1], false)
+        ));
+        // Empty terminator frame
+        assert!(!can_batch_voice_packet(
+            std::slice::from_ref(&first),
+            &opus_packet(1, 12, vec![], true)
+        ));
+        // End of transmission must be the last packet
+        assert!(!can_batch_voice_packet(
+            &[opus_packet(1, 10, vec![0xF8, 1], true)],
+            &opus_packet(1, 12, vec![0xF8, 1], false)
+        ));
+        // More than 120ms of audio
+        let batch = (0..6).map(|i| opus_packet(1, i * 2, vec![0xF8, 1], false)).collect::<Vec<_>>();
+        assert!(!can_batch_voice_packet(&batch, &opus_packet(1, 12, vec![0xF8, 1], false)));
+    }
+}
@@ -0,0 +1,73 @@
+//! Webhook notifications, events are posted as json to an external url
+
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Connect {
+        username: String,
+        session_id: u32,
+        timestamp: u64,
+    },
+    Disconnect {
+        username: String,
+        session_id: u32,
+        timestamp: u64,
+    },
+    /// Sent on the first audio of a transmission
+    TalkingStart {
+        username: String,
+        session_id: u32,
+        timestamp: u64,
+    },
+    /// Sent once a transmission ended, or no audio was received for a while
+    TalkingStop {
+        username: String,
+        session_id: u32,
+        timestamp: u64,
+    },
+    ContextAction {
+        username: String,
+        session_id: u32,
+        action: String,
+        target_session_id: Option<u32>,
+        target_channel_id: Option<u32>,
+        timestamp: u64,
+    },
+}
+
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    /// Post an event in a background task, a slow or failing webhook never blocks the caller
+    pub fn send(&self, event: WebhookEvent) {
+        let request = self.client.post(&self.url).json(&event);
+
+        tokio::spawn(async move {
+            match request.send().await.and_then(|response| response.error_for_status()) {
+                Ok(_) => tracing::trace!("webhook event sent: {:?}", event),
+                Err(e) => tracing::error!("cannot send webhook event {:?}: {}", event, e),
+            }
+        });
+    }
+}
+
+/// Current unix timestamp in seconds, used for the events timestamp
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
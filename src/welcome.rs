@@ -0,0 +1,221 @@
+//! Per client initialization, run in order once a connected client is synced
+
+use crate::client::Client;
+use crate::error::MumbleError;
+use crate::proto::mumble::TextMessage;
+use crate::proto::MessageKind;
+use crate::sync::RwLock;
+use crate::target::VOICE_TARGET_COUNT;
+use crate::ServerState;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Channel joined on connect by clients presenting an access token, written as `token:channel_name`
+///
+/// Channels are given by name as ids are reused once a temporary channel is removed, e.g. `police:Police station`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TokenChannelConfig {
+    pub token: String,
+    pub channel: String,
+}
+
+impl FromStr for TokenChannelConfig {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some((token, channel)) if !token.is_empty() && !channel.is_empty() => Ok(Self {
+                token: token.to_string(),
+                channel: channel.to_string(),
+            }),
+            _ => Err(format!("invalid token channel {}, expected token:channel_name", value)),
+        }
+    }
+}
+
+impl TryFrom<String> for TokenChannelConfig {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for TokenChannelConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.token, self.channel)
+    }
+}
+
+impl From<TokenChannelConfig> for String {
+    fn from(value: TokenChannelConfig) -> Self {
+        value.to_string()
+    }
+}
+
+/// Voice target set on connect, written as `target_id:channel_name`
+///
+/// e.g. `1:Police`, the client whispers to the Police channel with the target 1 until it sets the target itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct VoiceTargetConfig {
+    pub id: u8,
+    pub channel: String,
+}
+
+impl FromStr for VoiceTargetConfig {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (id, channel) = match value.split_once(':') {
+            Some((id, channel)) if !channel.is_empty() => (id, channel),
+            _ => return Err(format!("invalid voice target {}, expected target_id:channel_name", value)),
+        };
+
+        let id = match id.parse::<u8>() {
+            Ok(id) if (1..=VOICE_TARGET_COUNT).contains(&id) => id,
+            _ => {
+                return Err(format!(
+                    "invalid voice target id {}, expected a number from 1 to {}",
+                    id, VOICE_TARGET_COUNT
+                ))
+            }
+        };
+
+        Ok(Self {
+            id,
+            channel: channel.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for VoiceTargetConfig {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for VoiceTargetConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.id, self.channel)
+    }
+}
+
+impl From<VoiceTargetConfig> for String {
+    fn from(value: VoiceTargetConfig) -> Self {
+        value.to_string()
+    }
+}
+
+/// Name of an init step, used to configure the order of the steps
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InitStepKind {
+    TokenChannel,
+    VoiceTarget,
+    Greeting,
+}
+
+#[derive(Debug, Clone)]
+pub enum InitStep {
+    /// Move the client to the channel of the first mapping matching one of its tokens
+    ///
+    /// Clients placed out of the default channel on connect, by a channel placement or their registered default channel, are left there
+    JoinTokenChannel(Vec<TokenChannelConfig>),
+    /// Set voice targets of the client to channels, a target the client sets itself replaces it
+    VoiceTarget(Vec<VoiceTargetConfig>),
+    /// Send a text message to the client
+    Greeting(String),
+}
+
+impl InitStep {
+    pub async fn run(&self, state: &Arc<RwLock<ServerState>>, client: &Arc<RwLock<Client>>) -> Result<(), MumbleError> {
+        match self {
+            InitStep::JoinTokenChannel(token_channels) => {
+                let (tokens, current_channel_id) = {
+                    let client_read = client.read_err().await?;
+
+                    (client_read.tokens.clone(), client_read.channel_id.load(Ordering::Relaxed))
+                };
+
+                let token_channel = match token_channels.iter().find(|token_channel| tokens.contains(&token_channel.token)) {
+                    Some(token_channel) => token_channel,
+                    None => return Ok(()),
+                };
+
+                let leave_channel_id = {
+                    let state_read = state.read_err().await?;
+
+                    let default_channel = if state_read.channels.contains_key(&state_read.default_channel) {
+                        state_read.default_channel
+                    } else {
+                        0
+                    };
+
+                    if current_channel_id != default_channel {
+                        return Ok(());
+                    }
+
+                    let channel_id = match state_read.get_channel_by_name(&token_channel.channel).await? {
+                        Some(channel) => channel.read_err().await?.id,
+                        None => {
+                            tracing::warn!("token channel {} does not exist", token_channel.channel);
+
+                            return Ok(());
+                        }
+                    };
+
+                    state_read.set_client_channel(client.clone(), channel_id).await?
+                };
+
+                if let Some(leave_channel_id) = leave_channel_id {
+                    state.write_err().await?.remove_channel(leave_channel_id);
+                }
+            }
+            InitStep::VoiceTarget(voice_targets) => {
+                let mut targets: BTreeMap<u8, HashSet<u32>> = BTreeMap::new();
+
+                {
+                    let state_read = state.read_err().await?;
+
+                    for voice_target in voice_targets {
+                        match state_read.get_channel_by_name(&voice_target.channel).await? {
+                            Some(channel) => {
+                                let channel_id = { channel.read_err().await?.id };
+
+                                targets.entry(voice_target.id).or_default().insert(channel_id);
+                            }
+                            None => tracing::warn!("voice target channel {} does not exist", voice_target.channel),
+                        }
+                    }
+                }
+
+                for (id, channels) in targets {
+                    let target = { client.read_err().await?.get_target((id - 1) as usize) };
+
+                    if let Some(target) = target {
+                        target.write_err().await?.channels = channels;
+                    }
+                }
+            }
+            InitStep::Greeting(text) => {
+                let client_read = client.read_err().await?;
+
+                let mut text_message = TextMessage::new();
+                text_message.mut_session().push(client_read.session_id);
+                text_message.set_message(text.clone());
+
+                client_read.send_message(MessageKind::TextMessage, &text_message).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+//! Clients of both address families reach a server bound on a dual stack ipv6 address
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore, ServerName};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use zumble::ban::BanList;
+use zumble::crypt::CryptState;
+use zumble::message;
+use zumble::proto::mumble::{Authenticate, Version};
+use zumble::server::{bind_tcp, bind_udp};
+use zumble::state::ServerState;
+use zumble::tls::{create_tls_config, TlsVersion};
+
+const DUAL_STACK: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+
+/// Connect a client from the given local address to the dual stack server, and add it to the state
+async fn add_client(state: &mut ServerState, port: u16, from: IpAddr, name: &str) -> TlsStream<TcpStream> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).expect("cannot generate certificate");
+    let certs = vec![Certificate(cert.serialize_der().expect("cannot serialize certificate"))];
+    let key = PrivateKey(cert.serialize_private_key_der());
+
+    let mut roots = RootCertStore::empty();
+    roots.add(&certs[0]).expect("cannot add root certificate");
+
+    let server_config =
+        create_tls_config(vec![(certs, key)], TlsVersion::Tls12, rustls::ALL_CIPHER_SUITES, false).expect("cannot create tls config");
+    let client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let listener = bind_tcp(DUAL_STACK).expect("cannot bind dual stack tcp listener");
+    let address = SocketAddr::new(from, listener.local_addr().expect("cannot get listener address").port());
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let connector = TlsConnector::from(Arc::new(client_config));
+    let server_name = ServerName::try_from("localhost").expect("invalid server name");
+
+    let (accepted, connected) = tokio::join!(
+        async { acceptor.accept(listener.accept().await.expect("cannot accept").0).await },
+        async {
+            connector
+                .connect(server_name, TcpStream::connect(address).await.expect("cannot connect"))
+                .await
+        },
+    );
+
+    let server_stream = accepted.expect("cannot accept tls");
+    let tcp_address = server_stream.get_ref().0.peer_addr().expect("cannot get peer address");
+    let (_, write) = io::split(server_stream);
+    let (publisher, _) = message::channel(128);
+
+    let mut authenticate = Authenticate::new();
+    authenticate.set_username(name.to_string());
+
+    let client = state.add_client(
+        Version::new(),
+        authenticate,
+        CryptState::default(),
+        write,
+        tcp_address,
+        publisher,
+        None,
+    );
+
+    // Udp address of the client as seen by the server socket
+    let client_socket = UdpSocket::bind(SocketAddr::new(from, 0))
+        .await
+        .expect("cannot bind client udp socket");
+    client_socket
+        .send_to(b"ping", SocketAddr::new(from, port))
+        .await
+        .expect("cannot send udp");
+
+    let mut buffer = [0; 16];
+    let (_, udp_address) = state.socket.recv_from(&mut buffer).await.expect("cannot receive udp");
+    let local_address = client_socket.local_addr().expect("cannot get client udp address");
+
+    assert_eq!(udp_address.port(), local_address.port());
+    assert!(
+        udp_address.is_ipv6(),
+        "dual stack socket reports ipv6 addresses, got {}",
+        udp_address
+    );
+
+    state
+        .set_client_socket(client, udp_address)
+        .await
+        .expect("cannot set client socket");
+
+    connected.expect("cannot connect tls")
+}
+
+#[tokio::test]
+async fn ipv4_and_ipv6_clients_are_found_by_udp_address() {
+    let socket = bind_udp(DUAL_STACK).expect("cannot bind dual stack udp socket");
+    let port = socket.local_addr().expect("cannot get udp address").port();
+    let mut state = ServerState::new(Arc::new(socket), BanList::load(None).expect("cannot create ban list"));
+
+    let _v4 = add_client(&mut state, port, IpAddr::V4(Ipv4Addr::LOCALHOST), "v4").await;
+    let _v6 = add_client(&mut state, port, IpAddr::V6(Ipv6Addr::LOCALHOST), "v6").await;
+
+    for address in state.clients_by_socket.keys() {
+        let client = state.get_client_by_socket(address).expect("client not found by its udp address");
+
+        assert_eq!(client.read_err().await.unwrap().udp_socket_addr, Some(*address));
+    }
+
+    let v4 = state
+        .clients_by_socket
+        .keys()
+        .find(|address| address.ip() == Ipv4Addr::LOCALHOST.to_ipv6_mapped());
+    let v6 = state.clients_by_socket.keys().find(|address| address.ip() == Ipv6Addr::LOCALHOST);
+
+    assert!(v4.is_some(), "ipv4 client is registered with its mapped address");
+    assert!(v6.is_some(), "ipv6 client is registered with its address");
+    assert_eq!(state.clients_by_socket.len(), 2);
+}
@@ -0,0 +1,902 @@
+//! A scripted mumble client connects to a running server, and gets its loopback voice back over tcp and udp
+//!
+//! A client staying silent after the tls connection is dropped once the handshake timeout is reached
+//!
+//! Oversized positional data is stripped from the echoed voice
+//!
+//! The anonymous udp ping reports the connected clients and the server limits
+//!
+//! Audio injected by the server reaches the client as the server session
+//!
+//! Channels created by a client are refused past the nesting limit, the per client count limit and the server channel limit
+//!
+//! The voice of a client is routed to the other clients of its channel, of linked channels, to its listeners and to its whisper targets
+//!
+//! The voice of a muted client, and the channel voice of a client in a listen only channel, is dropped before being routed
+//!
+//! A client cannot join a channel that reached its max occupancy, neither by its id nor by its name
+//!
+//! A client without one of the tokens of a restricted channel cannot join it by its name
+//!
+//! The ban list can only be read or replaced by an admin client
+//!
+//! A client presenting a registered token gets its user id and default channel, an anonymous client gets no user id
+//!
+//! A client presenting a token mapped to a channel is moved to that channel once connected
+//!
+//! Voice targets configured by channel name are set once connected, before the greeting is sent
+
+use bytes::{Bytes, BytesMut};
+use protobuf::Message;
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore, ServerName};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use zumble::ban::{Ban, BanList};
+use zumble::client::Client;
+use zumble::crypt::CryptState;
+use zumble::permission::PERM_ENTER;
+use zumble::proto::mumble::{
+    Authenticate, BanList as BanListMessage, ChannelState, CryptSetup, PermissionDenied, PermissionDenied_DenyType, ServerSync,
+    TextMessage, UserState, Version,
+};
+use zumble::proto::{message_to_bytes, tunnel_to_bytes, MessageKind};
+use zumble::registry::{RegisteredUser, Registry};
+use zumble::route::{get_voice_dropped, route_voice, VoiceDropped, CHANNEL_TARGET};
+use zumble::server::{bind_tcp, bind_udp, create_tcp_server, create_udp_server};
+use zumble::state::{ServerState, DEFAULT_MAX_BANDWIDTH, SERVER_SESSION_ID};
+use zumble::sync::RwLock;
+use zumble::tls::{create_tls_config, TlsVersion};
+use zumble::voice::{decode_voice_packet, encode_voice_packet, Clientbound, Serverbound, VoicePacket, VoicePacketPayload};
+use zumble::welcome::{InitStep, TokenChannelConfig, VoiceTargetConfig};
+
+const LOCALHOST: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+const PROTOCOL_VERSION: u32 = 0x010400;
+/// Voice target echoing the voice back to its speaker
+const LOOPBACK_TARGET: u8 = 31;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct TestServer {
+    tcp_address: SocketAddr,
+    udp_address: SocketAddr,
+    connector: TlsConnector,
+    handle: actix_server::ServerHandle,
+    state: Arc<RwLock<ServerState>>,
+}
+
+/// Start the tcp and udp servers on random local ports
+async fn start_server(configure: impl FnOnce(&mut ServerState)) -> TestServer {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).expect("cannot generate certificate");
+    let certs = vec![Certificate(cert.serialize_der().expect("cannot serialize certificate"))];
+    let key = PrivateKey(cert.serialize_private_key_der());
+
+    let mut roots = RootCertStore::empty();
+    roots.add(&certs[0]).expect("cannot add root certificate");
+
+    let server_config =
+        create_tls_config(vec![(certs, key)], TlsVersion::Tls12, rustls::ALL_CIPHER_SUITES, false).expect("cannot create tls config");
+    let client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let socket = Arc::new(bind_udp(LOCALHOST).expect("cannot bind udp socket"));
+    let udp_address = socket.local_addr().expect("cannot get udp address");
+    let mut server_state = ServerState::new(socket.clone(), BanList::load(None).expect("cannot create ban list"));
+    configure(&mut server_state);
+
+    let state = Arc::new(RwLock::new(server_state));
+
+    let mut server_version = Version::new();
+    server_version.set_version(PROTOCOL_VERSION);
+
+    let udp_state = state.clone();
+
+    actix_rt::spawn(async move {
+        create_udp_server(PROTOCOL_VERSION, socket, udp_state)
+            .await
+            .expect("udp server error");
+    });
+
+    let listener = bind_tcp(LOCALHOST).expect("cannot bind tcp listener");
+    let tcp_address = listener.local_addr().expect("cannot get tcp address");
+    let server = create_tcp_server(listener, TlsAcceptor::from(Arc::new(server_config)), server_version, state.clone());
+    let handle = server.handle();
+
+    actix_rt::spawn(server);
+
+    TestServer {
+        tcp_address,
+        udp_address,
+        connector: TlsConnector::from(Arc::new(client_config)),
+        handle,
+        state,
+    }
+}
+
+async fn send_bytes(stream: &mut TlsStream<TcpStream>, bytes: &[u8]) {
+    stream.write_all(bytes).await.expect("cannot send message");
+    stream.flush().await.expect("cannot flush message");
+}
+
+async fn send<T: Message>(stream: &mut TlsStream<TcpStream>, kind: MessageKind, message: &T) {
+    send_bytes(stream, &message_to_bytes(kind, message).expect("cannot encode message")).await;
+}
+
+/// Read messages until one of the given kind is received, returns its payload
+async fn receive_bytes(stream: &mut TlsStream<TcpStream>, kind: MessageKind) -> Vec<u8> {
+    loop {
+        let message_kind = stream.read_u16().await.expect("cannot read message kind");
+        let size = stream.read_u32().await.expect("cannot read message size");
+        let mut data = vec![0; size as usize];
+        stream.read_exact(&mut data).await.expect("cannot read message");
+
+        if message_kind == kind as u16 {
+            return data;
+        }
+    }
+}
+
+async fn receive<T: Message>(stream: &mut TlsStream<TcpStream>, kind: MessageKind) -> T {
+    T::parse_from_bytes(&receive_bytes(stream, kind).await).expect("cannot parse message")
+}
+
+/// Send the version and authenticate messages over a new tls connection
+async fn authenticate(server: &TestServer, name: &str, tokens: &[&str]) -> TlsStream<TcpStream> {
+    let stream = TcpStream::connect(server.tcp_address).await.expect("cannot connect");
+    let server_name = ServerName::try_from("localhost").expect("invalid server name");
+    let mut stream = server.connector.connect(server_name, stream).await.expect("cannot connect tls");
+
+    let mut version = Version::new();
+    version.set_version(PROTOCOL_VERSION);
+    send(&mut stream, MessageKind::Version, &version).await;
+
+    let mut authenticate = Authenticate::new();
+    authenticate.set_username(name.to_string());
+    authenticate.set_opus(true);
+    authenticate.set_tokens(tokens.iter().map(|token| token.to_string()).collect::<Vec<_>>().into());
+    send(&mut stream, MessageKind::Authenticate, &authenticate).await;
+
+    stream
+}
+
+/// Run the handshake, returns the stream with the crypt setup and session of the client
+async fn connect(server: &TestServer, name: &str) -> (TlsStream<TcpStream>, CryptSetup, u32) {
+    let mut stream = authenticate(server, name, &[]).await;
+
+    let crypt_setup: CryptSetup = timeout(TIMEOUT, receive(&mut stream, MessageKind::CryptSetup))
+        .await
+        .expect("no crypt setup");
+    let server_sync: ServerSync = timeout(TIMEOUT, receive(&mut stream, MessageKind::ServerSync))
+        .await
+        .expect("no server sync");
+
+    (stream, crypt_setup, server_sync.get_session())
+}
+
+fn loopback_packet(seq_num: u64) -> VoicePacket<Serverbound> {
+    VoicePacket::Audio {
+        _dst: PhantomData,
+        target: LOOPBACK_TARGET,
+        session_id: (),
+        seq_num,
+        payload: VoicePacketPayload::Opus(Bytes::from_static(b"loopback"), false),
+        position_info: None,
+    }
+}
+
+fn assert_echoed(packet: VoicePacket<Clientbound>, session: u32, seq_num: u64) {
+    match packet {
+        VoicePacket::Audio {
+            session_id,
+            seq_num: echoed_seq_num,
+            payload: VoicePacketPayload::Opus(frame, end),
+            ..
+        } => {
+            assert_eq!(session_id, session);
+            assert_eq!(echoed_seq_num, seq_num);
+            assert_eq!(&frame[..], b"loopback");
+            assert!(!end);
+        }
+        packet => panic!("unexpected voice packet {:?}", packet),
+    }
+}
+
+#[actix_rt::test]
+async fn loopback_voice_is_echoed_over_tcp_tunnel() {
+    let server = start_server(|_| ()).await;
+    let (mut stream, _, session) = connect(&server, "tunnel").await;
+
+    let mut data = BytesMut::new();
+    encode_voice_packet(&loopback_packet(1), &mut data);
+
+    // Tunneled voice packets are not protobuf messages, the payload is the raw packet
+    send_bytes(&mut stream, &tunnel_to_bytes(&data)).await;
+
+    let echoed = timeout(TIMEOUT, receive_bytes(&mut stream, MessageKind::UDPTunnel))
+        .await
+        .expect("no voice echoed");
+    let packet = decode_voice_packet::<Clientbound>(&mut BytesMut::from(echoed.as_slice())).expect("cannot decode voice packet");
+
+    assert_echoed(packet, session, 1);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn oversized_position_info_is_stripped() {
+    let server = start_server(|_| ()).await;
+    let (mut stream, _, session) = connect(&server, "oversized").await;
+
+    let mut packet = loopback_packet(1);
+
+    if let VoicePacket::Audio { position_info, .. } = &mut packet {
+        *position_info = Some(Bytes::from(vec![0; 1024]));
+    }
+
+    let mut data = BytesMut::new();
+    encode_voice_packet(&packet, &mut data);
+    send_bytes(&mut stream, &tunnel_to_bytes(&data)).await;
+
+    let echoed = timeout(TIMEOUT, receive_bytes(&mut stream, MessageKind::UDPTunnel))
+        .await
+        .expect("no voice echoed");
+    let packet = decode_voice_packet::<Clientbound>(&mut BytesMut::from(echoed.as_slice())).expect("cannot decode voice packet");
+
+    assert!(matches!(&packet, VoicePacket::Audio { position_info: None, .. }));
+    assert_echoed(packet, session, 1);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn server_audio_is_sent_to_session() {
+    let server = start_server(|_| ()).await;
+    let (mut stream, _, session) = connect(&server, "announced").await;
+
+    let payload = VoicePacketPayload::Opus(Bytes::from_static(b"announce"), true);
+    let sent = {
+        server
+            .state
+            .read_err()
+            .await
+            .expect("state lock")
+            .send_audio_to_session(session, 0, payload)
+            .await
+    };
+    assert!(sent.expect("cannot send server audio"));
+
+    let received = timeout(TIMEOUT, receive_bytes(&mut stream, MessageKind::UDPTunnel))
+        .await
+        .expect("no server audio");
+    let packet = decode_voice_packet::<Clientbound>(&mut BytesMut::from(received.as_slice())).expect("cannot decode voice packet");
+
+    match packet {
+        VoicePacket::Audio {
+            session_id,
+            payload: VoicePacketPayload::Opus(frame, end),
+            ..
+        } => {
+            assert_eq!(session_id, SERVER_SESSION_ID);
+            assert_eq!(&frame[..], b"announce");
+            assert!(end);
+        }
+        packet => panic!("unexpected voice packet {:?}", packet),
+    }
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn udp_ping_reports_users_and_limits() {
+    let server = start_server(|state| state.max_clients = 10).await;
+    let (_stream, _, _) = connect(&server, "pinged").await;
+
+    let socket = UdpSocket::bind(LOCALHOST).await.expect("cannot bind client udp socket");
+    let mut ping = vec![0; 4];
+    ping.extend_from_slice(&42u64.to_le_bytes());
+    socket.send_to(&ping, server.udp_address).await.expect("cannot send udp ping");
+
+    let mut buffer = [0; 64];
+    let (size, _) = timeout(TIMEOUT, socket.recv_from(&mut buffer))
+        .await
+        .expect("no ping reply")
+        .expect("cannot receive udp");
+    assert_eq!(size, 24);
+
+    let read_u32 = |offset: usize| u32::from_be_bytes(buffer[offset..offset + 4].try_into().unwrap());
+
+    assert_eq!(read_u32(0), PROTOCOL_VERSION);
+    assert_eq!(u64::from_le_bytes(buffer[4..12].try_into().unwrap()), 42);
+    assert_eq!(read_u32(12), 1);
+    assert_eq!(read_u32(16), 10);
+    assert_eq!(read_u32(20), DEFAULT_MAX_BANDWIDTH);
+
+    server.handle.stop(false).await;
+}
+
+/// Ask for a temporary channel, returns the id of the created channel
+async fn create_channel(stream: &mut TlsStream<TcpStream>, parent: u32, name: &str) -> u32 {
+    let mut channel_state = ChannelState::new();
+    channel_state.set_parent(parent);
+    channel_state.set_name(name.to_string());
+    channel_state.set_temporary(true);
+    send(stream, MessageKind::ChannelState, &channel_state).await;
+
+    let created: ChannelState = timeout(TIMEOUT, receive(stream, MessageKind::ChannelState))
+        .await
+        .expect("no channel state");
+    assert_eq!(created.get_name(), name);
+
+    created.get_channel_id()
+}
+
+#[actix_rt::test]
+async fn created_channels_are_limited() {
+    let server = start_server(|state| {
+        state.max_channel_depth = 2;
+        state.max_channels_per_client = 2;
+    })
+    .await;
+    let (mut stream, _, _) = connect(&server, "builder").await;
+
+    let first = create_channel(&mut stream, 0, "first").await;
+    let second = create_channel(&mut stream, first, "second").await;
+
+    let mut channel_state = ChannelState::new();
+    channel_state.set_parent(second);
+    channel_state.set_name("third".to_string());
+    channel_state.set_temporary(true);
+    send(&mut stream, MessageKind::ChannelState, &channel_state).await;
+
+    let denied: PermissionDenied = timeout(TIMEOUT, receive(&mut stream, MessageKind::PermissionDenied))
+        .await
+        .expect("no permission denied");
+    assert_eq!(denied.get_field_type(), PermissionDenied_DenyType::NestingLimit);
+
+    channel_state.set_parent(0);
+    send(&mut stream, MessageKind::ChannelState, &channel_state).await;
+
+    let denied: PermissionDenied = timeout(TIMEOUT, receive(&mut stream, MessageKind::PermissionDenied))
+        .await
+        .expect("no permission denied");
+    assert_eq!(denied.get_field_type(), PermissionDenied_DenyType::ChannelCountLimit);
+    assert_eq!(server.state.read_err().await.unwrap().channels.len(), 3);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn channels_past_the_server_limit_are_refused() {
+    let server = start_server(|state| state.max_channels = 3).await;
+    let (mut stream, _, _) = connect(&server, "builder").await;
+
+    // Nested so the first channel is not removed when its creator moves to the second one
+    let first = create_channel(&mut stream, 0, "first").await;
+    create_channel(&mut stream, first, "second").await;
+
+    let mut channel_state = ChannelState::new();
+    channel_state.set_parent(0);
+    channel_state.set_name("third".to_string());
+    channel_state.set_temporary(true);
+    send(&mut stream, MessageKind::ChannelState, &channel_state).await;
+
+    let denied: PermissionDenied = timeout(TIMEOUT, receive(&mut stream, MessageKind::PermissionDenied))
+        .await
+        .expect("no permission denied");
+    assert_eq!(denied.get_field_type(), PermissionDenied_DenyType::ChannelCountLimit);
+    assert_eq!(server.state.read_err().await.unwrap().channels.len(), 3);
+
+    server.handle.stop(false).await;
+}
+
+/// Create the named channels under the root channel, their ids start at 1 in the given order
+fn add_channels(state: &mut ServerState, names: &[&str]) {
+    for name in names {
+        let mut channel_state = ChannelState::new();
+        channel_state.set_parent(0);
+        channel_state.set_name(name.to_string());
+        state.add_channel(&channel_state);
+    }
+}
+
+async fn get_client(server: &TestServer, session: u32) -> Arc<RwLock<Client>> {
+    server
+        .state
+        .read_err()
+        .await
+        .unwrap()
+        .clients
+        .get(&session)
+        .cloned()
+        .expect("client not found")
+}
+
+/// Sorted sessions and channels reached by the voice of a speaker
+async fn route(server: &TestServer, speaker: u32, target: u8, position: Option<[f32; 3]>) -> (Vec<u32>, Vec<u32>) {
+    let route = route_voice(&server.state, &get_client(server, speaker).await, target, position)
+        .await
+        .unwrap()
+        .expect("no route");
+
+    let mut sessions = route.listeners.keys().copied().collect::<Vec<_>>();
+    let mut channels = route.channels.into_iter().collect::<Vec<_>>();
+    sessions.sort_unstable();
+    channels.sort_unstable();
+
+    (sessions, channels)
+}
+
+#[actix_rt::test]
+async fn channel_voice_is_routed_to_the_channel_members() {
+    let server = start_server(|_| ()).await;
+    let (_speaker, _, speaker_session) = connect(&server, "speaker").await;
+    let (_listener, _, listener_session) = connect(&server, "listener").await;
+
+    // The speaker is part of the route, it is only skipped when the voice is sent
+    assert_eq!(
+        route(&server, speaker_session, CHANNEL_TARGET, None).await,
+        (vec![speaker_session, listener_session], vec![0])
+    );
+
+    // An unconfigured whisper target reaches nobody, an invalid one has no route
+    assert_eq!(route(&server, speaker_session, 1, None).await, (vec![], vec![]));
+
+    let speaker = get_client(&server, speaker_session).await;
+    assert!(route_voice(&server.state, &speaker, 40, None).await.unwrap().is_none());
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn muted_and_listen_only_voice_is_dropped() {
+    let server = start_server(|state| {
+        state.admin_tokens.insert("admin".to_string());
+        state.listen_only_channels.insert("briefing".to_string());
+        add_channels(state, &["briefing"]);
+    })
+    .await;
+    let (_speaker, _, speaker_session) = connect(&server, "speaker").await;
+    let (_admin, _, admin_session) = connect(&server, "admin").await;
+    let speaker = get_client(&server, speaker_session).await;
+    let admin = get_client(&server, admin_session).await;
+
+    admin.write_err().await.unwrap().tokens = vec!["admin".to_string()];
+
+    for client in [&speaker, &admin] {
+        client.read_err().await.unwrap().channel_id.store(1, Ordering::Relaxed);
+    }
+
+    // Only the channel voice is dropped in a listen only channel, and admins can still speak in it
+    assert_eq!(
+        get_voice_dropped(&server.state, &speaker, CHANNEL_TARGET).await.unwrap(),
+        Some(VoiceDropped::ListenOnly { channel_id: 1 })
+    );
+    assert_eq!(get_voice_dropped(&server.state, &speaker, 1).await.unwrap(), None);
+    assert_eq!(get_voice_dropped(&server.state, &admin, CHANNEL_TARGET).await.unwrap(), None);
+
+    speaker.write_err().await.unwrap().server_mute = true;
+
+    assert_eq!(
+        get_voice_dropped(&server.state, &speaker, 1).await.unwrap(),
+        Some(VoiceDropped::Muted)
+    );
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn channel_voice_is_routed_to_linked_channels_and_listeners() {
+    let server = start_server(|state| add_channels(state, &["radio", "linked", "unlinked"])).await;
+    let (_speaker, _, speaker) = connect(&server, "speaker").await;
+    let (_linked, _, linked) = connect(&server, "linked").await;
+    let (_unlinked, _, unlinked) = connect(&server, "unlinked").await;
+    let (_channel_listener, _, channel_listener) = connect(&server, "channel-listener").await;
+    let (_user_listener, _, user_listener) = connect(&server, "user-listener").await;
+
+    {
+        let state_read = server.state.read_err().await.unwrap();
+
+        for (channel_id, linked_id) in [(1, 2), (2, 1)] {
+            state_read.channels[&channel_id].write_err().await.unwrap().links.insert(linked_id);
+        }
+
+        // A link must be listed by both channels to be followed
+        state_read.channels[&1].write_err().await.unwrap().links.insert(3);
+        state_read.channels[&1]
+            .write_err()
+            .await
+            .unwrap()
+            .listeners
+            .insert(channel_listener);
+    }
+
+    for (session, channel_id) in [(speaker, 1), (linked, 2), (unlinked, 3)] {
+        get_client(&server, session)
+            .await
+            .read_err()
+            .await
+            .unwrap()
+            .join_channel(channel_id);
+    }
+
+    get_client(&server, user_listener)
+        .await
+        .write_err()
+        .await
+        .unwrap()
+        .listened_users
+        .insert(speaker);
+
+    assert_eq!(
+        route(&server, speaker, CHANNEL_TARGET, None).await,
+        (vec![speaker, linked, channel_listener, user_listener], vec![1, 2])
+    );
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn channel_voice_is_routed_within_the_audio_range() {
+    let server = start_server(|state| {
+        state.channel_ranges.insert("street".to_string(), 10.0);
+        add_channels(state, &["street"]);
+    })
+    .await;
+    let (_speaker, _, speaker) = connect(&server, "speaker").await;
+    let (_near, _, near) = connect(&server, "near").await;
+    let (_far, _, far) = connect(&server, "far").await;
+    let (_unknown, _, unknown) = connect(&server, "unknown").await;
+
+    for (session, position) in [
+        (speaker, Some([0.0, 0.0, 0.0])),
+        (near, Some([3.0, 4.0, 0.0])),
+        (far, Some([100.0, 0.0, 0.0])),
+        (unknown, None),
+    ] {
+        let client = get_client(&server, session).await;
+        let client_read = client.read_err().await.unwrap();
+
+        client_read.join_channel(1);
+        client_read.set_position(position);
+    }
+
+    // Clients without a known position hear the speaker whatever the distance
+    assert_eq!(
+        route(&server, speaker, CHANNEL_TARGET, Some([0.0, 0.0, 0.0])).await,
+        (vec![speaker, near, unknown], vec![1])
+    );
+
+    // Without a position the speaker is heard by the whole channel
+    assert_eq!(
+        route(&server, speaker, CHANNEL_TARGET, None).await.0,
+        vec![speaker, near, far, unknown]
+    );
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn whisper_is_routed_to_the_target_sessions_and_channels() {
+    let server = start_server(|state| add_channels(state, &["dispatch"])).await;
+    let (_speaker, _, speaker) = connect(&server, "speaker").await;
+    let (_whispered, _, whispered) = connect(&server, "whispered").await;
+    let (_dispatcher, _, dispatcher) = connect(&server, "dispatcher").await;
+    let (_bystander, _, _) = connect(&server, "bystander").await;
+
+    get_client(&server, dispatcher).await.read_err().await.unwrap().join_channel(1);
+
+    {
+        let speaker_client = get_client(&server, speaker).await;
+        let target = speaker_client.read_err().await.unwrap().get_target(0).expect("no voice target");
+        let mut target_write = target.write_err().await.unwrap();
+
+        target_write.sessions.insert(whispered);
+        target_write.channels.insert(1);
+    }
+
+    assert_eq!(route(&server, speaker, 1, None).await, (vec![whispered, dispatcher], vec![1]));
+    assert_eq!(route(&server, speaker, 2, None).await, (vec![], vec![]));
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn full_channel_cannot_be_joined() {
+    let server = start_server(|state| {
+        state.channel_capacities.insert("car".to_string(), 1);
+
+        let mut channel_state = ChannelState::new();
+        channel_state.set_parent(0);
+        channel_state.set_name("car".to_string());
+        state.add_channel(&channel_state);
+    })
+    .await;
+    let (mut driver, _, driver_session) = connect(&server, "driver").await;
+    let (mut passenger, _, passenger_session) = connect(&server, "passenger").await;
+
+    let mut user_state = UserState::new();
+    user_state.set_session(driver_session);
+    user_state.set_channel_id(1);
+    send(&mut driver, MessageKind::UserState, &user_state).await;
+
+    // Wait for the move of the driver so it is handled before the join of the passenger
+    loop {
+        let moved: UserState = timeout(TIMEOUT, receive(&mut passenger, MessageKind::UserState))
+            .await
+            .expect("no user state");
+
+        if moved.get_session() == driver_session && moved.get_channel_id() == 1 {
+            break;
+        }
+    }
+
+    user_state.set_session(passenger_session);
+    send(&mut passenger, MessageKind::UserState, &user_state).await;
+
+    let denied: PermissionDenied = timeout(TIMEOUT, receive(&mut passenger, MessageKind::PermissionDenied))
+        .await
+        .expect("no permission denied");
+    assert_eq!(denied.get_field_type(), PermissionDenied_DenyType::ChannelFull);
+
+    // Joining by name is limited the same way
+    let mut channel_state = ChannelState::new();
+    channel_state.set_parent(0);
+    channel_state.set_name("car".to_string());
+    channel_state.set_temporary(true);
+    send(&mut passenger, MessageKind::ChannelState, &channel_state).await;
+
+    let denied: PermissionDenied = timeout(TIMEOUT, receive(&mut passenger, MessageKind::PermissionDenied))
+        .await
+        .expect("no permission denied");
+    assert_eq!(denied.get_field_type(), PermissionDenied_DenyType::ChannelFull);
+
+    let channel_ids = {
+        let state_read = server.state.read_err().await.unwrap();
+        let mut channel_ids = Vec::new();
+
+        for session in [driver_session, passenger_session] {
+            let client = state_read.clients.get(&session).cloned().expect("client not found");
+            channel_ids.push(client.read_err().await.unwrap().channel_id.load(Ordering::Relaxed));
+        }
+
+        channel_ids
+    };
+
+    assert_eq!(channel_ids, vec![1, 0]);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn restricted_channel_cannot_be_joined_by_name() {
+    let server = start_server(|state| {
+        state
+            .restricted_channel_tokens
+            .insert("police".to_string(), HashSet::from(["police".to_string()]));
+        add_channels(state, &["police"]);
+    })
+    .await;
+    let (mut stream, _, session) = connect(&server, "thief").await;
+
+    let mut channel_state = ChannelState::new();
+    channel_state.set_parent(0);
+    channel_state.set_name("police".to_string());
+    channel_state.set_temporary(true);
+    send(&mut stream, MessageKind::ChannelState, &channel_state).await;
+
+    let denied: PermissionDenied = timeout(TIMEOUT, receive(&mut stream, MessageKind::PermissionDenied))
+        .await
+        .expect("no permission denied");
+    assert_eq!(denied.get_field_type(), PermissionDenied_DenyType::Permission);
+    assert_eq!(denied.get_permission(), PERM_ENTER);
+    assert_eq!(denied.get_channel_id(), 1);
+
+    let client = get_client(&server, session).await;
+    assert_eq!(client.read_err().await.unwrap().channel_id.load(Ordering::Relaxed), 0);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn ban_list_is_refused_to_non_admins() {
+    let server = start_server(|state| {
+        state.admin_tokens.insert("admin".to_string());
+        let _ = state.bans.add(Ban::new("192.0.2.1".parse().unwrap(), 128));
+    })
+    .await;
+    let mut player = authenticate(&server, "player", &[]).await;
+    let mut admin = authenticate(&server, "admin", &["admin"]).await;
+
+    let mut query = BanListMessage::new();
+    query.set_query(true);
+
+    // An empty list sent without query would lift every ban
+    for ban_list in [query.clone(), BanListMessage::new()] {
+        send(&mut player, MessageKind::BanList, &ban_list).await;
+
+        let denied: PermissionDenied = timeout(TIMEOUT, receive(&mut player, MessageKind::PermissionDenied))
+            .await
+            .expect("no permission denied");
+        assert_eq!(denied.get_field_type(), PermissionDenied_DenyType::Permission);
+    }
+
+    send(&mut admin, MessageKind::BanList, &query).await;
+
+    let ban_list: BanListMessage = timeout(TIMEOUT, receive(&mut admin, MessageKind::BanList))
+        .await
+        .expect("no ban list");
+    assert_eq!(ban_list.get_bans().len(), 1);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn loopback_voice_is_echoed_over_udp() {
+    let server = start_server(|_| ()).await;
+    let (_stream, crypt_setup, session) = connect(&server, "udp").await;
+
+    // The client encrypts with the nonce the server decrypts with, and the other way around
+    let mut crypt = CryptState::new(
+        crypt_setup.get_key().try_into().expect("invalid key"),
+        crypt_setup.get_client_nonce().try_into().expect("invalid client nonce"),
+        crypt_setup.get_server_nonce().try_into().expect("invalid server nonce"),
+    );
+
+    let socket = UdpSocket::bind(LOCALHOST).await.expect("cannot bind client udp socket");
+    let mut data = BytesMut::new();
+    crypt.encrypt(&loopback_packet(1), &mut data);
+    socket.send_to(&data, server.udp_address).await.expect("cannot send udp voice");
+
+    let mut buffer = BytesMut::zeroed(1024);
+    let (size, _) = timeout(TIMEOUT, socket.recv_from(&mut buffer))
+        .await
+        .expect("no voice echoed")
+        .expect("cannot receive udp");
+    buffer.truncate(size);
+
+    let packet = crypt.decrypt::<Clientbound>(&mut buffer).expect("cannot decrypt voice packet");
+
+    assert_echoed(packet, session, 1);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn silent_client_is_dropped_after_handshake_timeout() {
+    let server = start_server(|state| state.handshake_timeout = Duration::from_millis(200)).await;
+
+    let stream = TcpStream::connect(server.tcp_address).await.expect("cannot connect");
+    let server_name = ServerName::try_from("localhost").expect("invalid server name");
+    let mut stream = server.connector.connect(server_name, stream).await.expect("cannot connect tls");
+
+    // Neither version nor authenticate is sent, the server closes the connection
+    let mut buffer = [0; 64];
+    let read = timeout(TIMEOUT, stream.read(&mut buffer)).await.expect("connection not dropped");
+
+    assert!(matches!(read, Ok(0) | Err(_)), "unexpected data from the server: {:?}", read);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn registered_user_gets_its_user_id_and_default_channel() {
+    let server = start_server(|state| {
+        let mut channel_state = ChannelState::new();
+        channel_state.set_parent(0);
+        channel_state.set_name("registered".to_string());
+        state.add_channel(&channel_state);
+
+        state.registry = Some(
+            Registry::new(vec![RegisteredUser {
+                user_id: 1,
+                fingerprint: None,
+                token: Some("registered-token".to_string()),
+                default_channel: Some(1),
+            }])
+            .expect("invalid registry"),
+        );
+    })
+    .await;
+
+    let mut stream = authenticate(&server, "registered", &["registered-token"]).await;
+
+    // Alone on the server, the first user state is the one of the client
+    let user_state: UserState = timeout(TIMEOUT, receive(&mut stream, MessageKind::UserState))
+        .await
+        .expect("no user state");
+
+    assert_eq!(user_state.get_user_id(), 1);
+    assert_eq!(user_state.get_channel_id(), 1);
+
+    // The session of an anonymous client can equal a registered user id, it must not be reported as its user id
+    let (mut anonymous, _, anonymous_session) = connect(&server, "anonymous").await;
+
+    let user_state = loop {
+        let user_state: UserState = timeout(TIMEOUT, receive(&mut anonymous, MessageKind::UserState))
+            .await
+            .expect("no user state");
+
+        if user_state.get_session() == anonymous_session {
+            break user_state;
+        }
+    };
+
+    assert!(!user_state.has_user_id());
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn token_channel_is_joined_on_connect() {
+    let server = start_server(|state| {
+        for name in ["police", "ems"] {
+            let mut channel_state = ChannelState::new();
+            channel_state.set_parent(0);
+            channel_state.set_name(name.to_string());
+            state.add_channel(&channel_state);
+        }
+
+        state.init_steps.push(InitStep::JoinTokenChannel(vec![
+            "ems:ems".parse::<TokenChannelConfig>().expect("invalid token channel"),
+            "police:police".parse::<TokenChannelConfig>().expect("invalid token channel"),
+        ]));
+    })
+    .await;
+
+    let mut stream = authenticate(&server, "officer", &["police", "ems"]).await;
+
+    // The client is synced in the default channel, then moved by the init step
+    let user_state = loop {
+        let user_state: UserState = timeout(TIMEOUT, receive(&mut stream, MessageKind::UserState))
+            .await
+            .expect("no user state");
+
+        if user_state.get_channel_id() != 0 {
+            break user_state;
+        }
+    };
+
+    // The first mapping matching one of the tokens wins, whatever the order of the tokens
+    assert_eq!(user_state.get_channel_id(), 2);
+
+    server.handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn voice_targets_are_set_on_connect() {
+    let server = start_server(|state| {
+        add_channels(state, &["police", "ems"]);
+
+        state.init_steps = vec![
+            InitStep::VoiceTarget(vec![
+                "1:police".parse::<VoiceTargetConfig>().expect("invalid voice target"),
+                "1:ems".parse::<VoiceTargetConfig>().expect("invalid voice target"),
+                "2:unknown".parse::<VoiceTargetConfig>().expect("invalid voice target"),
+            ]),
+            InitStep::Greeting("welcome".to_string()),
+        ];
+    })
+    .await;
+
+    let (mut stream, _, session) = connect(&server, "officer").await;
+
+    // Steps run in order, the voice targets are set once the greeting is received
+    let text_message: TextMessage = timeout(TIMEOUT, receive(&mut stream, MessageKind::TextMessage))
+        .await
+        .expect("no greeting");
+
+    assert_eq!(text_message.get_message(), "welcome");
+    assert_eq!(route(&server, session, 1, None).await, (vec![], vec![1, 2]));
+    assert_eq!(route(&server, session, 2, None).await, (vec![], vec![]));
+
+    server.handle.stop(false).await;
+}